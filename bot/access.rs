@@ -0,0 +1,285 @@
+use crate::command::Invocation;
+use err_context::AnyError;
+use serenity::http::Http;
+use serenity::model::channel::{GuildChannel, PermissionOverwriteType};
+use serenity::model::id::{RoleId, UserId};
+use serenity::model::permissions::Permissions;
+use shared::CommandError;
+use std::collections::HashSet;
+
+/// Who may run a command, as declared on its builder.
+#[derive(Clone, Default)]
+pub struct Requirements {
+    /// Guild permissions needed, each with its name as Discord shows it.
+    pub permissions: Vec<(Permissions, &'static str)>,
+    /// Only the bot's owners may run it.
+    pub owner_only: bool,
+}
+
+impl Requirements {
+    pub fn is_empty(&self) -> bool {
+        self.permissions.is_empty() && !self.owner_only
+    }
+}
+
+/// Which roles of a guild may use a command. Denied roles win over allowed ones, and an empty
+/// allow list allows everyone.
+#[derive(Clone, Default, Debug)]
+pub struct RoleRules {
+    pub allow: Vec<RoleId>,
+    pub deny: Vec<RoleId>,
+}
+
+impl RoleRules {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn allows(&self, roles: &[RoleId]) -> bool {
+        if roles.iter().any(|role| self.deny.contains(role)) {
+            return false;
+        }
+
+        self.allow.is_empty() || roles.iter().any(|role| self.allow.contains(role))
+    }
+}
+
+/// The author of a command, with what they may do where it was run.
+pub struct Author {
+    pub id: UserId,
+    pub roles: Vec<RoleId>,
+    /// Everything in DMs, where guild permissions don't apply.
+    pub permissions: Permissions,
+    pub owner: bool,
+}
+
+impl Author {
+    pub async fn fetch(
+        http: &Http,
+        invocation: &Invocation,
+        owners: &HashSet<UserId>,
+    ) -> Result<Self, AnyError> {
+        let id = invocation.author_id();
+        let owner = owners.contains(&id);
+        let guild_id = match invocation.guild_id() {
+            Some(guild_id) => guild_id,
+            None => {
+                return Ok(Self {
+                    id,
+                    roles: vec![],
+                    permissions: Permissions::all(),
+                    owner,
+                })
+            }
+        };
+
+        let roles = match invocation.roles() {
+            Some(roles) => roles,
+            None => guild_id.member(http, id).await?.roles,
+        };
+        let guild = guild_id.to_partial_guild(http).await?;
+        if guild.owner_id == id {
+            return Ok(Self {
+                id,
+                roles,
+                permissions: Permissions::all(),
+                owner,
+            });
+        }
+
+        // @everyone shares the guild's id
+        let mut permissions = guild
+            .roles
+            .get(&RoleId(guild_id.0))
+            .map(|role| role.permissions)
+            .unwrap_or_else(Permissions::empty);
+        for role in &roles {
+            if let Some(role) = guild.roles.get(role) {
+                permissions |= role.permissions;
+            }
+        }
+        if permissions.contains(Permissions::ADMINISTRATOR) {
+            permissions = Permissions::all();
+        }
+
+        Ok(Self {
+            id,
+            roles,
+            permissions,
+            owner,
+        })
+    }
+
+    /// What the author may do in `channel`, after its permission overwrites.
+    pub fn channel_permissions(&self, channel: &GuildChannel) -> Permissions {
+        if self.permissions.contains(Permissions::ADMINISTRATOR) {
+            return Permissions::all();
+        }
+
+        // @everyone's overwrite applies first, then all the roles' at once, then the member's
+        let mut permissions = self.permissions;
+        let (mut allow, mut deny) = (Permissions::empty(), Permissions::empty());
+        for overwrite in &channel.permission_overwrites {
+            match overwrite.kind {
+                PermissionOverwriteType::Role(role) if role.0 == channel.guild_id.0 => {
+                    permissions = (permissions - overwrite.deny) | overwrite.allow;
+                }
+                PermissionOverwriteType::Role(role) if self.roles.contains(&role) => {
+                    allow |= overwrite.allow;
+                    deny |= overwrite.deny;
+                }
+                _ => {}
+            }
+        }
+        permissions = (permissions - deny) | allow;
+        for overwrite in &channel.permission_overwrites {
+            if overwrite.kind == PermissionOverwriteType::Member(self.id) {
+                permissions = (permissions - overwrite.deny) | overwrite.allow;
+            }
+        }
+
+        permissions
+    }
+
+    /// Fails unless the author meets `requirements` and the guild's `rules` for the command.
+    /// Admins aren't held to role rules, so a guild can always undo them.
+    pub fn check(
+        &self,
+        requirements: &Requirements,
+        rules: Option<&RoleRules>,
+    ) -> Result<(), CommandError> {
+        if requirements.owner_only && !self.owner {
+            return Err(CommandError::GenericError(
+                "Only the bot's owners can do that.",
+            ));
+        }
+        for (permission, name) in &requirements.permissions {
+            if !self.permissions.contains(*permission) {
+                return Err(CommandError::MissingPermission(name));
+            }
+        }
+
+        let admin = self.permissions.contains(Permissions::ADMINISTRATOR);
+        match rules {
+            Some(rules) if !admin && !rules.allows(&self.roles) => Err(CommandError::GenericError(
+                "Your roles don't allow you to use that here.",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    static GUILD: u64 = 1;
+    static READ: Permissions = Permissions::READ_MESSAGES;
+
+    fn author(permissions: Permissions, roles: &[u64]) -> Author {
+        Author {
+            id: UserId(5),
+            roles: roles.iter().copied().map(RoleId).collect(),
+            permissions,
+            owner: false,
+        }
+    }
+
+    /// An overwrite for a role (kind 0) or member (kind 1).
+    fn overwrite(id: u64, kind: u8, allow: Permissions, deny: Permissions) -> serde_json::Value {
+        json!({
+            "id": id.to_string(),
+            "type": kind,
+            "allow": allow.bits().to_string(),
+            "deny": deny.bits().to_string(),
+        })
+    }
+
+    fn channel(overwrites: Vec<serde_json::Value>) -> GuildChannel {
+        serde_json::from_value(json!({
+            "id": "10",
+            "guild_id": GUILD.to_string(),
+            "type": 0,
+            "name": "general",
+            "permission_overwrites": overwrites,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn applies_the_everyone_overwrite() {
+        let none = Permissions::empty();
+        let hidden = channel(vec![overwrite(GUILD, 0, none, READ)]);
+        let shown = channel(vec![overwrite(GUILD, 0, READ, none)]);
+
+        assert!(!author(READ, &[])
+            .channel_permissions(&hidden)
+            .contains(READ));
+        assert!(author(none, &[]).channel_permissions(&shown).contains(READ));
+    }
+
+    #[test]
+    fn role_overwrites_beat_everyone() {
+        let none = Permissions::empty();
+        let channel = channel(vec![
+            overwrite(GUILD, 0, none, READ),
+            overwrite(2, 0, READ, none),
+        ]);
+
+        assert!(author(READ, &[2])
+            .channel_permissions(&channel)
+            .contains(READ));
+        assert!(!author(READ, &[3])
+            .channel_permissions(&channel)
+            .contains(READ));
+    }
+
+    #[test]
+    fn any_role_allowing_wins() {
+        let none = Permissions::empty();
+        let channel = channel(vec![
+            overwrite(2, 0, none, READ),
+            overwrite(3, 0, READ, none),
+        ]);
+
+        assert!(author(READ, &[2, 3])
+            .channel_permissions(&channel)
+            .contains(READ));
+        assert!(!author(READ, &[2])
+            .channel_permissions(&channel)
+            .contains(READ));
+    }
+
+    #[test]
+    fn member_overwrites_beat_roles() {
+        let none = Permissions::empty();
+        let denied = channel(vec![
+            overwrite(2, 0, READ, none),
+            overwrite(5, 1, none, READ),
+        ]);
+        let allowed = channel(vec![
+            overwrite(2, 0, none, READ),
+            overwrite(5, 1, READ, none),
+        ]);
+        let someone_else = channel(vec![overwrite(6, 1, none, READ)]);
+
+        assert!(!author(READ, &[2])
+            .channel_permissions(&denied)
+            .contains(READ));
+        assert!(author(none, &[2])
+            .channel_permissions(&allowed)
+            .contains(READ));
+        assert!(author(READ, &[])
+            .channel_permissions(&someone_else)
+            .contains(READ));
+    }
+
+    #[test]
+    fn admins_ignore_overwrites() {
+        let admin = author(Permissions::ADMINISTRATOR, &[]);
+        let channel = channel(vec![overwrite(5, 1, Permissions::empty(), READ)]);
+
+        assert_eq!(admin.channel_permissions(&channel), Permissions::all());
+    }
+}
@@ -1,27 +1,97 @@
-use crate::command::Command;
-use crate::tenor::TenorClient;
+use crate::command::{Command, Invocation};
+use crate::history::{Image, ImageHistory};
+use crate::provider::Providers;
+use crate::settings::GuildSettings;
+use crate::store::Store;
 use err_context::AnyError;
 use linkify::LinkFinder;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 use reqwest::{RequestBuilder, Response};
 use serenity::{
     async_trait,
     model::{gateway::Ready, prelude::*},
     prelude::*,
 };
+use serenity::http::Http;
 use shared::CommandError;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::ops::Add;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use crate::process::get_first_url;
+use std::time::{Duration, Instant};
 
 pub struct BotHandler {
     bot: BotLock,
+    /// Whether the slash commands were registered, as `ready` comes again on reconnects.
+    registered: AtomicBool,
 }
 
 impl BotHandler {
     async fn new(bot: BotLock) -> Self {
-        Self { bot }
+        Self {
+            bot,
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    /// Runs the command named by `args[0]`, if the guild's settings allow it here.
+    async fn dispatch(&self, http: Arc<Http>, args: Vec<String>, invocation: Invocation) {
+        let r = self.bot.read().await;
+        let name = match args.first() {
+            Some(name) => name,
+            None => return,
+        };
+        let command = match r.command(name) {
+            Some(command) => command.clone(),
+            None => {
+                // only typed commands can be mistyped
+                let suggestion = match (&invocation, invocation.guild_id()) {
+                    (Invocation::Message(_), Some(guild)) if r.settings_for(guild).suggestions => {
+                        r.suggest(name)
+                    }
+                    _ => None,
+                };
+                drop(r);
+
+                if let Some(suggestion) = suggestion {
+                    let reply = format!(
+                        "No command named `{}`. Did you mean `{}`?",
+                        name, suggestion
+                    );
+                    if let Ok(msg) = invocation.say(&http, reply).await {
+                        crate::process::delay_delete(http, msg, Duration::from_millis(10000)).await;
+                    }
+                }
+                return;
+            }
+        };
+        let allowed = match invocation.guild_id() {
+            Some(guild) => r
+                .settings_for(guild)
+                .check_command(command.name, invocation.channel_id()),
+            None => Ok(()),
+        };
+
+        drop(r);
+
+        if let Err(reason) = allowed {
+            // interactions always need an answer, or they show as failed
+            let reason = match (reason, &invocation) {
+                (Some(reason), _) => reason,
+                (None, Invocation::Interaction(_)) => {
+                    "Commands don't work in this channel.".to_string()
+                }
+                (None, Invocation::Message(_)) => return,
+            };
+            if let Ok(msg) = invocation.say(&http, reason).await {
+                crate::process::delay_delete(http, msg, Duration::from_millis(3000)).await;
+            }
+            return;
+        }
+
+        command.run(http, self.bot.clone(), args, invocation).await;
     }
 }
 
@@ -32,60 +102,143 @@ impl EventHandler for BotHandler {
         let guild_id = &_new_message.guild_id;
 
         if let Some(id) = guild_id {
-            for attachment in &_new_message.attachments {
-                if let Some(content) = &attachment.content_type {
-                    if content.starts_with("image") {
-                        let mut w = self.bot.write().await;
-                        w.latest_image
-                            .insert(_new_message.channel_id.clone(), attachment.url.clone());
-                    }
+            let urls = crate::source::posted_images(&_new_message);
+            if !urls.is_empty() {
+                let mut w = self.bot.write().await;
+                // the best image goes in last, as the latest
+                for url in urls.into_iter().rev() {
+                    w.history.push(
+                        _new_message.channel_id,
+                        Image {
+                            url,
+                            author: _new_message.author.id,
+                            author_name: _new_message.author.name.to_lowercase(),
+                            message: _new_message.id,
+                            posted: Instant::now(),
+                        },
+                    );
                 }
             }
 
-            if let Some(url) = get_first_url(content).await {
-                let mut w = self.bot.write().await;
-                w.latest_image
-                    .insert(_new_message.channel_id.clone(), url);
+            let r = self.bot.read().await;
+            if let Some(content) = strip_prefix(content, r.prefix_for(*id), r.user_id) {
+                let split: Vec<String> = crate::process::get_args(&content.to_string());
+
+                drop(r);
+
+                let invocation = Invocation::Message(Box::new(_new_message));
+                self.dispatch(_ctx.http.clone(), split, invocation).await;
             }
+        }
+    }
 
-            let r = self.bot.read().await;
-            let default = "=".to_string();
-            let prefix = r.prefix.get(&id).unwrap_or(&default);
-            if content.starts_with(prefix) {
-                let content = &content[prefix.len()..].to_string();
-                let split: Vec<String> = crate::process::get_args(content);
-                let void = "<void>".to_string();
+    async fn message_delete(
+        &self,
+        _ctx: Context,
+        _channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        _guild_id: Option<GuildId>,
+    ) {
+        let job = {
+            let mut w = self.bot.write().await;
+            w.history.remove_message(_channel_id, deleted_message_id);
+            w.running_jobs
+                .remove(&deleted_message_id)
+                .map(|job_url| (w.client.clone(), job_url))
+        };
 
-                let command = split.get(0).unwrap_or(&void);
+        // nobody is waiting for the image anymore
+        if let Some((client, job_url)) = job {
+            let _ = client.delete(job_url).send().await;
+        }
+    }
 
-                let command = r.commands.get(command);
+    async fn ready(&self, _ctx: Context, _data_about_bot: Ready) {
+        self.bot.write().await.user_id = Some(_data_about_bot.user.id);
+        match _ctx.http.get_current_application_info().await {
+            Ok(info) => {
+                let owners = match info.team {
+                    Some(team) => team.members.iter().map(|m| m.user.id).collect(),
+                    None => HashSet::from([info.owner.id]),
+                };
+                self.bot.write().await.owners = owners;
+            }
+            Err(e) => tracing::warn!(error = %e, "Could not look up the bot's owners"),
+        }
+        tracing::info!(user = %_data_about_bot.user.name, "Starting bot!");
 
-                if let Some(command) = command {
-                    let clone = command.clone();
+        if !self.registered.swap(true, Ordering::SeqCst) {
+            let commands = self.bot.read().await.commands.clone();
+            if let Err(e) = crate::slash::register(&_ctx.http, &commands).await {
+                tracing::warn!(error = %e, "Could not register slash commands");
+                self.registered.store(false, Ordering::SeqCst);
+            }
+        }
+    }
 
-                    drop(r);
+    async fn interaction_create(&self, _ctx: Context, _interaction: Interaction) {
+        let interaction = match _interaction {
+            Interaction::ApplicationCommand(interaction) => Arc::new(interaction),
+            _ => return,
+        };
 
-                    clone
-                        .run(_ctx.http.clone(), self.bot.clone(), split, _new_message)
-                        .await;
-                }
+        // image jobs take longer than the 3s Discord gives for a first answer
+        if let Err(e) = interaction.defer(&_ctx.http).await {
+            tracing::warn!(error = %e, "Could not defer interaction");
+            return;
+        }
+
+        let args = {
+            let r = self.bot.read().await;
+            crate::slash::interaction_args(&r.commands, &interaction)
+        };
+        let invocation = Invocation::Interaction(interaction);
+
+        match args {
+            Ok(args) => self.dispatch(_ctx.http.clone(), args, invocation).await,
+            Err(e) => {
+                let _ = invocation.say(&_ctx.http, format!("```{}```", e)).await;
             }
         }
     }
+}
 
-    async fn ready(&self, _ctx: Context, _data_about_bot: Ready) {
-        println!("Starting bot!")
+/// Strips the guild's prefix, or a mention of the bot, from the start of a message.
+fn strip_prefix<'a>(content: &'a str, prefix: &str, user_id: Option<UserId>) -> Option<&'a str> {
+    if let Some(user_id) = user_id {
+        for mention in [format!("<@{}>", user_id.0), format!("<@!{}>", user_id.0)] {
+            if let Some(rest) = content.strip_prefix(mention.as_str()) {
+                return Some(rest.trim_start());
+            }
+        }
     }
+
+    content.strip_prefix(prefix)
 }
 
 pub type BotLock = Arc<RwLock<BotData>>;
 
+/// Prefix of guilds that haven't picked their own.
+pub static DEFAULT_PREFIX: &str = "=";
+
 pub struct BotData {
+    /// Custom prefixes, mirroring the store.
     pub prefix: HashMap<GuildId, String>,
-    pub latest_image: HashMap<ChannelId, String>,
+    pub store: Store,
+    /// Settings of guilds that changed any, mirroring the store.
+    pub settings: HashMap<GuildId, GuildSettings>,
+    /// The bot's own user, once connected, so it can be mentioned instead of using a prefix.
+    pub user_id: Option<UserId>,
+    /// Whoever owns the bot's application, or everyone on its team, once connected.
+    pub owners: HashSet<UserId>,
+    /// Recent images of each channel, for commands not given one.
+    pub history: ImageHistory,
     pub client: reqwest::Client,
     pub commands: HashMap<String, Command>,
-    pub tenor_client: crate::tenor::TenorClient,
+    /// Resolves links to GIF pages into the media on them.
+    pub providers: Arc<Providers>,
+    /// Image jobs in progress, by the message that asked for them.
+    pub running_jobs: HashMap<MessageId, String>,
     url_base: &'static str,
 }
 
@@ -95,16 +248,38 @@ impl BotData {
     /// # Panics
     /// If environment variable `IMGBOT_DISCORD_TOKEN` is not set, `#new` will panic.
     /// If building the client fails, `#new` will panic.
+    /// If `IMGBOT_SERVER_KEY` is not a valid header value, `#new` will panic.
+    /// If the database at `IMGBOT_DB` (default `imgbot.db`) cannot be opened, `#new` will panic.
+    /// If `IMGBOT_HISTORY_TTL` is set but isn't a number of seconds, `#new` will panic.
     pub async fn new() -> BotLock {
+        let db = env::var("IMGBOT_DB").unwrap_or_else(|_| "imgbot.db".to_string());
+        let store = Store::open(&PathBuf::from(db)).expect("database open failure");
+        let prefix = store.prefixes().expect("database read failure");
+        let settings = store.settings().expect("database read failure");
+
+        // `client' talks to the image server, so only it carries the server's API key
+        let mut headers = HeaderMap::new();
+        if let Ok(key) = env::var("IMGBOT_SERVER_KEY") {
+            let mut value = HeaderValue::from_str(&format!("Bearer {}", key))
+                .expect("server key is not a valid header");
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
         let client = reqwest::Client::builder()
+            .default_headers(headers)
             .build()
             .expect("http client build failure");
 
         let bot = Arc::new(RwLock::new(Self {
-            prefix: Default::default(),
+            prefix,
+            store,
+            settings,
+            user_id: None,
+            owners: HashSet::new(),
             commands: Default::default(),
-            latest_image: Default::default(),
-            tenor_client: TenorClient::new(client.clone()),
+            history: ImageHistory::from_env(),
+            running_jobs: Default::default(),
+            providers: Arc::new(Providers::from_env(reqwest::Client::new())),
             client,
             url_base: match env::var("KUBERNETES_SERVICE_HOST") {
                 Ok(_) => {
@@ -127,6 +302,57 @@ impl BotData {
         self.add_command(crate::command::caption::caption()).await;
         self.add_command(crate::command::severed::severed()).await;
         self.add_command(crate::command::help::help()).await;
+        self.add_command(crate::command::prefix::prefix()).await;
+        self.add_command(crate::command::config::config()).await;
+        self.add_command(crate::command::roles::roles()).await;
+        self.add_command(crate::command::sync::sync()).await;
+        self.add_command(crate::command::animate::spin()).await;
+        self.add_command(crate::command::animate::shake()).await;
+        self.add_command(crate::command::animate::zoom()).await;
+        self.add_command(crate::command::animate::pulse()).await;
+        self.add_command(crate::command::animate::speedlines()).await;
+        self.add_command(crate::command::animate::triggered()).await;
+        self.add_command(crate::command::timeline::reverse()).await;
+        self.add_command(crate::command::timeline::boomerang()).await;
+        self.add_command(crate::command::timeline::trim()).await;
+        self.add_command(crate::command::timeline::concat()).await;
+        self.add_command(crate::command::frames::frame()).await;
+        self.add_command(crate::command::frames::spritesheet()).await;
+        self.add_command(crate::command::frames::gifinfo()).await;
+        self.add_command(crate::command::crop::crop()).await;
+    }
+
+    pub fn prefix_for(&self, guild: GuildId) -> &str {
+        self.prefix
+            .get(&guild)
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_PREFIX)
+    }
+
+    pub fn settings_for(&self, guild: GuildId) -> GuildSettings {
+        self.settings.get(&guild).cloned().unwrap_or_default()
+    }
+
+    /// Finds a command by its name or one of its aliases, ignoring case.
+    pub fn command(&self, name: &str) -> Option<&Command> {
+        let name = name.to_lowercase();
+        self.commands.get(&name).or_else(|| {
+            self.commands
+                .values()
+                .find(|command| command.aliases.contains(&name.as_str()))
+        })
+    }
+
+    /// The command name or alias `name` was probably meant to be.
+    pub fn suggest(&self, name: &str) -> Option<&'static str> {
+        let mut names: Vec<&'static str> = self
+            .commands
+            .values()
+            .flat_map(|command| std::iter::once(command.name).chain(command.aliases.iter().copied()))
+            .collect();
+        // ties go to the first name, which shouldn't depend on the map's order
+        names.sort_unstable();
+        crate::suggest::closest(name, names)
     }
 
     pub fn get_url(&self, url: &str) -> String {
@@ -1,44 +1,226 @@
+pub mod animate;
 pub mod caption;
+pub mod config;
+pub mod crop;
+pub mod frames;
 pub mod help;
+pub mod prefix;
+pub mod roles;
 pub mod severed;
+pub mod sync;
+pub mod timeline;
 
+use crate::access::{Author, Requirements, RoleRules};
 use crate::bot::BotLock;
+use crate::cooldown::{Cooldown, Cooldowns, Scope};
 use clap::ErrorKind;
 use err_context::AnyError;
 use serenity::async_trait;
-use serenity::http::Http;
+use serenity::http::{AttachmentType, Http};
 use serenity::model::channel::Message;
+use serenity::model::id::{ChannelId, GuildId, MessageId, RoleId, UserId};
+use serenity::model::interactions::application_command::ApplicationCommandInteraction;
+use serenity::model::permissions::Permissions;
+use std::borrow::Cow;
 use std::sync::Arc;
 use std::time::Duration;
+use tracing::{info, info_span, warn, Instrument};
+use uuid::Uuid;
 
 pub type CommandAppCreate = fn(String) -> clap::App<'static>;
 
+/// What started a command: a message with the guild's prefix, or a slash or context-menu
+/// command.
+#[derive(Clone)]
+pub enum Invocation {
+    Message(Box<Message>),
+    /// Already deferred, so every reply is a followup.
+    Interaction(Arc<ApplicationCommandInteraction>),
+}
+
+impl Invocation {
+    /// Identifies the invocation while its job runs. Interactions can't be deleted, so
+    /// theirs never cancel anything.
+    pub fn id(&self) -> MessageId {
+        match self {
+            Invocation::Message(msg) => msg.id,
+            Invocation::Interaction(interaction) => MessageId(interaction.id.0),
+        }
+    }
+
+    pub fn channel_id(&self) -> ChannelId {
+        match self {
+            Invocation::Message(msg) => msg.channel_id,
+            Invocation::Interaction(interaction) => interaction.channel_id,
+        }
+    }
+
+    pub fn guild_id(&self) -> Option<GuildId> {
+        match self {
+            Invocation::Message(msg) => msg.guild_id,
+            Invocation::Interaction(interaction) => interaction.guild_id,
+        }
+    }
+
+    pub fn author_id(&self) -> UserId {
+        match self {
+            Invocation::Message(msg) => msg.author.id,
+            Invocation::Interaction(interaction) => interaction.user.id,
+        }
+    }
+
+    /// The author's roles, when Discord sent them along.
+    pub fn roles(&self) -> Option<Vec<RoleId>> {
+        match self {
+            Invocation::Message(msg) => msg.member.as_ref().map(|m| m.roles.clone()),
+            Invocation::Interaction(interaction) => {
+                interaction.member.as_ref().map(|m| m.roles.clone())
+            }
+        }
+    }
+
+    /// The message a command replied to, or a context-menu command was used on. Replies to
+    /// messages in other channels, like crossposts, are fetched when the bot can see them.
+    pub async fn target(&self, http: &Http) -> Option<Message> {
+        match self {
+            Invocation::Message(msg) => {
+                if let Some(referenced) = &msg.referenced_message {
+                    return Some(*referenced.clone());
+                }
+                let reference = msg.message_reference.as_ref()?;
+                http.get_message(reference.channel_id.0, reference.message_id?.0)
+                    .await
+                    .ok()
+            }
+            Invocation::Interaction(interaction) => {
+                let data = &interaction.data;
+                let id = data.target_id?.to_message_id();
+                data.resolved.messages.get(&id).cloned()
+            }
+        }
+    }
+
+    pub async fn say(
+        &self,
+        http: &Http,
+        content: impl std::fmt::Display,
+    ) -> serenity::Result<Message> {
+        match self {
+            Invocation::Message(msg) => msg.channel_id.say(http, content).await,
+            Invocation::Interaction(interaction) => {
+                interaction
+                    .create_followup_message(http, |f| f.content(content))
+                    .await
+            }
+        }
+    }
+
+    pub async fn send_file(
+        &self,
+        http: &Http,
+        data: &[u8],
+        filename: String,
+    ) -> serenity::Result<Message> {
+        let file = AttachmentType::Bytes {
+            data: Cow::Borrowed(data),
+            filename,
+        };
+
+        match self {
+            Invocation::Message(msg) => msg.channel_id.send_files(http, [file], |m| m).await,
+            Invocation::Interaction(interaction) => {
+                interaction
+                    .create_followup_message(http, |f| f.add_file(file))
+                    .await
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct CommandRunArgs {
     pub http: Arc<Http>,
     pub bot: BotLock,
     pub matches: clap::ArgMatches,
-    pub msg: Message,
+    pub invocation: Invocation,
     pub name: String,
+    /// Sent to the image server with every request made for this command, to tie their logs
+    /// together.
+    pub request_id: String,
 }
 
 #[derive(Clone)]
 pub struct Command {
     pub name: &'static str,
+    /// Other names the command answers to in messages.
+    pub aliases: Vec<&'static str>,
+    about: Option<&'static str>,
     parser: CommandAppCreate,
     runnable: Arc<dyn CommandRun>,
+    /// Shared between clones, so every copy of the command counts towards the same limits,
+    /// and possibly with other commands.
+    cooldowns: Arc<Cooldowns>,
+    requirements: Requirements,
 }
 
 impl Command {
     pub fn new(
         name: &'static str,
+        about: Option<&'static str>,
         parser: CommandAppCreate,
         f: Arc<dyn CommandRun + 'static>,
+        cooldowns: Arc<Cooldowns>,
+        requirements: Requirements,
+        aliases: Vec<&'static str>,
     ) -> Self {
         Self {
             name,
+            aliases,
+            about,
             parser,
             runnable: f,
+            cooldowns,
+            requirements,
+        }
+    }
+
+    pub fn requirements(&self) -> &Requirements {
+        &self.requirements
+    }
+
+    /// Fails unless the author of `invocation` may run this command, looking them up only
+    /// when the command or the guild restricts it.
+    pub async fn check_access(
+        &self,
+        http: &Http,
+        bot: &BotLock,
+        invocation: &Invocation,
+    ) -> Result<(), AnyError> {
+        let (rules, owners) = {
+            let r = bot.read().await;
+            let rules = invocation
+                .guild_id()
+                .and_then(|guild| r.settings.get(&guild))
+                .and_then(|settings| settings.command_roles.get(self.name))
+                .cloned();
+            (rules, r.owners.clone())
+        };
+        if self.requirements.is_empty() && rules.as_ref().is_none_or(RoleRules::is_empty) {
+            return Ok(());
+        }
+
+        let author = Author::fetch(http, invocation, &owners).await?;
+        author.check(&self.requirements, rules.as_ref())?;
+        Ok(())
+    }
+
+    /// Creates the clap app for this command, named `name`.
+    pub fn app(&self, name: String) -> clap::App<'static> {
+        let app = (self.parser)(name);
+
+        match self.about {
+            Some(about) => app.about(about),
+            None => app,
         }
     }
 
@@ -46,8 +228,14 @@ impl Command {
         CommandBuilder::new(name)
     }
 
-    pub async fn run(&self, http: Arc<Http>, bot: BotLock, args: Vec<String>, msg: Message) {
-        let app: clap::App = (self.parser)(self.name.to_string());
+    pub async fn run(
+        &self,
+        http: Arc<Http>,
+        bot: BotLock,
+        args: Vec<String>,
+        invocation: Invocation,
+    ) {
+        let app: clap::App = self.app(self.name.to_string());
         let has_verbose = app
             .get_arguments()
             .filter(|a| a.get_name() == "verbose")
@@ -58,29 +246,75 @@ impl Command {
 
         match matches {
             Ok(matches) => {
-                let channel_id = msg.channel_id.clone();
-
                 let verbose = match has_verbose {
                     true => matches.is_present("verbose"),
                     false => false,
                 };
 
-                let result = self
-                    .runnable
-                    .run(CommandRunArgs {
-                        http: http.clone(),
-                        bot,
-                        matches,
-                        msg,
-                        name: self.name.to_string().clone(),
-                    })
-                    .await;
+                if let Err(e) = self.check_access(&http, &bot, &invocation).await {
+                    invocation.say(&http, format!("```{}```", e)).await.unwrap();
+                    return;
+                }
+
+                if let Err(wait) = self.cooldowns.take(&invocation) {
+                    // admins are trusted not to spam, and may need to act quickly
+                    let admin = crate::process::author_permissions(&http, &invocation)
+                        .await
+                        .is_ok_and(|p| p.contains(Permissions::ADMINISTRATOR));
+                    if !admin {
+                        let new_msg = invocation
+                            .say(
+                                &http,
+                                format!(
+                                    "Slow down! Try `{}` again in {}s.",
+                                    self.name,
+                                    wait.as_secs() + 1
+                                ),
+                            )
+                            .await
+                            .unwrap();
+
+                        crate::process::delay_delete(http, new_msg, wait).await;
+                        return;
+                    }
+                }
+
+                let request_id = Uuid::new_v4().to_string();
+                let span = info_span!(
+                    "command",
+                    name = self.name,
+                    request_id = %request_id,
+                    guild = ?invocation.guild_id().map(|g| g.0),
+                    channel = invocation.channel_id().0,
+                );
+
+                let result = async {
+                    info!("Running command");
+                    let result = self
+                        .runnable
+                        .run(CommandRunArgs {
+                            http: http.clone(),
+                            bot,
+                            matches,
+                            invocation: invocation.clone(),
+                            name: self.name.to_string().clone(),
+                            request_id,
+                        })
+                        .await;
+
+                    if let Err(e) = &result {
+                        warn!(error = %e, "Command failed");
+                    }
+                    result
+                }
+                .instrument(span)
+                .await;
 
                 if let Err(e) = result {
                     if verbose {
-                        channel_id
+                        invocation
                             .say(
-                                http,
+                                &http,
                                 format!(
                                     "```{}\n\n{:#?}\n\nFor more information try --help```",
                                     e, e
@@ -89,9 +323,9 @@ impl Command {
                             .await
                             .unwrap();
                     } else {
-                        channel_id
+                        invocation
                             .say(
-                                http,
+                                &http,
                                 format!("```{}\n\nFor more information try --help```", e),
                             )
                             .await
@@ -101,19 +335,18 @@ impl Command {
             }
             Err(e) => match e.kind {
                 ErrorKind::DisplayHelp => {
-                    msg.channel_id
-                        .say(http, format!("Help: ```{}```", e.to_string()))
+                    invocation
+                        .say(&http, format!("Help: ```{}```", e.to_string()))
                         .await
                         .unwrap();
                 }
                 ErrorKind::DisplayVersion => {
-                    msg.channel_id.say(http, e.to_string()).await.unwrap();
+                    invocation.say(&http, e.to_string()).await.unwrap();
                 }
                 _ => {
                     if e.kind != ErrorKind::DisplayHelp && e.kind != ErrorKind::DisplayVersion {
-                        let new_msg = msg
-                            .channel_id
-                            .say(http.clone(), format!("```{}```", e.to_string()))
+                        let new_msg = invocation
+                            .say(&http, format!("```{}```", e.to_string()))
                             .await
                             .unwrap();
 
@@ -128,8 +361,13 @@ impl Command {
 
 pub struct CommandBuilder {
     name: &'static str,
+    about: Option<&'static str>,
     app: Option<CommandAppCreate>,
     run: Option<Arc<dyn CommandRun>>,
+    cooldowns: Vec<Cooldown>,
+    shared_cooldowns: Option<Arc<Cooldowns>>,
+    requirements: Requirements,
+    aliases: Vec<&'static str>,
 }
 
 struct UnimplementedCommandRun;
@@ -146,7 +384,12 @@ impl CommandBuilder {
         Self {
             app: Some(|n| clap::App::new(n)),
             name,
+            about: None,
             run: None,
+            cooldowns: vec![],
+            shared_cooldowns: None,
+            requirements: Default::default(),
+            aliases: vec![],
         }
     }
 
@@ -154,14 +397,22 @@ impl CommandBuilder {
         let run = match self.run.take() {
             Some(r) => r,
             None => {
-                println!("No run provided for command!");
+                warn!(name = self.name, "No run provided for command!");
                 Arc::new(UnimplementedCommandRun)
             }
         };
+        let cooldowns = match self.shared_cooldowns.take() {
+            Some(cooldowns) => cooldowns,
+            None => Arc::new(Cooldowns::new(std::mem::take(&mut self.cooldowns))),
+        };
         Command::new(
             std::mem::take(&mut self.name),
+            self.about.take(),
             std::mem::take(&mut self.app).unwrap(),
             run,
+            cooldowns,
+            std::mem::take(&mut self.requirements),
+            std::mem::take(&mut self.aliases),
         )
     }
 
@@ -175,6 +426,45 @@ impl CommandBuilder {
         self
     }
 
+    /// Adds another name for the command, e.g. `cap` for `caption`.
+    pub fn alias(&mut self, alias: &'static str) -> &mut Self {
+        self.aliases.push(alias);
+        self
+    }
+
+    /// Overrides the description taken from the parser, so several commands can share one.
+    pub fn about(&mut self, about: &'static str) -> &mut Self {
+        self.about = Some(about);
+        self
+    }
+
+    /// Limits how often the command runs, checked after its arguments parse. Can be given
+    /// several times, e.g. both per user and per guild.
+    pub fn cooldown(&mut self, scope: Scope, uses: usize, per: Duration) -> &mut Self {
+        self.cooldowns.push(Cooldown::new(scope, uses, per));
+        self
+    }
+
+    /// Counts the command's uses together with every other command given the same
+    /// `cooldowns`, e.g. [`image_cooldowns`](crate::cooldown::image_cooldowns). Replaces any
+    /// given with [`cooldown`](Self::cooldown).
+    pub fn shared_cooldowns(&mut self, cooldowns: Arc<Cooldowns>) -> &mut Self {
+        self.shared_cooldowns = Some(cooldowns);
+        self
+    }
+
+    /// Requires the author to have a guild permission, called `name` when they don't.
+    pub fn permission(&mut self, permission: Permissions, name: &'static str) -> &mut Self {
+        self.requirements.permissions.push((permission, name));
+        self
+    }
+
+    /// Only lets the bot's owners run the command.
+    pub fn owner_only(&mut self) -> &mut Self {
+        self.requirements.owner_only = true;
+        self
+    }
+
     // pub fn options(
     //     &mut self,
     //     f: impl Fn(String) -> clap::App<'static> + Send + Sync + 'static,
@@ -0,0 +1,102 @@
+use crate::command::{Command, CommandRun, CommandRunArgs};
+use crate::cooldown::image_cooldowns;
+use crate::process::{gif_options, img_job_with, GifArgs};
+
+use clap::{AppSettings, Parser};
+use err_context::AnyError;
+use serde_json::json;
+use serenity::async_trait;
+
+struct AnimateRun(&'static str);
+
+#[async_trait]
+impl CommandRun for AnimateRun {
+    async fn run(&self, a: CommandRunArgs) -> Result<(), AnyError> {
+        let text = a
+            .matches
+            .values_of("text")
+            .map(|t| t.collect::<Vec<&str>>().join(" "));
+        let frames = a.matches.value_of_t::<u32>("frames").ok();
+        let fps = a.matches.value_of_t::<u32>("fps").ok();
+        let gif = gif_options(&a)?;
+
+        img_job_with(
+            a,
+            format!("/animate/{}", self.0).as_str(),
+            false,
+            json!({
+                "text": text,
+                "frames": frames,
+                "fps": fps,
+                "gif": gif,
+            }),
+        )
+        .await
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(author = "rwilliaise (lego man)", version = "v0.1.0")]
+#[clap(setting(AppSettings::TrailingVarArg))]
+/// Animate a still image.
+///
+/// If the image is a GIF, only its first frame is animated. Images larger than 512px are
+/// scaled down first.
+struct AnimateArgs {
+    #[clap(short, long)]
+    /// URL pointing to image to animate. If this is not supplied, imgBot will
+    /// automatically pull the latest image from chat. `^2' picks the one before
+    /// it, and `@user' that user's latest image.
+    url: Option<String>,
+
+    #[clap(short, long)]
+    /// Print extra information with some error messages.
+    verbose: bool,
+
+    #[clap(short, long)]
+    /// Number of frames to generate, from 2 to 60. Defaults to 20.
+    frames: Option<u32>,
+
+    #[clap(long)]
+    /// Frames per second, from 1 to 50. Defaults to 20.
+    fps: Option<u32>,
+
+    #[clap(flatten)]
+    gif: GifArgs,
+
+    /// Banner text, only used by `triggered'.
+    text: Vec<String>,
+}
+
+fn animate(name: &'static str, about: &'static str) -> Command {
+    Command::builder(name)
+        .run(AnimateRun(name))
+        .parser::<AnimateArgs>()
+        .shared_cooldowns(image_cooldowns())
+        .about(about)
+        .build()
+}
+
+pub fn spin() -> Command {
+    animate("spin", "Spin an image in a full circle.")
+}
+
+pub fn shake() -> Command {
+    animate("shake", "Shake an image around.")
+}
+
+pub fn zoom() -> Command {
+    animate("zoom", "Zoom into the center of an image.")
+}
+
+pub fn pulse() -> Command {
+    animate("pulse", "Make an image pulse in and out.")
+}
+
+pub fn speedlines() -> Command {
+    animate("speedlines", "Draw anime speed lines over an image.")
+}
+
+pub fn triggered() -> Command {
+    animate("triggered", "Shake an image with a TRIGGERED banner.")
+}
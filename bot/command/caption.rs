@@ -1,8 +1,10 @@
 use crate::command::{Command, CommandRun, CommandRunArgs};
-use crate::process::img_job;
+use crate::cooldown::image_cooldowns;
+use crate::process::{gif_options, guild_settings, img_job_with, text_arg, GifArgs};
 
 use clap::{AppSettings, Parser};
 use err_context::AnyError;
+use serde_json::json;
 use serenity::async_trait;
 
 struct CaptionsRun;
@@ -10,7 +12,15 @@ struct CaptionsRun;
 #[async_trait]
 impl CommandRun for CaptionsRun {
     async fn run(&self, a: CommandRunArgs) -> Result<(), AnyError> {
-        img_job(a, "/caption", false).await
+        let settings = guild_settings(&a).await;
+        let body = json!({
+            "text": text_arg(&a)?,
+            "aspect": a.matches.value_of("aspect"),
+            "style": a.matches.value_of("style").unwrap_or(&settings.caption_style),
+            "gif": gif_options(&a)?,
+        });
+
+        img_job_with(a, "/caption", false, body).await
     }
 }
 
@@ -28,13 +38,26 @@ impl CommandRun for CaptionsRun {
 struct CaptionArgs {
     #[clap(short, long)]
     /// URL pointing to image to caption. If this is not supplied, imgBot will
-    /// automatically pull the latest image from chat.
+    /// automatically pull the latest image from chat. `^2' picks the one before
+    /// it, and `@user' that user's latest image.
     url: Option<String>,
 
     #[clap(short, long)]
     /// Print extra information with some error messages.
     verbose: bool,
 
+    #[clap(short, long)]
+    /// Crop the image to this aspect ratio first (e.g. `16:9', `1:1'), keeping its most
+    /// detailed part. Handy for tall screenshots.
+    aspect: Option<String>,
+
+    #[clap(short, long, possible_values = &["light", "dark"])]
+    /// Caption colors. Defaults to the server's `caption_style' setting.
+    style: Option<String>,
+
+    #[clap(flatten)]
+    gif: GifArgs,
+
     /// Text to caption the image with.
     text: Vec<String>,
 }
@@ -43,5 +66,7 @@ pub fn caption() -> Command {
     Command::builder("caption")
         .run(CaptionsRun)
         .parser::<CaptionArgs>()
+        .alias("cap")
+        .shared_cooldowns(image_cooldowns())
         .build()
 }
@@ -0,0 +1,129 @@
+use crate::command::{Command, CommandRun, CommandRunArgs};
+use crate::process::require_permission;
+use crate::settings::{command_names, GuildSettings, Key};
+use clap::{AppSettings, ArgMatches, Parser, Subcommand};
+use err_context::AnyError;
+use serenity::async_trait;
+use serenity::model::permissions::Permissions;
+use shared::CommandError;
+
+struct ConfigRun;
+
+fn key_arg(matches: &ArgMatches) -> Result<Key, CommandError> {
+    let name = matches.value_of("key").unwrap_or_default();
+    Key::from_name(name).ok_or_else(|| {
+        let names: Vec<&str> = Key::ALL.iter().map(|key| key.name()).collect();
+        CommandError::StringError(format!(
+            "No setting named `{}'. Settings are: {}",
+            name,
+            names.join(", ")
+        ))
+    })
+}
+
+fn show(value: String) -> String {
+    match value.is_empty() {
+        true => "(none)".to_string(),
+        false => value,
+    }
+}
+
+#[async_trait]
+impl CommandRun for ConfigRun {
+    async fn run(&self, a: CommandRunArgs) -> Result<(), AnyError> {
+        let guild = a.invocation.guild_id().ok_or(CommandError::GenericError(
+            "Settings only exist in servers.",
+        ))?;
+
+        let reply = match a.matches.subcommand() {
+            Some(("set", matches)) => {
+                require_permission(&a, Permissions::MANAGE_GUILD, "Manage Server").await?;
+                let key = key_arg(matches)?;
+                let mut value = matches
+                    .values_of("value")
+                    .map(|v| v.collect::<Vec<_>>().join(" "))
+                    .unwrap_or_default();
+
+                let mut w = a.bot.write().await;
+                if key == Key::DisabledCommands {
+                    value = command_names(&value, |name| w.command(name).map(|c| c.name))?;
+                }
+
+                let mut settings = w.settings_for(guild);
+                settings.set(key, &value)?;
+                let stored = settings.get(key);
+                // values equal to the default are dropped, so later changes to it apply
+                let changed = stored != GuildSettings::default().get(key);
+                w.store
+                    .set_setting(guild, key, changed.then_some(stored.as_str()))?;
+                w.settings.insert(guild, settings);
+
+                format!("Set {} to {}.", key.name(), show(stored))
+            }
+            Some(("reset", matches)) => {
+                require_permission(&a, Permissions::MANAGE_GUILD, "Manage Server").await?;
+                let key = key_arg(matches)?;
+
+                let mut w = a.bot.write().await;
+                let mut settings = w.settings_for(guild);
+                settings.reset(key);
+                w.store.set_setting(guild, key, None)?;
+                let value = settings.get(key);
+                w.settings.insert(guild, settings);
+
+                format!("Reset {} to {}.", key.name(), show(value))
+            }
+            Some(("get", matches)) if matches.is_present("key") => {
+                let key = key_arg(matches)?;
+                let settings = a.bot.read().await.settings_for(guild);
+
+                format!(
+                    "**{}**: {}\n{}",
+                    key.name(),
+                    show(settings.get(key)),
+                    key.about()
+                )
+            }
+            _ => {
+                let settings = a.bot.read().await.settings_for(guild);
+
+                Key::ALL
+                    .iter()
+                    .map(|key| format!("**{}**: {}", key.name(), show(settings.get(*key))))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+        };
+
+        a.invocation.say(&a.http, reply).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+/// Show or change this server's settings.
+///
+/// Changing them needs the Manage Server permission.
+struct ConfigArgs {
+    #[clap(subcommand)]
+    action: Option<ConfigAction>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Show a setting and what it does, or every setting.
+    Get { key: Option<String> },
+    /// Change a setting. Lists are separated by spaces or commas.
+    #[clap(setting(AppSettings::TrailingVarArg))]
+    Set { key: String, value: Vec<String> },
+    /// Put a setting back to its default.
+    Reset { key: String },
+}
+
+pub fn config() -> Command {
+    Command::builder("config")
+        .run(ConfigRun)
+        .parser::<ConfigArgs>()
+        .build()
+}
@@ -0,0 +1,54 @@
+use crate::command::{Command, CommandRun, CommandRunArgs};
+use crate::cooldown::image_cooldowns;
+use crate::process::{gif_options, img_job_with, GifArgs};
+
+use clap::Parser;
+use err_context::AnyError;
+use serde_json::json;
+use serenity::async_trait;
+
+struct CropRun;
+
+#[async_trait]
+impl CommandRun for CropRun {
+    async fn run(&self, a: CommandRunArgs) -> Result<(), AnyError> {
+        let body = json!({
+            "aspect": a.matches.value_of("aspect"),
+            "gif": gif_options(&a)?,
+        });
+
+        img_job_with(a, "/crop", false, body).await
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(author = "rwilliaise (lego man)", version = "v0.1.0")]
+/// Crop an image to an aspect ratio, keeping its most detailed part.
+///
+/// The region is picked by looking for edges and texture, and is the same for every frame of
+/// a GIF.
+struct CropArgs {
+    #[clap(short, long)]
+    /// URL pointing to image to crop. If this is not supplied, imgBot will
+    /// automatically pull the latest image from chat. `^2' picks the one before
+    /// it, and `@user' that user's latest image.
+    url: Option<String>,
+
+    #[clap(short, long)]
+    /// Print extra information with some error messages.
+    verbose: bool,
+
+    #[clap(flatten)]
+    gif: GifArgs,
+
+    /// Aspect ratio to crop to, like `16:9', `4/3', `1.5' or `square'.
+    aspect: String,
+}
+
+pub fn crop() -> Command {
+    Command::builder("crop")
+        .run(CropRun)
+        .parser::<CropArgs>()
+        .shared_cooldowns(image_cooldowns())
+        .build()
+}
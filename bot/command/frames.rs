@@ -0,0 +1,185 @@
+use crate::command::{Command, CommandRun, CommandRunArgs};
+use crate::cooldown::image_cooldowns;
+use crate::process::{generic_img_job, img_job_with};
+
+use clap::Parser;
+use err_context::AnyError;
+use serde_json::{json, Map};
+use serenity::async_trait;
+use shared::{CommandError, RangeBound};
+
+/// Longest delay list printed by `gifinfo`, to stay under Discord's message limit.
+static MAX_LISTED_DELAYS: usize = 100;
+
+struct FrameRun;
+
+#[async_trait]
+impl CommandRun for FrameRun {
+    async fn run(&self, a: CommandRunArgs) -> Result<(), AnyError> {
+        let body = match a.matches.value_of("at") {
+            Some(at) => match at.parse::<RangeBound>()? {
+                RangeBound::Frame(index) => json!({ "index": index }),
+                RangeBound::Time(_) => json!({ "time": at }),
+            },
+            None => json!({}),
+        };
+
+        img_job_with(a, "/frame", false, body).await
+    }
+}
+
+struct SpritesheetRun;
+
+#[async_trait]
+impl CommandRun for SpritesheetRun {
+    async fn run(&self, a: CommandRunArgs) -> Result<(), AnyError> {
+        let columns = a.matches.value_of_t::<u32>("columns").ok();
+
+        img_job_with(a, "/spritesheet", false, json!({ "columns": columns })).await
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ImageInfo {
+    format: String,
+    width: u32,
+    height: u32,
+    frame_count: usize,
+    duration_ms: u64,
+    delays_ms: Vec<u64>,
+    loop_count: Option<u16>,
+}
+
+struct InfoRun;
+
+#[async_trait]
+impl CommandRun for InfoRun {
+    async fn run(&self, a: CommandRunArgs) -> Result<(), AnyError> {
+        let response = generic_img_job(&a, "/info", Map::new()).await?;
+
+        if response.error_for_status_ref().is_err() {
+            let text = response.text().await?;
+            return Err(CommandError::StringError(format!(
+                "Image server contact failure.\n\n{}",
+                text.as_str()
+            ))
+            .into());
+        }
+
+        let info = response.json::<ImageInfo>().await?;
+
+        let mut delays = info
+            .delays_ms
+            .iter()
+            .take(MAX_LISTED_DELAYS)
+            .map(|d| d.to_string())
+            .collect::<Vec<String>>()
+            .join(" ");
+        if info.delays_ms.len() > MAX_LISTED_DELAYS {
+            delays += " ...";
+        }
+
+        let looping = match info.loop_count {
+            Some(0) => "loops forever".to_string(),
+            Some(n) => format!("repeats {} times", n),
+            None => "plays once".to_string(),
+        };
+
+        a.invocation
+            .say(
+                &a.http,
+                format!(
+                    "```{} {}x{}, {} frames, {:.2}s, {}\n\ndelays (ms): {}```",
+                    info.format,
+                    info.width,
+                    info.height,
+                    info.frame_count,
+                    info.duration_ms as f64 / 1000.,
+                    looping,
+                    delays
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(author = "rwilliaise (lego man)", version = "v0.1.0")]
+/// Grab a single frame from a GIF as a PNG.
+struct FrameArgs {
+    #[clap(short, long)]
+    /// URL pointing to the GIF. If this is not supplied, imgBot will
+    /// automatically pull the latest image from chat. `^2' picks the one before
+    /// it, and `@user' that user's latest image.
+    url: Option<String>,
+
+    #[clap(short, long)]
+    /// Print extra information with some error messages.
+    verbose: bool,
+
+    /// Frame to grab, either an index counting from 0 (`12`) or a time (`1.5s`, `300ms`).
+    /// Defaults to the first frame.
+    at: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(author = "rwilliaise (lego man)", version = "v0.1.0")]
+/// Lay out every frame of a GIF in a grid.
+struct SpritesheetArgs {
+    #[clap(short, long)]
+    /// URL pointing to the GIF. If this is not supplied, imgBot will
+    /// automatically pull the latest image from chat. `^2' picks the one before
+    /// it, and `@user' that user's latest image.
+    url: Option<String>,
+
+    #[clap(short, long)]
+    /// Print extra information with some error messages.
+    verbose: bool,
+
+    #[clap(short, long)]
+    /// Number of frames per row. Defaults to a roughly square grid.
+    columns: Option<u32>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(author = "rwilliaise (lego man)", version = "v0.1.0")]
+/// Show the size, frame count, frame delays and loop count of an image.
+struct InfoArgs {
+    #[clap(short, long)]
+    /// URL pointing to the image. If this is not supplied, imgBot will
+    /// automatically pull the latest image from chat. `^2' picks the one before
+    /// it, and `@user' that user's latest image.
+    url: Option<String>,
+
+    #[clap(short, long)]
+    /// Print extra information with some error messages.
+    verbose: bool,
+}
+
+pub fn frame() -> Command {
+    Command::builder("frame")
+        .run(FrameRun)
+        .parser::<FrameArgs>()
+        .shared_cooldowns(image_cooldowns())
+        .build()
+}
+
+pub fn spritesheet() -> Command {
+    Command::builder("spritesheet")
+        .run(SpritesheetRun)
+        .parser::<SpritesheetArgs>()
+        .alias("sheet")
+        .shared_cooldowns(image_cooldowns())
+        .build()
+}
+
+pub fn gifinfo() -> Command {
+    Command::builder("gifinfo")
+        .run(InfoRun)
+        .parser::<InfoArgs>()
+        .alias("info")
+        .shared_cooldowns(image_cooldowns())
+        .build()
+}
@@ -1,7 +1,11 @@
+use crate::access::Author;
+use crate::bot::DEFAULT_PREFIX;
 use crate::command::{Command, CommandRun, CommandRunArgs};
+use crate::cooldown::Scope;
 use clap::Parser;
 use err_context::AnyError;
 use serenity::async_trait;
+use std::time::Duration;
 
 struct HelpRun;
 
@@ -9,12 +13,20 @@ struct HelpRun;
 impl CommandRun for HelpRun {
     async fn run(&self, a: CommandRunArgs) -> Result<(), AnyError> {
         let target_command = a.matches.value_of("command");
-        let r = a.bot.read().await;
         if target_command.is_none() {
-            let mut prefix = &"".to_string();
-            if let Some(id) = &a.msg.guild_id {
-                prefix = r.prefix.get(id).unwrap_or(prefix);
-            }
+            // looking the author up calls Discord, so nothing waits on the lock meanwhile
+            let (prefix, owners, settings, commands) = {
+                let r = a.bot.read().await;
+                let prefix = match a.invocation.guild_id() {
+                    Some(id) => r.prefix_for(id).to_string(),
+                    None => DEFAULT_PREFIX.to_string(),
+                };
+                let settings = match a.invocation.guild_id() {
+                    Some(id) => r.settings_for(id),
+                    None => Default::default(),
+                };
+                (prefix, r.owners.clone(), settings, r.commands.clone())
+            };
 
             let mut out = format!(
                 r#"```imgBot v{}
@@ -27,8 +39,17 @@ Type `{}help name' to find out more about the function `{}name'.
                 prefix,
                 prefix
             );
-            for (k, v) in &r.commands {
-                let mut app: clap::App = (v.parser)(k.clone());
+            // only the commands the author can use are listed
+            let author = Author::fetch(&a.http, &a.invocation, &owners).await?;
+            for (k, v) in &commands {
+                if author
+                    .check(v.requirements(), settings.command_roles.get(v.name))
+                    .is_err()
+                {
+                    continue;
+                }
+
+                let mut app: clap::App = v.app(k.clone());
                 let usage = app.render_usage();
                 let usage = &usage[11..];
                 out += format!(
@@ -38,34 +59,43 @@ Type `{}help name' to find out more about the function `{}name'.
                     app.get_about().unwrap_or("No description provided")
                 )
                 .as_str();
+                if !v.aliases.is_empty() {
+                    out += format!("{:<31}Also `{}'\n", "", v.aliases.join("', `")).as_str();
+                }
             }
 
             out += "```";
 
-            a.msg.channel_id.say(a.http.clone(), out).await?;
+            a.invocation.say(&a.http, out).await?;
         } else if target_command.is_some() {
             let target_command = target_command.unwrap();
-            let command = r.commands.get(target_command);
+            let (command, suggestion) = {
+                let r = a.bot.read().await;
+                (r.command(target_command).cloned(), r.suggest(target_command))
+            };
 
             if let None = command {
-                a.msg
-                    .channel_id
-                    .say(
-                        a.http.clone(),
-                        format!("No command named {}", target_command),
-                    )
-                    .await?;
+                let reply = match suggestion {
+                    Some(suggestion) => format!(
+                        "No command named {}. Did you mean `{}`?",
+                        target_command, suggestion
+                    ),
+                    None => format!("No command named {}", target_command),
+                };
+                a.invocation.say(&a.http, reply).await?;
                 return Ok(());
             }
 
             let command = command.unwrap();
-            let mut app: clap::App = (command.parser)(target_command.to_string().clone());
+            let mut app: clap::App = command.app(command.name.to_string());
             let mut buf = Vec::new();
             app.write_long_help(&mut buf)?;
-            a.msg
-                .channel_id
+            if !command.aliases.is_empty() {
+                buf.extend(format!("\nALIASES:\n    {}\n", command.aliases.join(", ")).bytes());
+            }
+            a.invocation
                 .say(
-                    a.http.clone(),
+                    &a.http,
                     format!("```{}```", std::str::from_utf8(buf.as_slice())?.to_string()),
                 )
                 .await?;
@@ -85,5 +115,6 @@ pub fn help() -> Command {
     Command::builder("help")
         .run(HelpRun)
         .parser::<HelpArgs>()
+        .cooldown(Scope::Channel, 3, Duration::from_secs(15))
         .build()
 }
@@ -0,0 +1,88 @@
+use crate::command::{Command, CommandRun, CommandRunArgs};
+use crate::process::require_permission;
+use clap::Parser;
+use err_context::AnyError;
+use serenity::async_trait;
+use serenity::model::permissions::Permissions;
+use shared::CommandError;
+
+/// Longest prefix a guild can pick.
+static MAX_PREFIX_LEN: usize = 8;
+
+struct PrefixRun;
+
+#[async_trait]
+impl CommandRun for PrefixRun {
+    async fn run(&self, a: CommandRunArgs) -> Result<(), AnyError> {
+        let guild = a.invocation.guild_id().ok_or(CommandError::GenericError(
+            "Prefixes can only be set in servers.",
+        ))?;
+        let new_prefix = a.matches.value_of("prefix");
+        let reset = a.matches.is_present("reset");
+
+        if new_prefix.is_none() && !reset {
+            let r = a.bot.read().await;
+            a.invocation
+                .say(
+                    &a.http,
+                    format!(
+                        "The prefix here is `{}`. Mentioning me works too.",
+                        r.prefix_for(guild)
+                    ),
+                )
+                .await?;
+            return Ok(());
+        }
+
+        require_permission(&a, Permissions::MANAGE_GUILD, "Manage Server").await?;
+
+        let new_prefix = match new_prefix {
+            Some(prefix) if !reset => {
+                if prefix.chars().count() > MAX_PREFIX_LEN
+                    || prefix.chars().any(|c| c.is_whitespace() || c == '`')
+                {
+                    return Err(CommandError::StringError(format!(
+                        "Prefixes are at most {} characters, without spaces or backticks.",
+                        MAX_PREFIX_LEN
+                    ))
+                    .into());
+                }
+                Some(prefix.to_string())
+            }
+            _ => None,
+        };
+
+        let mut w = a.bot.write().await;
+        w.store.set_prefix(guild, new_prefix.as_deref())?;
+        match new_prefix {
+            Some(prefix) => w.prefix.insert(guild, prefix),
+            None => w.prefix.remove(&guild),
+        };
+
+        let reply = format!("Prefix set to `{}`.", w.prefix_for(guild));
+        drop(w);
+        a.invocation.say(&a.http, reply).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+/// Show or change the command prefix for this server.
+///
+/// Changing it needs the Manage Server permission.
+struct PrefixArgs {
+    #[clap(short, long)]
+    /// Go back to the default prefix.
+    reset: bool,
+
+    /// The new prefix.
+    prefix: Option<String>,
+}
+
+pub fn prefix() -> Command {
+    Command::builder("prefix")
+        .run(PrefixRun)
+        .parser::<PrefixArgs>()
+        .build()
+}
@@ -0,0 +1,185 @@
+use crate::access::RoleRules;
+use crate::command::{Command, CommandRun, CommandRunArgs};
+use crate::settings::ALWAYS_ALLOWED;
+use clap::{AppSettings, ArgMatches, Parser, Subcommand};
+use err_context::AnyError;
+use serenity::async_trait;
+use serenity::model::id::RoleId;
+use serenity::model::permissions::Permissions;
+use shared::CommandError;
+
+struct RolesRun;
+
+fn parse_role(value: &str) -> Option<RoleId> {
+    let id = value
+        .strip_prefix("<@&")
+        .and_then(|id| id.strip_suffix('>'))
+        .unwrap_or(value);
+    id.parse().ok().map(RoleId)
+}
+
+fn mentions(roles: &[RoleId]) -> String {
+    match roles.is_empty() {
+        true => "(none)".to_string(),
+        false => roles
+            .iter()
+            .map(|role| format!("<@&{}>", role.0))
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+fn describe(command: &str, rules: &RoleRules) -> String {
+    format!(
+        "**{}**: allowed {}, denied {}",
+        command,
+        mentions(&rules.allow),
+        mentions(&rules.deny)
+    )
+}
+
+#[async_trait]
+impl CommandRun for RolesRun {
+    async fn run(&self, a: CommandRunArgs) -> Result<(), AnyError> {
+        let guild = a.invocation.guild_id().ok_or(CommandError::GenericError(
+            "Role rules only exist in servers.",
+        ))?;
+
+        let (command, matches) = match a.matches.subcommand() {
+            Some((_, matches)) if matches.is_present("command") => {
+                (matches.value_of("command").unwrap(), matches)
+            }
+            _ => {
+                let settings = a.bot.read().await.settings_for(guild);
+                let mut lines: Vec<String> = settings
+                    .command_roles
+                    .iter()
+                    .filter(|(_, rules)| !rules.is_empty())
+                    .map(|(command, rules)| describe(command, rules))
+                    .collect();
+                lines.sort();
+                let reply = match lines.is_empty() {
+                    true => "Every role may use every command.".to_string(),
+                    false => lines.join("\n"),
+                };
+                a.invocation.say(&a.http, reply).await?;
+                return Ok(());
+            }
+        };
+
+        let mut w = a.bot.write().await;
+        let command = match w.command(command) {
+            Some(command) => command.name,
+            None => {
+                return Err(
+                    CommandError::StringError(format!("No command named `{}'.", command)).into(),
+                )
+            }
+        };
+        let mut settings = w.settings_for(guild);
+        let mut rules = settings
+            .command_roles
+            .get(command)
+            .cloned()
+            .unwrap_or_default();
+
+        let action = a.matches.subcommand_name().unwrap_or_default();
+        if action != "show" && ALWAYS_ALLOWED.contains(&command) {
+            return Err(CommandError::StringError(format!(
+                "`{}' can't be limited to roles.",
+                command
+            ))
+            .into());
+        }
+
+        let roles = role_args(matches)?;
+        match action {
+            "allow" => {
+                rules.deny.retain(|role| !roles.contains(role));
+                rules.allow.extend(roles);
+            }
+            "deny" => {
+                rules.allow.retain(|role| !roles.contains(role));
+                rules.deny.extend(roles);
+            }
+            "clear" => rules = RoleRules::default(),
+            _ => {}
+        }
+        rules.allow.sort();
+        rules.allow.dedup();
+        rules.deny.sort();
+        rules.deny.dedup();
+
+        if action != "show" {
+            w.store.set_command_roles(guild, command, &rules)?;
+            match rules.is_empty() {
+                true => settings.command_roles.remove(command),
+                false => settings
+                    .command_roles
+                    .insert(command.to_string(), rules.clone()),
+            };
+            w.settings.insert(guild, settings);
+        }
+        drop(w);
+
+        a.invocation.say(&a.http, describe(command, &rules)).await?;
+
+        Ok(())
+    }
+}
+
+fn role_args(matches: &ArgMatches) -> Result<Vec<RoleId>, CommandError> {
+    let values = match matches.values_of("roles") {
+        Some(values) => values,
+        None => return Ok(vec![]),
+    };
+
+    values
+        .flat_map(|value| value.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            parse_role(value).ok_or_else(|| {
+                CommandError::StringError(format!("`{}' isn't a role mention.", value))
+            })
+        })
+        .collect()
+}
+
+#[derive(Parser, Debug)]
+/// Limit commands to some roles, or keep roles from using them.
+///
+/// Needs the Manage Server permission. Admins are never limited.
+struct RolesArgs {
+    #[clap(subcommand)]
+    action: Option<RolesAction>,
+}
+
+#[derive(Subcommand, Debug)]
+enum RolesAction {
+    /// Show which roles may use a command, or every command with rules.
+    Show { command: Option<String> },
+    /// Only let these roles, and others allowed before, use a command.
+    #[clap(setting(AppSettings::TrailingVarArg))]
+    Allow {
+        command: String,
+        #[clap(required = true)]
+        roles: Vec<String>,
+    },
+    /// Keep these roles from using a command.
+    #[clap(setting(AppSettings::TrailingVarArg))]
+    Deny {
+        command: String,
+        #[clap(required = true)]
+        roles: Vec<String>,
+    },
+    /// Let every role use a command again.
+    Clear { command: String },
+}
+
+pub fn roles() -> Command {
+    Command::builder("roles")
+        .run(RolesRun)
+        .parser::<RolesArgs>()
+        .permission(Permissions::MANAGE_GUILD, "Manage Server")
+        .build()
+}
@@ -1,4 +1,5 @@
 use crate::command::{Command, CommandRun, CommandRunArgs};
+use crate::cooldown::image_cooldowns;
 use crate::process::img_job;
 use clap::{AppSettings, Parser};
 use err_context::AnyError;
@@ -34,5 +35,6 @@ pub fn severed() -> Command {
     Command::builder("severed")
         .run(SeveredRun)
         .parser::<SeveredArgs>()
+        .shared_cooldowns(image_cooldowns())
         .build()
 }
@@ -0,0 +1,37 @@
+use crate::command::{Command, CommandRun, CommandRunArgs};
+use clap::Parser;
+use err_context::AnyError;
+use serenity::async_trait;
+
+struct SyncRun;
+
+#[async_trait]
+impl CommandRun for SyncRun {
+    async fn run(&self, a: CommandRunArgs) -> Result<(), AnyError> {
+        let commands = a.bot.read().await.commands.clone();
+        crate::slash::register(&a.http, &commands).await?;
+
+        a.invocation
+            .say(
+                &a.http,
+                format!("Registered {} slash commands.", commands.len()),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+/// Register the slash commands again, e.g. after they failed to at startup.
+///
+/// Only the bot's owners can use this.
+struct SyncArgs {}
+
+pub fn sync() -> Command {
+    Command::builder("sync")
+        .run(SyncRun)
+        .parser::<SyncArgs>()
+        .owner_only()
+        .build()
+}
@@ -0,0 +1,146 @@
+use crate::command::{Command, CommandRun, CommandRunArgs};
+use crate::cooldown::image_cooldowns;
+use crate::process::{gif_options, image_url, img_job_with, resolve_media_url, GifArgs};
+
+use clap::Parser;
+use err_context::AnyError;
+use serde_json::json;
+use serenity::async_trait;
+use shared::FrameRange;
+
+struct TimelineRun(&'static str);
+
+#[async_trait]
+impl CommandRun for TimelineRun {
+    async fn run(&self, a: CommandRunArgs) -> Result<(), AnyError> {
+        let range = a.matches.value_of("range").map(|r| r.to_string());
+        if let Some(range) = &range {
+            range.parse::<FrameRange>()?;
+        }
+
+        let gif = gif_options(&a)?;
+
+        img_job_with(
+            a,
+            format!("/{}", self.0).as_str(),
+            false,
+            json!({ "range": range, "gif": gif }),
+        )
+        .await
+    }
+}
+
+struct ConcatRun;
+
+#[async_trait]
+impl CommandRun for ConcatRun {
+    async fn run(&self, a: CommandRunArgs) -> Result<(), AnyError> {
+        let other = a.matches.value_of("other").unwrap_or_default().to_string();
+        let other = image_url(&a, &other).await?;
+        let other = resolve_media_url(&a, other).await?;
+
+        let gif = gif_options(&a)?;
+
+        img_job_with(a, "/concat", false, json!({ "other_url": other, "gif": gif })).await
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(author = "rwilliaise (lego man)", version = "v0.1.0")]
+/// Play a GIF backwards.
+struct ReverseArgs {
+    #[clap(short, long)]
+    /// URL pointing to the GIF. If this is not supplied, imgBot will
+    /// automatically pull the latest image from chat. `^2' picks the one before
+    /// it, and `@user' that user's latest image.
+    url: Option<String>,
+
+    #[clap(short, long)]
+    /// Print extra information with some error messages.
+    verbose: bool,
+
+    #[clap(flatten)]
+    gif: GifArgs,
+}
+
+#[derive(Parser, Debug)]
+#[clap(author = "rwilliaise (lego man)", version = "v0.1.0")]
+/// Cut a GIF down to a range of frames or time.
+///
+/// Ranges are written `start-end`. Use plain numbers for frames (`5-20`, counting from 0,
+/// end included) or a unit for time (`1.2s-3s`, `500ms-2s`, end excluded). Either end may be
+/// left out: `2s-` keeps everything after two seconds.
+struct TrimArgs {
+    #[clap(short, long)]
+    /// URL pointing to the GIF. If this is not supplied, imgBot will
+    /// automatically pull the latest image from chat. `^2' picks the one before
+    /// it, and `@user' that user's latest image.
+    url: Option<String>,
+
+    #[clap(short, long)]
+    /// Print extra information with some error messages.
+    verbose: bool,
+
+    #[clap(flatten)]
+    gif: GifArgs,
+
+    /// Range to keep, like `1.2s-3s` or `5-20`.
+    range: String,
+}
+
+#[derive(Parser, Debug)]
+#[clap(author = "rwilliaise (lego man)", version = "v0.1.0")]
+/// Play one GIF after another.
+///
+/// The second GIF is scaled to fit the first one's size.
+struct ConcatArgs {
+    #[clap(short, long)]
+    /// URL pointing to the first GIF. If this is not supplied, imgBot will
+    /// automatically pull the latest image from chat. `^2' picks the one before
+    /// it, and `@user' that user's latest image.
+    url: Option<String>,
+
+    #[clap(short, long)]
+    /// Print extra information with some error messages.
+    verbose: bool,
+
+    #[clap(flatten)]
+    gif: GifArgs,
+
+    /// URL pointing to the GIF to play second, or an earlier image like `^2' or `@user'.
+    other: String,
+}
+
+pub fn reverse() -> Command {
+    Command::builder("reverse")
+        .run(TimelineRun("reverse"))
+        .parser::<ReverseArgs>()
+        .alias("rev")
+        .shared_cooldowns(image_cooldowns())
+        .build()
+}
+
+pub fn boomerang() -> Command {
+    Command::builder("boomerang")
+        .run(TimelineRun("boomerang"))
+        .parser::<ReverseArgs>()
+        .shared_cooldowns(image_cooldowns())
+        .about("Play a GIF forwards, then backwards.")
+        .build()
+}
+
+pub fn trim() -> Command {
+    Command::builder("trim")
+        .run(TimelineRun("trim"))
+        .parser::<TrimArgs>()
+        .shared_cooldowns(image_cooldowns())
+        .build()
+}
+
+pub fn concat() -> Command {
+    Command::builder("concat")
+        .run(ConcatRun)
+        .parser::<ConcatArgs>()
+        .shared_cooldowns(image_cooldowns())
+        .build()
+}
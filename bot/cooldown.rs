@@ -0,0 +1,244 @@
+use crate::command::Invocation;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Cooldowns of the commands that hit the image servers, see [`image_cooldowns`].
+static IMAGE_COOLDOWNS: &[Cooldown] = &[
+    Cooldown::new(Scope::User, 3, Duration::from_secs(30)),
+    Cooldown::new(Scope::Channel, 6, Duration::from_secs(30)),
+    Cooldown::new(Scope::Guild, 15, Duration::from_secs(60)),
+];
+
+/// What a cooldown counts uses by.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Scope {
+    User,
+    Channel,
+    /// Falls back to the channel in DMs.
+    Guild,
+}
+
+/// Lets a command run `uses` times per `per` in each user, channel or guild.
+#[derive(Clone, Copy, Debug)]
+pub struct Cooldown {
+    pub scope: Scope,
+    pub uses: usize,
+    pub per: Duration,
+}
+
+impl Cooldown {
+    /// # Panics
+    /// If `uses` is 0, as the command could never run.
+    pub const fn new(scope: Scope, uses: usize, per: Duration) -> Self {
+        assert!(uses > 0, "a cooldown needs at least one use");
+        Self { scope, uses, per }
+    }
+
+    fn bucket(&self, invocation: &Invocation) -> (Scope, u64) {
+        let id = match self.scope {
+            Scope::User => invocation.author_id().0,
+            Scope::Channel => invocation.channel_id().0,
+            Scope::Guild => match invocation.guild_id() {
+                Some(guild) => guild.0,
+                None => invocation.channel_id().0,
+            },
+        };
+
+        (self.scope, id)
+    }
+}
+
+/// The cooldowns of every command that hits the image servers. They all count towards the
+/// same buckets, so spreading requests over several commands doesn't get around them.
+pub fn image_cooldowns() -> Arc<Cooldowns> {
+    static SHARED: OnceLock<Arc<Cooldowns>> = OnceLock::new();
+    SHARED
+        .get_or_init(|| Arc::new(Cooldowns::new(IMAGE_COOLDOWNS.to_vec())))
+        .clone()
+}
+
+/// Cooldowns of one or more commands, with when each bucket was last used.
+#[derive(Default)]
+pub struct Cooldowns {
+    cooldowns: Vec<Cooldown>,
+    uses: Mutex<HashMap<(Scope, u64), VecDeque<Instant>>>,
+}
+
+impl Cooldowns {
+    /// # Panics
+    /// If any of `cooldowns` allows no uses.
+    pub fn new(cooldowns: Vec<Cooldown>) -> Self {
+        assert!(
+            cooldowns.iter().all(|c| c.uses > 0),
+            "a cooldown needs at least one use"
+        );
+
+        Self {
+            cooldowns,
+            uses: Mutex::default(),
+        }
+    }
+
+    /// Counts a use by `invocation` if every bucket it falls in has room, or says how long
+    /// until they all do.
+    pub fn take(&self, invocation: &Invocation) -> Result<(), Duration> {
+        self.take_at(invocation, Instant::now())
+    }
+
+    fn take_at(&self, invocation: &Invocation, now: Instant) -> Result<(), Duration> {
+        if self.cooldowns.is_empty() {
+            return Ok(());
+        }
+
+        let mut uses = self.uses.lock().unwrap();
+
+        // uses older than the cooldown they were counted for no longer matter
+        for ((scope, _), times) in uses.iter_mut() {
+            let per = self
+                .cooldowns
+                .iter()
+                .filter(|c| c.scope == *scope)
+                .map(|c| c.per)
+                .max()
+                .unwrap_or_default();
+            while times.front().is_some_and(|t| now - *t >= per) {
+                times.pop_front();
+            }
+        }
+        uses.retain(|_, times| !times.is_empty());
+
+        let wait = self
+            .cooldowns
+            .iter()
+            .filter_map(|cooldown| {
+                let times = uses.get(&cooldown.bucket(invocation))?;
+                let recent = times.iter().filter(|t| now - **t < cooldown.per).count();
+                match recent >= cooldown.uses {
+                    // the oldest use still counted has to expire first
+                    true => Some(times[times.len() - cooldown.uses] + cooldown.per - now),
+                    false => None,
+                }
+            })
+            .max();
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+
+        // cooldowns of the same scope share a bucket, which only counts the use once
+        let mut buckets = vec![];
+        for bucket in self.cooldowns.iter().map(|c| c.bucket(invocation)) {
+            if !buckets.contains(&bucket) {
+                uses.entry(bucket).or_default().push_back(now);
+                buckets.push(bucket);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn invocation(author: u64, channel: u64, guild: u64) -> Invocation {
+        let msg = json!({
+            "id": "1",
+            "channel_id": channel.to_string(),
+            "guild_id": guild.to_string(),
+            "author": {
+                "id": author.to_string(),
+                "username": "a",
+                "discriminator": "0001",
+                "avatar": null
+            },
+            "content": "",
+            "timestamp": "2022-01-01T00:00:00Z",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [],
+            "embeds": [],
+            "pinned": false,
+            "type": 0
+        });
+
+        Invocation::Message(Box::new(serde_json::from_value(msg).unwrap()))
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn uses_expire() {
+        let cooldowns = Cooldowns::new(vec![Cooldown::new(Scope::User, 2, secs(10))]);
+        let (a, start) = (invocation(1, 10, 100), Instant::now());
+
+        assert_eq!(cooldowns.take_at(&a, start), Ok(()));
+        assert_eq!(cooldowns.take_at(&a, start + secs(1)), Ok(()));
+        assert_eq!(cooldowns.take_at(&a, start + secs(2)), Err(secs(8)));
+        assert_eq!(cooldowns.take_at(&a, start + secs(5)), Err(secs(5)));
+        assert_eq!(cooldowns.take_at(&a, start + secs(10)), Ok(()));
+        assert_eq!(cooldowns.take_at(&a, start + secs(10)), Err(secs(1)));
+
+        // someone else has their own bucket
+        assert_eq!(cooldowns.take_at(&invocation(2, 10, 100), start), Ok(()));
+    }
+
+    #[test]
+    fn waits_for_the_fullest_scope() {
+        let cooldowns = Cooldowns::new(vec![
+            Cooldown::new(Scope::User, 2, secs(10)),
+            Cooldown::new(Scope::Guild, 3, secs(30)),
+        ]);
+        let (a, b) = (invocation(1, 10, 100), invocation(2, 11, 100));
+        let start = Instant::now();
+
+        assert_eq!(cooldowns.take_at(&a, start), Ok(()));
+        assert_eq!(cooldowns.take_at(&a, start), Ok(()));
+        assert_eq!(cooldowns.take_at(&a, start + secs(1)), Err(secs(9)));
+        assert_eq!(cooldowns.take_at(&b, start + secs(1)), Ok(()));
+        // b has a use left, but the guild doesn't
+        assert_eq!(cooldowns.take_at(&b, start + secs(2)), Err(secs(28)));
+        // a's own limit has passed, the guild's hasn't
+        assert_eq!(cooldowns.take_at(&a, start + secs(10)), Err(secs(20)));
+        // another guild isn't affected
+        assert_eq!(cooldowns.take_at(&invocation(1, 12, 200), start), Ok(()));
+    }
+
+    #[test]
+    fn counts_a_use_once_per_bucket() {
+        // both are per user, so share a bucket
+        let cooldowns = Cooldowns::new(vec![
+            Cooldown::new(Scope::User, 2, secs(10)),
+            Cooldown::new(Scope::User, 3, secs(60)),
+        ]);
+        let (a, start) = (invocation(1, 10, 100), Instant::now());
+
+        assert_eq!(cooldowns.take_at(&a, start), Ok(()));
+        assert_eq!(cooldowns.take_at(&a, start), Ok(()));
+        assert_eq!(cooldowns.take_at(&a, start), Err(secs(10)));
+        assert_eq!(cooldowns.take_at(&a, start + secs(10)), Ok(()));
+        assert_eq!(cooldowns.take_at(&a, start + secs(20)), Err(secs(40)));
+    }
+
+    #[test]
+    fn image_commands_share_cooldowns() {
+        assert!(Arc::ptr_eq(&image_cooldowns(), &image_cooldowns()));
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one use")]
+    fn needs_a_use() {
+        Cooldowns::new(vec![Cooldown {
+            scope: Scope::User,
+            uses: 0,
+            per: secs(10),
+        }]);
+    }
+}
@@ -0,0 +1,248 @@
+use serenity::model::id::{ChannelId, MessageId, UserId};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Most images remembered per channel.
+static CAPACITY: usize = 25;
+/// How long images are remembered when `IMGBOT_HISTORY_TTL` isn't set.
+static DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// An image posted in a channel.
+#[derive(Clone, Debug)]
+pub struct Image {
+    pub url: String,
+    pub author: UserId,
+    /// Lowercased, so `@name` can find it without a mention.
+    pub author_name: String,
+    pub message: MessageId,
+    pub posted: Instant,
+}
+
+/// Points at an image in a channel's history, given in place of a URL.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImageRef {
+    /// `^` or `^1` is the latest image, `^2` the one before it, and so on.
+    Back(usize),
+    /// `@name` or a mention, for that user's latest image.
+    Mention(UserId),
+    Name(String),
+}
+
+impl ImageRef {
+    pub fn parse(value: &str) -> Option<ImageRef> {
+        if let Some(back) = value.strip_prefix('^') {
+            return match back {
+                "" => Some(ImageRef::Back(1)),
+                back => back.parse().ok().filter(|n| *n > 0).map(ImageRef::Back),
+            };
+        }
+
+        let mention = value
+            .strip_prefix("<@")
+            .and_then(|id| id.strip_suffix('>'))
+            .map(|id| id.trim_start_matches('!'));
+        if let Some(id) = mention {
+            return id.parse().ok().map(|id| ImageRef::Mention(UserId(id)));
+        }
+
+        match value.strip_prefix('@') {
+            Some(name) if !name.is_empty() => Some(ImageRef::Name(name.to_lowercase())),
+            _ => None,
+        }
+    }
+}
+
+/// The latest images of every channel, oldest first, forgotten after a while.
+pub struct ImageHistory {
+    channels: HashMap<ChannelId, VecDeque<Image>>,
+    ttl: Duration,
+}
+
+impl ImageHistory {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            channels: HashMap::new(),
+            ttl,
+        }
+    }
+
+    /// Remembers images for `IMGBOT_HISTORY_TTL` seconds, or an hour.
+    ///
+    /// # Panics
+    /// If `IMGBOT_HISTORY_TTL` is set but isn't a number of seconds.
+    pub fn from_env() -> Self {
+        let ttl = match std::env::var("IMGBOT_HISTORY_TTL") {
+            Ok(ttl) => {
+                Duration::from_secs(ttl.parse().expect("IMGBOT_HISTORY_TTL is not a number"))
+            }
+            Err(_) => DEFAULT_TTL,
+        };
+
+        Self::new(ttl)
+    }
+
+    /// Remembers `image`, forgetting what expired by the time it was posted in every channel,
+    /// so channels that went quiet don't stay around.
+    pub fn push(&mut self, channel: ChannelId, image: Image) {
+        let ttl = self.ttl;
+        let posted = image.posted;
+        self.channels.retain(|_, images| {
+            while images
+                .front()
+                .is_some_and(|oldest| posted.saturating_duration_since(oldest.posted) >= ttl)
+            {
+                images.pop_front();
+            }
+            !images.is_empty()
+        });
+
+        let images = self.channels.entry(channel).or_default();
+        while images.len() >= CAPACITY {
+            images.pop_front();
+        }
+        images.push_back(image);
+    }
+
+    /// Forgets the images of a deleted message.
+    pub fn remove_message(&mut self, channel: ChannelId, message: MessageId) {
+        if let Some(images) = self.channels.get_mut(&channel) {
+            images.retain(|image| image.message != message);
+            if images.is_empty() {
+                self.channels.remove(&channel);
+            }
+        }
+    }
+
+    /// The channel's images that haven't expired, latest first.
+    fn recent(&self, channel: ChannelId) -> impl Iterator<Item = &Image> {
+        let now = Instant::now();
+        self.channels
+            .get(&channel)
+            .into_iter()
+            .flat_map(|images| images.iter().rev())
+            .filter(move |image| now - image.posted < self.ttl)
+    }
+
+    pub fn latest(&self, channel: ChannelId) -> Option<&Image> {
+        self.recent(channel).next()
+    }
+
+    pub fn find(&self, channel: ChannelId, image: &ImageRef) -> Option<&Image> {
+        match image {
+            ImageRef::Back(n) => self.recent(channel).nth(n - 1),
+            ImageRef::Mention(user) => self.recent(channel).find(|i| i.author == *user),
+            ImageRef::Name(name) => self.recent(channel).find(|i| i.author_name == *name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(message: u64, author: u64, name: &str, age: Duration) -> Image {
+        Image {
+            url: format!("https://example.com/{}.png", message),
+            author: UserId(author),
+            author_name: name.to_string(),
+            message: MessageId(message),
+            posted: Instant::now() - age,
+        }
+    }
+
+    fn messages<'a>(images: impl Iterator<Item = &'a Image>) -> Vec<u64> {
+        images.map(|image| image.message.0).collect()
+    }
+
+    #[test]
+    fn parses_image_refs() {
+        assert_eq!(ImageRef::parse("^"), Some(ImageRef::Back(1)));
+        assert_eq!(ImageRef::parse("^2"), Some(ImageRef::Back(2)));
+        assert_eq!(ImageRef::parse("^0"), None);
+        assert_eq!(ImageRef::parse("^x"), None);
+        assert_eq!(
+            ImageRef::parse("<@123>"),
+            Some(ImageRef::Mention(UserId(123)))
+        );
+        assert_eq!(
+            ImageRef::parse("<@!123>"),
+            Some(ImageRef::Mention(UserId(123)))
+        );
+        assert_eq!(ImageRef::parse("<@abc>"), None);
+        assert_eq!(
+            ImageRef::parse("@Name"),
+            Some(ImageRef::Name("name".to_string()))
+        );
+        assert_eq!(ImageRef::parse("@"), None);
+        assert_eq!(ImageRef::parse("https://example.com/a.png"), None);
+    }
+
+    #[test]
+    fn finds_the_latest_images_first() {
+        let channel = ChannelId(1);
+        let mut history = ImageHistory::new(DEFAULT_TTL);
+        let second = Duration::from_secs(1);
+        history.push(channel, image(1, 10, "alice", second * 3));
+        history.push(channel, image(2, 20, "bob", second * 2));
+        history.push(channel, image(3, 10, "alice", second));
+
+        let find = |image| history.find(channel, &image).map(|i| i.message.0);
+        assert_eq!(history.latest(channel).map(|i| i.message.0), Some(3));
+        assert_eq!(find(ImageRef::Back(1)), Some(3));
+        assert_eq!(find(ImageRef::Back(3)), Some(1));
+        assert_eq!(find(ImageRef::Back(4)), None);
+        assert_eq!(find(ImageRef::Mention(UserId(10))), Some(3));
+        assert_eq!(find(ImageRef::Name("bob".to_string())), Some(2));
+        assert_eq!(find(ImageRef::Name("carol".to_string())), None);
+        assert_eq!(history.latest(ChannelId(2)).map(|i| i.message.0), None);
+    }
+
+    #[test]
+    fn forgets_expired_images() {
+        let channel = ChannelId(1);
+        let mut history = ImageHistory::new(Duration::from_secs(60));
+        history.push(channel, image(1, 10, "alice", Duration::from_secs(90)));
+        history.push(channel, image(2, 10, "alice", Duration::from_secs(45)));
+        assert_eq!(messages(history.recent(channel)), [2]);
+
+        history.push(channel, image(3, 10, "alice", Duration::ZERO));
+        assert_eq!(messages(history.channels[&channel].iter()), [2, 3]);
+    }
+
+    #[test]
+    fn keeps_at_most_capacity_images() {
+        let channel = ChannelId(1);
+        let mut history = ImageHistory::new(DEFAULT_TTL);
+        for message in 0..CAPACITY as u64 + 5 {
+            history.push(channel, image(message, 10, "alice", Duration::ZERO));
+        }
+
+        let kept = messages(history.channels[&channel].iter());
+        assert_eq!(kept.len(), CAPACITY);
+        assert_eq!(kept.first(), Some(&5));
+    }
+
+    #[test]
+    fn forgets_quiet_channels() {
+        let mut history = ImageHistory::new(Duration::from_secs(60));
+        history.push(ChannelId(1), image(1, 10, "alice", Duration::from_secs(90)));
+        history.push(ChannelId(2), image(2, 10, "alice", Duration::ZERO));
+
+        assert!(!history.channels.contains_key(&ChannelId(1)));
+        assert!(history.channels.contains_key(&ChannelId(2)));
+    }
+
+    #[test]
+    fn forgets_deleted_messages() {
+        let channel = ChannelId(1);
+        let mut history = ImageHistory::new(DEFAULT_TTL);
+        history.push(channel, image(1, 10, "alice", Duration::ZERO));
+        history.push(channel, image(2, 10, "alice", Duration::ZERO));
+
+        history.remove_message(channel, MessageId(2));
+        assert_eq!(messages(history.recent(channel)), [1]);
+
+        history.remove_message(channel, MessageId(1));
+        assert!(history.channels.is_empty());
+    }
+}
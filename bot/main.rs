@@ -1,10 +1,20 @@
+mod access;
 mod bot;
 mod command;
+mod cooldown;
+mod history;
 mod process;
-mod tenor;
+mod provider;
+mod settings;
+mod slash;
+mod source;
+mod store;
+mod suggest;
 
 #[tokio::main]
 async fn main() {
+    shared::init_logging();
+
     let mut bot = bot::Bot::new().await.expect("Bot creation err");
     bot.start().await.unwrap();
 }
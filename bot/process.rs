@@ -1,102 +1,173 @@
-use crate::command::CommandRunArgs;
+use crate::access::Author;
+use crate::command::{CommandRunArgs, Invocation};
 use err_context::AnyError;
-use reqwest::header::CONTENT_TYPE;
-use reqwest::Response;
-use serde_json::json;
-use serenity::http::{AttachmentType, Http};
-use serenity::model::channel::{Message, MessageReference};
-use shared::CommandError;
-use std::borrow::{Borrow, Cow};
+use reqwest::header::{CONTENT_TYPE, RETRY_AFTER};
+use reqwest::{Response, StatusCode};
+use serde_json::{json, Map, Value};
+use serenity::http::Http;
+use serenity::model::channel::{Channel, Message};
+use serenity::model::id::{ChannelId, GuildId, MessageId};
+use serenity::model::permissions::Permissions;
+use shared::{CommandError, REQUEST_ID_HEADER};
+use std::borrow::Borrow;
+use std::collections::HashSet;
 use std::sync::Arc;
-use std::time::Duration;
-use linkify::LinkFinder;
-use crate::bot::BotLock;
-
-pub async fn get_first_attachment(message: &Message) -> Option<String> {
-    for attachment in &message.attachments {
-        if let Some(content) = &attachment.content_type {
-            if content.starts_with("image") {
-                return Some(attachment.url.clone());
-            }
-        }
-    }
+use std::time::{Duration, Instant};
+use crate::history::ImageRef;
+use crate::settings::{GuildSettings, NsfwRule};
 
-    None
+/// How often a running job's progress is checked.
+static POLL_INTERVAL: Duration = Duration::from_millis(1000);
+/// Jobs still unfinished after this long are given up on.
+static JOB_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Header telling the image server how long it has for a job.
+static DEADLINE_HEADER: &str = "X-Deadline-Ms";
+
+async fn url_from_reply(a: &CommandRunArgs) -> Option<String> {
+    let msg = a.invocation.target(&a.http).await?;
+    crate::source::first_image(&msg)
 }
 
-pub async fn get_first_url(content: &String) -> Option<String> {
-    let finder = LinkFinder::new();
-    let links: Vec<_> = finder.links(content).collect();
+/// Works out what the author of a command may do in its guild.
+pub async fn author_permissions(
+    http: &Http,
+    invocation: &Invocation,
+) -> Result<Permissions, AnyError> {
+    if invocation.guild_id().is_none() {
+        return Err(CommandError::GenericError("This only works in servers.").into());
+    }
 
-    for link in links {
-        let string = link.as_str().to_string().clone();
-        if string.ends_with(".gif") || string.ends_with(".png") || string.ends_with(".jpg")
-        {
-            return Some(string)
-        } else {
-            let url = url::Url::parse(link.as_str());
+    Ok(Author::fetch(http, invocation, &HashSet::new()).await?.permissions)
+}
 
-            if let Ok(url) = url {
-                if url.host_str() == Some("tenor.com") {
-                    return Some(string)
-                }
-            }
-        }
+/// Fails unless the command's author has `permission`, called `name` in the error.
+pub async fn require_permission(
+    a: &CommandRunArgs,
+    permission: Permissions,
+    name: &'static str,
+) -> Result<(), AnyError> {
+    match author_permissions(&a.http, &a.invocation).await?.contains(permission) {
+        true => Ok(()),
+        false => Err(CommandError::MissingPermission(name).into()),
     }
-
-    None
 }
 
-async fn url_from_reply(a: &CommandRunArgs) -> Option<String> {
-    let reference = &a.msg.message_reference;
+/// Turns links to GIF pages (e.g. tenor.com) into links to the media itself.
+pub async fn resolve_media_url(a: &CommandRunArgs, img_url: String) -> Result<String, AnyError> {
+    // resolving can load a page, which shouldn't hold up everything waiting on the lock
+    let providers = a.bot.read().await.providers.clone();
+    Ok(providers.resolve(&img_url).await?)
+}
 
-    if let None = reference {
-        return None
+/// The image in a linked message, as long as the author could see the message themselves.
+async fn linked_image(
+    a: &CommandRunArgs,
+    guild: GuildId,
+    channel: ChannelId,
+    message: MessageId,
+) -> Result<String, AnyError> {
+    if a.invocation.guild_id() != Some(guild) {
+        return Err(
+            CommandError::GenericError("Only messages from this server can be linked.").into(),
+        );
     }
 
-    let reply = reference.as_ref().unwrap();
+    // the link's guild is only what it claims, so the channel has to be checked too
+    let in_guild = |channel: Channel| channel.guild().filter(|channel| channel.guild_id == guild);
+    let mut target = channel
+        .to_channel(&a.http)
+        .await
+        .ok()
+        .and_then(in_guild)
+        .ok_or(CommandError::GenericError("I can't see that message."))?;
+    // threads take their permissions from the channel they're in
+    if let (Some(_), Some(parent)) = (&target.thread_metadata, target.category_id) {
+        target = parent
+            .to_channel(&a.http)
+            .await
+            .ok()
+            .and_then(in_guild)
+            .ok_or(CommandError::GenericError("I can't see that message."))?;
+    }
 
-    if reply.channel_id == a.msg.channel_id {
-        let msg = a.http.get_message(reply.channel_id.0, reply.message_id.unwrap().0).await;
+    let author = Author::fetch(&a.http, &a.invocation, &HashSet::new()).await?;
+    let needed = Permissions::READ_MESSAGES | Permissions::READ_MESSAGE_HISTORY;
+    if !author.channel_permissions(&target).contains(needed) {
+        return Err(CommandError::GenericError("You can't see that message.").into());
+    }
 
-        if let Err(e) = msg {
-            return None
-        }
+    let msg = channel
+        .message(&a.http, message)
+        .await
+        .map_err(|_| CommandError::GenericError("I can't see that message."))?;
+    crate::source::first_image(&msg)
+        .ok_or_else(|| CommandError::GenericError("That message has no image.").into())
+}
 
-        let msg = msg.as_ref().unwrap();
-        let url = get_first_url(&msg.content).await;
+/// Turns a URL argument into a URL, looking up message links, custom emoji and references to
+/// earlier images like `^2` or `@user` in the channel's history. A mentioned user who hasn't
+/// posted an image lately stands for their avatar.
+pub async fn image_url(a: &CommandRunArgs, value: &str) -> Result<String, AnyError> {
+    if let Some((guild, channel, message)) = crate::source::message_link(value) {
+        return linked_image(a, guild, channel, message).await;
+    }
+    if let Some(url) = crate::source::emoji(value).into_iter().next() {
+        return Ok(url);
+    }
+    let image = match ImageRef::parse(value) {
+        Some(image) => image,
+        None => return Ok(value.to_string()),
+    };
 
-        if let None = url {
-            if let Some(url) = get_first_attachment(msg).await {
-                return Some(url);
-            }
+    let found = a
+        .bot
+        .read()
+        .await
+        .history
+        .find(a.invocation.channel_id(), &image)
+        .map(|found| found.url.clone());
+    if let Some(url) = found {
+        return Ok(url);
+    }
 
-            return None
+    match image {
+        // someone who hasn't posted an image lately still has an avatar
+        ImageRef::Mention(user) => {
+            let user = user
+                .to_user(&a.http)
+                .await
+                .map_err(|_| CommandError::GenericError("I can't find that user."))?;
+            Ok(user.face())
         }
-
-        return Some(url.unwrap());
+        _ => Err(CommandError::StringError(format!(
+            "No image `{}` found in recent messages.",
+            value
+        ))
+        .into()),
     }
-
-    None
 }
 
-async fn generic_img_job(a: &CommandRunArgs, request_url: &str) -> Result<Response, AnyError> {
-    let mut r = a.bot.write().await;
-
-    r.check_health().await?;
-
-    let mut img_url: String;
+/// Finds the image a command should work on: its `-u` argument, the message it replies to,
+/// or the latest image in the channel.
+async fn source_url(a: &CommandRunArgs) -> Result<String, AnyError> {
+    let img_url: String;
     let url = a.matches.value_of("url");
     if url.is_none() {
 
         if let Some(url) = url_from_reply(a).await {
             img_url = url.clone();
         } else {
-            let url = r.latest_image.get(&a.msg.channel_id);
+            let url = a
+                .bot
+                .read()
+                .await
+                .history
+                .latest(a.invocation.channel_id())
+                .map(|image| image.url.clone());
 
             match url {
                 Some(url) => {
-                    img_url = url.clone();
+                    img_url = url;
                 }
                 None => {
                     return Err(CommandError::GenericError(
@@ -107,26 +178,30 @@ async fn generic_img_job(a: &CommandRunArgs, request_url: &str) -> Result<Respon
             }
         }
     } else {
-        img_url = url.unwrap().to_string();
+        img_url = image_url(a, url.unwrap()).await?;
     }
 
-    let url = url::Url::parse(img_url.as_str());
-    if let Ok(url) = url {
-        if url.host_str() == Some("tenor.com") {
-            let gif = r.tenor_client.fetch(img_url).await?;
-            img_url = gif.url;
-        }
-    }
+    resolve_media_url(a, img_url).await
+}
 
-    let request = json!({
-        "target_url": img_url,
-        "text": a.matches.values_of("text").ok_or(CommandError::GenericError("No text provided"))?.collect::<Vec<&str>>().join(" ")
-    });
+pub async fn generic_img_job(
+    a: &CommandRunArgs,
+    request_url: &str,
+    mut body: Map<String, Value>,
+) -> Result<Response, AnyError> {
+    // looking up the image can call Discord, so it's done before taking the lock
+    let img_url = source_url(a).await?;
+    body.insert("target_url".to_string(), Value::String(img_url));
+
+    let r = a.bot.read().await;
+
+    r.check_health().await?;
 
     let out = r
         .construct_post(request_url)
         .await
-        .json(&request)
+        .header(REQUEST_ID_HEADER, &a.request_id)
+        .json(&body)
         .send()
         .await;
 
@@ -137,30 +212,72 @@ async fn generic_img_job(a: &CommandRunArgs, request_url: &str) -> Result<Respon
     return Ok(out.unwrap());
 }
 
-async fn exploitable_img_job(
-    a: &CommandRunArgs,
-    request_url: &str,
-) -> Result<Response, AnyError> {
-    let r = a.bot.write().await;
+/// Joins the trailing `text` argument of a command.
+pub fn text_arg(a: &CommandRunArgs) -> Result<String, CommandError> {
+    Ok(a
+        .matches
+        .values_of("text")
+        .ok_or(CommandError::GenericError("No text provided"))?
+        .collect::<Vec<&str>>()
+        .join(" "))
+}
 
-    r.check_health().await?;
+/// GIF encoding flags, shared by every command that can output an animation.
+#[derive(clap::Args, Debug)]
+pub struct GifArgs {
+    #[clap(long, possible_values = &["none", "floyd-steinberg", "ordered"])]
+    /// Dithering used when an animation's colors are reduced.
+    dither: Option<String>,
 
-    let request = json!({
-        "text": a.matches.values_of("text").ok_or(CommandError::GenericError("No text provided"))?.collect::<Vec<&str>>().join(" ")
-    });
+    #[clap(long, possible_values = &["local", "global"])]
+    /// Whether every frame gets its own palette, or all frames share one.
+    palette: Option<String>,
 
-    let out = r
-        .construct_post(request_url)
-        .await
-        .json(&request)
-        .send()
-        .await;
+    #[clap(long)]
+    /// Color quality, from 1 (fastest) to 100 (best).
+    quality: Option<u8>,
 
-    if let Err(e) = out {
-        return Err(Box::new(e));
+    #[clap(long)]
+    /// How often the animation repeats: `forever', `once' or a number.
+    loops: Option<String>,
+
+    #[clap(long)]
+    /// Make transparent pixels opaque.
+    no_transparency: bool,
+}
+
+/// Builds the `gif` options object from a command's [`GifArgs`].
+pub fn gif_options(a: &CommandRunArgs) -> Result<Value, CommandError> {
+    let mut options = Map::new();
+
+    if let Some(dither) = a.matches.value_of("dither") {
+        options.insert("dither".to_string(), json!(dither.replace('-', "_")));
     }
 
-    return Ok(out.unwrap());
+    if let Some(palette) = a.matches.value_of("palette") {
+        options.insert("palette".to_string(), json!(palette));
+    }
+
+    if let Ok(quality) = a.matches.value_of_t::<u8>("quality") {
+        options.insert("quality".to_string(), json!(quality));
+    }
+
+    if let Some(loops) = a.matches.value_of("loops") {
+        let loops = match loops {
+            "forever" => json!(0),
+            "once" => Value::Null,
+            n => json!(n.parse::<u16>().map_err(|_| CommandError::GenericError(
+                "--loops must be `forever', `once' or a number"
+            ))?),
+        };
+        options.insert("loop_count".to_string(), loops);
+    }
+
+    if a.matches.is_present("no-transparency") {
+        options.insert("transparency".to_string(), json!(false));
+    }
+
+    Ok(Value::Object(options))
 }
 
 pub async fn img_job(
@@ -168,39 +285,231 @@ pub async fn img_job(
     request_url: &str,
     exploitable: bool,
 ) -> Result<(), AnyError> {
-    let mut msg = a
-        .msg
-        .channel_id
-        .say(a.http.clone(), "1/3 🟩⬛⬛ Requesting")
-        .await?;
+    let text = text_arg(&a)?;
 
-    let response = match exploitable {
-        true => exploitable_img_job(&a, request_url).await,
-        false => generic_img_job(&a, request_url).await,
-    };
+    img_job_with(a, request_url, exploitable, json!({ "text": text })).await
+}
 
-    msg.edit(a.http.clone(), |m| m.content("2/3 🟩🟩⬛ Processing"))
-        .await?;
+fn timeout_error() -> AnyError {
+    CommandError::GenericError("The image took too long to process.").into()
+}
 
-    if let Err(e) = response {
-        crate::process::delay_delete(a.http.clone(), msg, Duration::from_millis(1000)).await;
-        return Err(e);
-    }
+/// Turns a 503 or 429 from the image server into a friendly error.
+fn busy_error(response: &Response) -> AnyError {
+    let retry = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<u64>().ok())
+        .unwrap_or(5);
+
+    CommandError::StringError(format!(
+        "The image server is busy right now. Try again in {}s.",
+        retry
+    ))
+    .into()
+}
 
-    let response = response?;
+/// Fails with the server's error text if `response` is not a success.
+async fn check_response(response: Response) -> Result<Response, AnyError> {
+    match response.status() {
+        StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS => {
+            return Err(busy_error(&response))
+        }
+        StatusCode::GATEWAY_TIMEOUT => return Err(timeout_error()),
+        _ => (),
+    }
 
     match response.error_for_status_ref() {
-        Ok(_) => (),
+        Ok(_) => Ok(response),
         Err(_) => {
-            crate::process::delay_delete(a.http.clone(), msg, Duration::from_millis(1000)).await;
             let text = response.text().await?;
-            return Err(CommandError::StringError(format!(
+            Err(CommandError::StringError(format!(
                 "Image server contact failure.\n\n{}",
                 text.as_str()
             ))
-            .into());
+            .into())
+        }
+    }
+}
+
+fn progress_message(status: &Value) -> String {
+    match status["state"].as_str() {
+        Some("queued") => format!(
+            "2/3 🟩🟩⬛ Queued ({} ahead)",
+            status["ahead"].as_u64().unwrap_or(0)
+        ),
+        _ => {
+            let done = status["frames_done"].as_u64().unwrap_or(0);
+            let total = status["frames_total"].as_u64().unwrap_or(0).max(1);
+            let stage = match status["stage"].as_str() {
+                Some("encoding") => "Encoding",
+                _ => "Processing",
+            };
+
+            format!("2/3 🟩🟩⬛ {} {}%", stage, done * 100 / total)
+        }
+    }
+}
+
+/// Submits an image job to the server's `/jobs`, then follows its progress in `msg` until
+/// it finishes. Returns the response holding the result, or `None` if the job was cancelled
+/// because its command was deleted.
+async fn run_job(
+    a: &CommandRunArgs,
+    msg: &mut Message,
+    request_url: &str,
+    exploitable: bool,
+    mut body: Map<String, Value>,
+) -> Result<Option<Response>, AnyError> {
+    if !exploitable {
+        let img_url = source_url(a).await?;
+        body.insert("target_url".to_string(), Value::String(img_url));
+    }
+
+    let (client, submit_url) = {
+        let r = a.bot.read().await;
+
+        r.check_health().await?;
+
+        (r.client.clone(), r.get_url("/jobs"))
+    };
+
+    let response = client
+        .post(submit_url)
+        .header(REQUEST_ID_HEADER, &a.request_id)
+        .header(DEADLINE_HEADER, JOB_TIMEOUT.as_millis().to_string())
+        .json(&json!({ "endpoint": request_url, "request": body }))
+        .send()
+        .await?;
+    let status: Value = check_response(response).await?.json().await?;
+
+    // jobs live on the replica that took them, which is where `url' points
+    let job_url = status["url"]
+        .as_str()
+        .ok_or(CommandError::GenericError("Malformed job response"))?;
+    let job_url = match job_url.starts_with("http") {
+        true => job_url.to_string(),
+        false => a.bot.read().await.get_url(job_url),
+    };
+    a.bot
+        .write()
+        .await
+        .running_jobs
+        .insert(a.invocation.id(), job_url.clone());
+
+    let started = Instant::now();
+    let mut shown = String::new();
+    loop {
+        let response = client
+            .get(&job_url)
+            .header(REQUEST_ID_HEADER, &a.request_id)
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            // deleting the command forgets the job before cancelling it; anything else means
+            // the job expired or its replica went away
+            let forgotten = !a
+                .bot
+                .read()
+                .await
+                .running_jobs
+                .contains_key(&a.invocation.id());
+            return match forgotten {
+                true => Ok(None),
+                false => Err(CommandError::GenericError(
+                    "The image server lost track of the image. Try again.",
+                )
+                .into()),
+            };
+        }
+        let status: Value = check_response(response).await?.json().await?;
+
+        if matches!(status["state"].as_str(), Some("done") | Some("failed")) {
+            break;
         }
+
+        let content = progress_message(&status);
+        if content != shown {
+            msg.edit(a.http.clone(), |m| m.content(&content)).await?;
+            shown = content;
+        }
+
+        if started.elapsed() > JOB_TIMEOUT + POLL_INTERVAL {
+            let _ = client
+                .delete(&job_url)
+                .header(REQUEST_ID_HEADER, &a.request_id)
+                .send()
+                .await;
+            return Err(timeout_error());
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    let response = client
+        .get(format!("{}/result", job_url))
+        .header(REQUEST_ID_HEADER, &a.request_id)
+        .send()
+        .await?;
+    Ok(Some(check_response(response).await?))
+}
+
+/// The settings of the guild a command was sent in.
+pub async fn guild_settings(a: &CommandRunArgs) -> GuildSettings {
+    match a.invocation.guild_id() {
+        Some(guild) => a.bot.read().await.settings_for(guild),
+        None => GuildSettings::default(),
+    }
+}
+
+/// Fails if the guild only allows image commands in NSFW channels and this isn't one.
+async fn check_nsfw(a: &CommandRunArgs, settings: &GuildSettings) -> Result<(), AnyError> {
+    if settings.nsfw == NsfwRule::Anywhere {
+        return Ok(());
     }
+
+    match a.invocation.channel_id().to_channel(&a.http).await? {
+        Channel::Guild(channel) if channel.nsfw => Ok(()),
+        _ => Err(CommandError::GenericError(
+            "Image commands only work in NSFW channels on this server.",
+        )
+        .into()),
+    }
+}
+
+/// Runs an image job, sending `body` to the image server. For non-exploitable jobs,
+/// `target_url` is filled in from the resolved source image.
+pub async fn img_job_with(
+    a: CommandRunArgs,
+    request_url: &str,
+    exploitable: bool,
+    body: Value,
+) -> Result<(), AnyError> {
+    let body = match body {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    };
+
+    let settings = guild_settings(&a).await;
+    check_nsfw(&a, &settings).await?;
+
+    let mut msg = a.invocation.say(&a.http, "1/3 🟩⬛⬛ Requesting").await?;
+
+    let response = run_job(&a, &mut msg, request_url, exploitable, body).await;
+    a.bot.write().await.running_jobs.remove(&a.invocation.id());
+
+    let response = match response {
+        Ok(Some(response)) => response,
+        Ok(None) => {
+            msg.delete(a.http.clone()).await?;
+            return Ok(());
+        }
+        Err(e) => {
+            crate::process::delay_delete(a.http.clone(), msg, Duration::from_millis(1000)).await;
+            return Err(e);
+        }
+    };
     let mime = response
         .headers()
         .get(CONTENT_TYPE)
@@ -209,21 +518,23 @@ pub async fn img_job(
 
     let bytes = response.bytes().await?;
 
+    if let Err(e) = settings.check_size(bytes.len() as u64) {
+        crate::process::delay_delete(a.http.clone(), msg, Duration::from_millis(1000)).await;
+        return Err(e.into());
+    }
+
     msg.edit(a.http.clone(), |m| m.content("3/3 🟩🟩🟩 Uploading"))
         .await?;
 
-    a.http
-        .send_files(
-            a.msg.channel_id.clone().into(),
-            [AttachmentType::Bytes {
-                data: Cow::Borrowed(bytes.borrow()),
-                filename: match is_gif {
-                    // TODO: support more content types
-                    true => format!("{}.gif", a.name.clone()),
-                    false => format!("{}.png", a.name.clone()),
-                },
-            }],
-            serde_json::Map::default(),
+    a.invocation
+        .send_file(
+            &a.http,
+            bytes.borrow(),
+            match is_gif {
+                // TODO: support more content types
+                true => format!("{}.gif", a.name.clone()),
+                false => format!("{}.png", a.name.clone()),
+            },
         )
         .await?;
 
@@ -0,0 +1,235 @@
+mod giphy;
+mod imgur;
+#[cfg(test)]
+mod mock;
+mod opengraph;
+mod tenor;
+
+pub use giphy::Giphy;
+pub use imgur::Imgur;
+pub use opengraph::OpenGraph;
+pub use tenor::Tenor;
+
+use crate::source::has_image_extension;
+use serenity::async_trait;
+use shared::ProviderError;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Most resolved links remembered.
+static CACHE_CAPACITY: usize = 1000;
+/// How long what a link resolved to is trusted, as pages can change what they show.
+static CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Turns links to pages showing a GIF into links to the media itself.
+#[async_trait]
+pub trait GifProvider: Send + Sync {
+    /// Whether `url` is a page this provider knows how to resolve.
+    fn matches(&self, url: &Url) -> bool;
+
+    /// The media on the page at `url`.
+    async fn resolve(&self, url: &Url) -> Result<String, ProviderError>;
+}
+
+/// Whether `url` is on one of `hosts`, with or without `www.`.
+fn on_host(url: &Url, hosts: &[&str]) -> bool {
+    url.host_str()
+        .is_some_and(|host| hosts.contains(&host.trim_start_matches("www.")))
+}
+
+/// Sends `request` and reads its JSON response.
+async fn get_json<T: serde::de::DeserializeOwned>(
+    request: reqwest::RequestBuilder,
+) -> Result<T, ProviderError> {
+    let response = request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| ProviderError::RequestError(e.into()))?;
+
+    response
+        .json()
+        .await
+        .map_err(|e| ProviderError::CannotParse(e.into()))
+}
+
+/// Whether `url` is a page on one of the GIF sites, as opposed to any page with an image.
+pub fn is_gif_page(url: &Url) -> bool {
+    [tenor::HOSTS, giphy::HOSTS, imgur::HOSTS]
+        .iter()
+        .any(|hosts| on_host(url, hosts))
+}
+
+/// What links resolved to, oldest first, forgotten after a while.
+#[derive(Default)]
+struct Cache {
+    media: HashMap<String, (String, Instant)>,
+    order: VecDeque<(String, Instant)>,
+}
+
+impl Cache {
+    fn get(&self, link: &str, now: Instant) -> Option<String> {
+        self.media
+            .get(link)
+            .filter(|(_, added)| now - *added < CACHE_TTL)
+            .map(|(media, _)| media.clone())
+    }
+
+    fn insert(&mut self, link: String, media: String, now: Instant) {
+        while self.order.len() >= CACHE_CAPACITY
+            || self
+                .order
+                .front()
+                .is_some_and(|(_, added)| now - *added >= CACHE_TTL)
+        {
+            let (old, added) = self.order.pop_front().unwrap();
+            // the link may have been resolved again since
+            if self.media.get(&old).is_some_and(|(_, a)| *a == added) {
+                self.media.remove(&old);
+            }
+        }
+
+        self.order.push_back((link.clone(), now));
+        self.media.insert(link, (media, now));
+    }
+}
+
+/// Every provider, tried in order, with what they resolved links to.
+pub struct Providers {
+    providers: Vec<Box<dyn GifProvider>>,
+    cache: Mutex<Cache>,
+}
+
+impl Providers {
+    pub fn new(providers: Vec<Box<dyn GifProvider>>) -> Self {
+        Self {
+            providers,
+            cache: Default::default(),
+        }
+    }
+
+    /// Every provider, with API keys from `IMGBOT_TENOR_APIKEY`, `IMGBOT_GIPHY_APIKEY` and
+    /// `IMGBOT_IMGUR_CLIENT_ID`. Providers whose key is missing fail, or fall back to guessing
+    /// where the media is, rather than being left out.
+    pub fn from_env(client: reqwest::Client) -> Self {
+        Self::new(vec![
+            Box::new(Tenor::new(
+                client.clone(),
+                env::var("IMGBOT_TENOR_APIKEY").ok(),
+            )),
+            Box::new(Giphy::new(
+                client.clone(),
+                env::var("IMGBOT_GIPHY_APIKEY").ok(),
+            )),
+            Box::new(Imgur::new(client, env::var("IMGBOT_IMGUR_CLIENT_ID").ok())),
+            // matches any page, so has to go last
+            Box::new(OpenGraph::new()),
+        ])
+    }
+
+    /// The media `link` shows, or `link` itself when it's already an image or no provider
+    /// matches it.
+    pub async fn resolve(&self, link: &str) -> Result<String, ProviderError> {
+        let url = match Url::parse(link) {
+            Ok(url) if !has_image_extension(url.path()) => url,
+            _ => return Ok(link.to_string()),
+        };
+        let provider = match self.providers.iter().find(|p| p.matches(&url)) {
+            Some(provider) => provider,
+            None => return Ok(link.to_string()),
+        };
+
+        if let Some(media) = self.cache.lock().unwrap().get(link, Instant::now()) {
+            return Ok(media);
+        }
+        let media = provider.resolve(&url).await?;
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(link.to_string(), media.clone(), Instant::now());
+
+        Ok(media)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(&'static str, &'static str);
+
+    #[async_trait]
+    impl GifProvider for Fixed {
+        fn matches(&self, url: &Url) -> bool {
+            on_host(url, &[self.0])
+        }
+
+        async fn resolve(&self, _: &Url) -> Result<String, ProviderError> {
+            Ok(self.1.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn picks_the_first_match() {
+        let providers = Providers::new(vec![
+            Box::new(Fixed("a.com", "https://a.com/a.gif")),
+            Box::new(Fixed("b.com", "https://b.com/b.gif")),
+            Box::new(Fixed("b.com", "https://b.com/other.gif")),
+        ]);
+
+        let resolve = |link| providers.resolve(link);
+        assert_eq!(
+            resolve("https://a.com/x").await.unwrap(),
+            "https://a.com/a.gif"
+        );
+        assert_eq!(
+            resolve("https://www.b.com/x").await.unwrap(),
+            "https://b.com/b.gif"
+        );
+        assert_eq!(resolve("https://c.com/x").await.unwrap(), "https://c.com/x");
+        assert_eq!(
+            resolve("https://a.com/x.png").await.unwrap(),
+            "https://a.com/x.png"
+        );
+        assert_eq!(resolve("not a link").await.unwrap(), "not a link");
+    }
+
+    #[test]
+    fn cache_forgets_old_links() {
+        let now = Instant::now();
+        let mut cache = Cache::default();
+        cache.insert("a".to_string(), "a.gif".to_string(), now);
+
+        assert_eq!(cache.get("a", now).as_deref(), Some("a.gif"));
+        assert_eq!(cache.get("a", now + CACHE_TTL), None);
+
+        cache.insert("b".to_string(), "b.gif".to_string(), now + CACHE_TTL);
+        assert!(!cache.media.contains_key("a"));
+    }
+
+    #[test]
+    fn cache_keeps_at_most_capacity_links() {
+        let now = Instant::now();
+        let mut cache = Cache::default();
+        for i in 0..=CACHE_CAPACITY {
+            cache.insert(i.to_string(), format!("{}.gif", i), now);
+        }
+
+        assert_eq!(cache.media.len(), CACHE_CAPACITY);
+        assert_eq!(cache.get("0", now), None);
+        assert_eq!(cache.get("1", now).as_deref(), Some("1.gif"));
+    }
+
+    #[test]
+    fn knows_gif_sites() {
+        let page = |link| is_gif_page(&Url::parse(link).unwrap());
+
+        assert!(page("https://tenor.com/view/cat-123"));
+        assert!(page("https://giphy.com/gifs/cat-abc"));
+        assert!(page("https://imgur.com/abc"));
+        assert!(!page("https://example.com/cat"));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::provider::{get_json, on_host, GifProvider};
+use serenity::async_trait;
+use shared::ProviderError;
+use url::Url;
+
+pub static HOSTS: &[&str] = &["giphy.com", "media.giphy.com", "i.giphy.com"];
+static API: &str = "https://api.giphy.com";
+
+#[derive(serde::Deserialize)]
+struct Rendition {
+    url: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Images {
+    original: Rendition,
+}
+
+#[derive(serde::Deserialize)]
+struct Gif {
+    images: Images,
+}
+
+#[derive(serde::Deserialize)]
+struct GifResponse {
+    data: Gif,
+}
+
+/// Giphy's pages. With an API key the GIF is looked up, otherwise its usual address is used.
+pub struct Giphy {
+    client: reqwest::Client,
+    key: Option<String>,
+    api: String,
+}
+
+impl Giphy {
+    pub fn new(client: reqwest::Client, key: Option<String>) -> Self {
+        Self::with_api(client, key, API)
+    }
+
+    pub fn with_api(client: reqwest::Client, key: Option<String>, api: &str) -> Self {
+        Self {
+            client,
+            key,
+            api: api.to_string(),
+        }
+    }
+}
+
+/// The GIF's id, from `/gifs/cat-dance-{id}`, `/gifs/{id}`, `/embed/{id}` or `/media/{id}/...`.
+fn gif_id(url: &Url) -> Option<&str> {
+    let mut segments = url.path_segments()?;
+    let id = match segments.next()? {
+        "gifs" => segments.next()?.rsplit('-').next()?,
+        "embed" | "media" => segments.next()?,
+        _ => return None,
+    };
+
+    Some(id).filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+#[async_trait]
+impl GifProvider for Giphy {
+    fn matches(&self, url: &Url) -> bool {
+        on_host(url, HOSTS)
+    }
+
+    async fn resolve(&self, url: &Url) -> Result<String, ProviderError> {
+        let id = gif_id(url).ok_or(ProviderError::InvalidLink)?;
+        let key = match &self.key {
+            Some(key) => key,
+            None => return Ok(format!("https://i.giphy.com/media/{}/giphy.gif", id)),
+        };
+
+        let request = self
+            .client
+            .get(format!("{}/v1/gifs/{}", self.api, id))
+            .query(&[("api_key", key)]);
+        let response: GifResponse = get_json(request).await?;
+
+        Ok(response.data.images.original.url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::mock;
+
+    #[test]
+    fn finds_ids() {
+        let id = |link| gif_id(&Url::parse(link).unwrap()).map(str::to_string);
+
+        assert_eq!(
+            id("https://giphy.com/gifs/cat-dance-abc123").as_deref(),
+            Some("abc123")
+        );
+        assert_eq!(
+            id("https://giphy.com/gifs/abc123").as_deref(),
+            Some("abc123")
+        );
+        assert_eq!(
+            id("https://giphy.com/embed/abc123").as_deref(),
+            Some("abc123")
+        );
+        assert_eq!(
+            id("https://media.giphy.com/media/abc123/giphy.mp4").as_deref(),
+            Some("abc123")
+        );
+        assert_eq!(id("https://giphy.com/explore/cats"), None);
+    }
+
+    #[tokio::test]
+    async fn resolves_through_the_api() {
+        let api = mock::serve(vec![(
+            "/v1/gifs/abc123?api_key=k",
+            r#"{"data": {"images": {"original": {"url": "https://media.giphy.com/media/abc123/giphy.gif?cid=1"}}}}"#,
+        )])
+        .await;
+        let giphy = Giphy::with_api(reqwest::Client::new(), Some("k".to_string()), &api);
+
+        let url = Url::parse("https://giphy.com/gifs/cat-dance-abc123").unwrap();
+        assert!(giphy.matches(&url));
+        assert_eq!(
+            giphy.resolve(&url).await.unwrap(),
+            "https://media.giphy.com/media/abc123/giphy.gif?cid=1"
+        );
+    }
+
+    #[tokio::test]
+    async fn guesses_without_a_key() {
+        let giphy = Giphy::new(reqwest::Client::new(), None);
+        let url = Url::parse("https://giphy.com/gifs/cat-dance-abc123").unwrap();
+
+        assert_eq!(
+            giphy.resolve(&url).await.unwrap(),
+            "https://i.giphy.com/media/abc123/giphy.gif"
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use crate::provider::{get_json, on_host, GifProvider};
+use serenity::async_trait;
+use shared::ProviderError;
+use url::Url;
+
+pub static HOSTS: &[&str] = &["imgur.com", "i.imgur.com", "m.imgur.com"];
+static API: &str = "https://api.imgur.com";
+/// Extensions Imgur serves animations as, which all have a GIF beside them.
+static VIDEO_EXTENSIONS: &[&str] = &["gifv", "mp4", "webm"];
+
+#[derive(serde::Deserialize)]
+struct Image {
+    link: String,
+}
+
+#[derive(serde::Deserialize)]
+struct ImageResponse {
+    data: Image,
+}
+
+#[derive(serde::Deserialize)]
+struct AlbumResponse {
+    data: Vec<Image>,
+}
+
+/// Imgur's direct links to videos, and its image and album pages through its API. The pages
+/// need a client id.
+pub struct Imgur {
+    client: reqwest::Client,
+    client_id: Option<String>,
+    api: String,
+}
+
+impl Imgur {
+    pub fn new(client: reqwest::Client, client_id: Option<String>) -> Self {
+        Self::with_api(client, client_id, API)
+    }
+
+    pub fn with_api(client: reqwest::Client, client_id: Option<String>, api: &str) -> Self {
+        Self {
+            client,
+            client_id,
+            api: api.to_string(),
+        }
+    }
+
+    async fn fetch<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T, ProviderError> {
+        let client_id = self
+            .client_id
+            .as_ref()
+            .ok_or(ProviderError::Unavailable("Imgur"))?;
+        let request = self
+            .client
+            .get(format!("{}/3/{}", self.api, path))
+            .header("Authorization", format!("Client-ID {}", client_id));
+
+        get_json(request).await
+    }
+}
+
+/// `link` with its video extension swapped for `.gif`, if it has one.
+fn as_gif(link: &str) -> Option<String> {
+    let (stem, extension) = link.rsplit_once('.')?;
+    VIDEO_EXTENSIONS
+        .contains(&extension)
+        .then(|| format!("{}.gif", stem))
+}
+
+#[async_trait]
+impl GifProvider for Imgur {
+    fn matches(&self, url: &Url) -> bool {
+        on_host(url, HOSTS)
+    }
+
+    async fn resolve(&self, url: &Url) -> Result<String, ProviderError> {
+        if url.host_str() == Some("i.imgur.com") {
+            let mut url = url.clone();
+            url.set_query(None);
+            return as_gif(url.as_str()).ok_or(ProviderError::InvalidLink);
+        }
+
+        let segments: Vec<&str> = url
+            .path_segments()
+            .ok_or(ProviderError::InvalidLink)?
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let link = match segments.as_slice() {
+            ["a" | "gallery", id] => {
+                let album: AlbumResponse = self.fetch(&format!("album/{}/images", id)).await?;
+                album
+                    .data
+                    .into_iter()
+                    .next()
+                    .ok_or(ProviderError::NoMedia)?
+                    .link
+            }
+            [id] => {
+                let image: ImageResponse = self.fetch(&format!("image/{}", id)).await?;
+                image.data.link
+            }
+            _ => return Err(ProviderError::InvalidLink),
+        };
+
+        Ok(as_gif(&link).unwrap_or(link))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::mock;
+
+    #[tokio::test]
+    async fn swaps_videos_for_gifs() {
+        let imgur = Imgur::new(reqwest::Client::new(), None);
+        let resolve = |link| mock::resolve(&imgur, link);
+
+        assert_eq!(
+            resolve("https://i.imgur.com/abc.gifv").await.unwrap(),
+            "https://i.imgur.com/abc.gif"
+        );
+        assert_eq!(
+            resolve("https://i.imgur.com/abc.mp4?1").await.unwrap(),
+            "https://i.imgur.com/abc.gif"
+        );
+        assert!(matches!(
+            resolve("https://imgur.com/abc").await,
+            Err(ProviderError::Unavailable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn resolves_through_the_api() {
+        let api = mock::serve(vec![
+            ("/3/image/abc", r#"{"data": {"link": "https://i.imgur.com/abc.mp4"}}"#),
+            (
+                "/3/album/xyz/images",
+                r#"{"data": [{"link": "https://i.imgur.com/one.png"}, {"link": "https://i.imgur.com/two.png"}]}"#,
+            ),
+            ("/3/album/empty/images", r#"{"data": []}"#),
+        ])
+        .await;
+        let imgur = Imgur::with_api(reqwest::Client::new(), Some("c".to_string()), &api);
+        let resolve = |link| mock::resolve(&imgur, link);
+
+        assert_eq!(
+            resolve("https://imgur.com/abc").await.unwrap(),
+            "https://i.imgur.com/abc.gif"
+        );
+        assert_eq!(
+            resolve("https://imgur.com/a/xyz").await.unwrap(),
+            "https://i.imgur.com/one.png"
+        );
+        assert_eq!(
+            resolve("https://imgur.com/gallery/xyz").await.unwrap(),
+            "https://i.imgur.com/one.png"
+        );
+        assert!(matches!(
+            resolve("https://imgur.com/a/empty").await,
+            Err(ProviderError::NoMedia)
+        ));
+        assert!(matches!(
+            resolve("https://imgur.com/t/cats/abc").await,
+            Err(ProviderError::InvalidLink)
+        ));
+    }
+}
@@ -0,0 +1,62 @@
+//! A tiny HTTP server standing in for the providers' APIs and pages in tests.
+
+use crate::provider::GifProvider;
+use shared::ProviderError;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use url::Url;
+
+/// The type of a canned body, judged by how it starts.
+fn content_type(body: &str) -> &'static str {
+    match body.chars().next() {
+        Some('{') | Some('[') => "application/json",
+        Some('<') => "text/html; charset=utf-8",
+        _ => "image/gif",
+    }
+}
+
+/// Serves `routes`, each a path with its query and the body to answer it with, until the test
+/// ends. A body like `-> /other` redirects there instead, and anything else is a 404. Returns the
+/// server's base URL, without a trailing slash.
+pub async fn serve(routes: Vec<(&'static str, &'static str)>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+
+            let request = String::from_utf8_lossy(&request);
+            let target = request.split(' ').nth(1).unwrap_or_default();
+            let response = match routes.iter().find(|(path, _)| *path == target) {
+                Some((_, body)) if body.starts_with("-> ") => format!(
+                    "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    &body[3..]
+                ),
+                Some((_, body)) => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    content_type(body),
+                    body.len(),
+                    body
+                ),
+                None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+            };
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+
+    base
+}
+
+/// Has `provider` resolve `link`, which has to be a valid URL.
+pub async fn resolve(provider: &dyn GifProvider, link: &str) -> Result<String, ProviderError> {
+    provider.resolve(&Url::parse(link).unwrap()).await
+}
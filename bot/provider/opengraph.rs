@@ -0,0 +1,341 @@
+use crate::provider::GifProvider;
+use regex::Regex;
+use reqwest::redirect::Policy;
+use serenity::async_trait;
+use shared::ProviderError;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use url::{Host, Url};
+
+/// Most of a page read looking for its media. The `<head>` is nearly always well within it.
+static MAX_PAGE_SIZE: usize = 1024 * 1024;
+/// How long a page may take to load.
+static TIMEOUT: Duration = Duration::from_secs(10);
+/// Most redirects followed from a link.
+static MAX_REDIRECTS: usize = 5;
+
+/// Any other page, by the media in its OpenGraph `og:image` and `og:video` tags. A link that
+/// turns out to be an image is taken as it is.
+///
+/// Anyone can post a link, so links, and every redirect they go through, are only followed to
+/// public addresses. That keeps the bot from reading pages on its own machine or network.
+pub struct OpenGraph {
+    /// Doesn't follow redirects, so each can be checked first.
+    client: reqwest::Client,
+    /// Whether private addresses may be read anyway, which only tests want.
+    allow_private: bool,
+}
+
+impl OpenGraph {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .redirect(Policy::none())
+                .build()
+                .expect("cannot build the OpenGraph HTTP client"),
+            allow_private: false,
+        }
+    }
+
+    /// Also reads pages on private addresses, such as the tests' local server.
+    #[cfg(test)]
+    fn allowing_private() -> Self {
+        Self {
+            allow_private: true,
+            ..Self::new()
+        }
+    }
+
+    /// Fails unless every address `url`'s host has is public.
+    async fn check_host(&self, url: &Url) -> Result<(), ProviderError> {
+        if self.allow_private {
+            return Ok(());
+        }
+
+        let addresses: Vec<IpAddr> = match url.host() {
+            Some(Host::Ipv4(ip)) => vec![ip.into()],
+            Some(Host::Ipv6(ip)) => vec![ip.into()],
+            Some(Host::Domain(domain)) => {
+                let port = url.port_or_known_default().unwrap_or(80);
+                tokio::net::lookup_host((domain, port))
+                    .await
+                    .map_err(|e| ProviderError::RequestError(e.into()))?
+                    .map(|address| address.ip())
+                    .collect()
+            }
+            None => return Err(ProviderError::InvalidLink),
+        };
+
+        match !addresses.is_empty() && addresses.into_iter().all(is_public) {
+            true => Ok(()),
+            false => Err(ProviderError::PrivateAddress),
+        }
+    }
+}
+
+impl Default for OpenGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `ip` is on the internet, rather than loopback, a private network or link-local.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast())
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// The `og:image` and `og:video` tags of `page`, as (property, content) pairs in page order.
+fn media_tags(page: &str) -> Vec<(String, String)> {
+    static META: OnceLock<Regex> = OnceLock::new();
+    static ATTRIBUTE: OnceLock<Regex> = OnceLock::new();
+    let meta = META.get_or_init(|| Regex::new(r"(?is)<meta\s[^>]*>").unwrap());
+    let attribute = ATTRIBUTE.get_or_init(|| {
+        Regex::new(r#"(?i)\b(property|name|content)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap()
+    });
+
+    let mut tags = vec![];
+    for tag in meta.find_iter(page) {
+        let mut property = None;
+        let mut content = None;
+        for c in attribute.captures_iter(tag.as_str()) {
+            let value = c.get(2).or_else(|| c.get(3)).unwrap().as_str();
+            match c[1].to_ascii_lowercase().as_str() {
+                "content" => content = Some(value),
+                _ => property = Some(value.to_ascii_lowercase()),
+            }
+        }
+
+        if let (Some(property), Some(content)) = (property, content) {
+            if matches!(
+                property.as_str(),
+                "og:image"
+                    | "og:image:url"
+                    | "og:image:secure_url"
+                    | "og:video"
+                    | "og:video:url"
+                    | "og:video:secure_url"
+            ) {
+                tags.push((property, content.replace("&amp;", "&")));
+            }
+        }
+    }
+
+    tags
+}
+
+/// The best media on `page`: a GIF if there is one, then an image, then a video.
+fn best_media(page: &str, base: &Url) -> Option<String> {
+    let tags: Vec<(String, Url)> = media_tags(page)
+        .into_iter()
+        .filter_map(|(property, content)| Some((property, base.join(&content).ok()?)))
+        .collect();
+    let is_gif = |url: &Url| url.path().to_ascii_lowercase().ends_with(".gif");
+
+    tags.iter()
+        .find(|(_, url)| is_gif(url))
+        .or_else(|| {
+            tags.iter()
+                .find(|(property, _)| property.starts_with("og:image"))
+        })
+        .or_else(|| tags.first())
+        .map(|(_, url)| url.to_string())
+}
+
+#[async_trait]
+impl GifProvider for OpenGraph {
+    fn matches(&self, url: &Url) -> bool {
+        matches!(url.scheme(), "http" | "https")
+    }
+
+    async fn resolve(&self, url: &Url) -> Result<String, ProviderError> {
+        let mut url = url.clone();
+        let mut redirects = 0;
+        let mut response = loop {
+            self.check_host(&url).await?;
+            let response = self
+                .client
+                .get(url.clone())
+                .timeout(TIMEOUT)
+                .send()
+                .await
+                .map_err(|e| ProviderError::RequestError(e.into()))?;
+            if !response.status().is_redirection() {
+                break response
+                    .error_for_status()
+                    .map_err(|e| ProviderError::RequestError(e.into()))?;
+            }
+
+            redirects += 1;
+            if redirects > MAX_REDIRECTS {
+                return Err(ProviderError::BadResponse("Too many redirects"));
+            }
+            url = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| url.join(location).ok())
+                .ok_or(ProviderError::BadResponse("Redirect without a location"))?;
+        };
+
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if content_type.starts_with("image/") {
+            return Ok(url.to_string());
+        }
+        if !content_type.starts_with("text/html") {
+            return Err(ProviderError::NoMedia);
+        }
+
+        let mut page = vec![];
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| ProviderError::RequestError(e.into()))?
+        {
+            page.extend_from_slice(&chunk);
+            if page.len() >= MAX_PAGE_SIZE {
+                break;
+            }
+        }
+
+        best_media(&String::from_utf8_lossy(&page), &url).ok_or(ProviderError::NoMedia)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::mock;
+
+    #[test]
+    fn prefers_gifs_then_images() {
+        let base = Url::parse("https://example.com/posts/1").unwrap();
+        let best = |page| best_media(page, &base);
+
+        assert_eq!(
+            best(r#"<meta property="og:video" content="https://a.com/v.mp4"><meta content='/i.png' property='og:image'>"#)
+                .as_deref(),
+            Some("https://example.com/i.png")
+        );
+        assert_eq!(
+            best(r#"<meta property="og:image" content="https://a.com/i.png"><META PROPERTY="og:video:url" CONTENT="https://a.com/v.gif?a=1&amp;b=2">"#)
+                .as_deref(),
+            Some("https://a.com/v.gif?a=1&b=2")
+        );
+        assert_eq!(
+            best(r#"<meta property="og:video" content="https://a.com/v.mp4">"#).as_deref(),
+            Some("https://a.com/v.mp4")
+        );
+        assert_eq!(best(r#"<meta property="og:title" content="Cats">"#), None);
+    }
+
+    #[tokio::test]
+    async fn reads_pages() {
+        let base = mock::serve(vec![
+            (
+                "/page",
+                r#"<html><head><meta property="og:image" content="/cat.gif"></head></html>"#,
+            ),
+            ("/cat.gif", "GIF89a"),
+            ("/plain", "<html><head><title>Nothing</title></head></html>"),
+            ("/moved", "-> /page"),
+            ("/loop", "-> /loop"),
+        ])
+        .await;
+        let opengraph = OpenGraph::allowing_private();
+        let resolve = |path| {
+            let link = format!("{}{}", base, path);
+            let opengraph = &opengraph;
+            async move { mock::resolve(opengraph, &link).await }
+        };
+
+        assert_eq!(resolve("/page").await.unwrap(), format!("{}/cat.gif", base));
+        assert_eq!(
+            resolve("/cat.gif").await.unwrap(),
+            format!("{}/cat.gif", base)
+        );
+        assert!(matches!(
+            resolve("/plain").await,
+            Err(ProviderError::NoMedia)
+        ));
+        assert!(matches!(
+            resolve("/missing").await,
+            Err(ProviderError::RequestError(_))
+        ));
+        assert_eq!(
+            resolve("/moved").await.unwrap(),
+            format!("{}/cat.gif", base)
+        );
+        assert!(matches!(
+            resolve("/loop").await,
+            Err(ProviderError::BadResponse(_))
+        ));
+    }
+
+    #[test]
+    fn knows_public_addresses() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+
+        assert!(public("93.184.216.34"));
+        assert!(public("2606:2800:220:1::"));
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!public(ip), "{} is not public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_private_addresses() {
+        let base = mock::serve(vec![("/cat.gif", "GIF89a")]).await;
+        let opengraph = OpenGraph::new();
+
+        for link in [
+            format!("{}/cat.gif", base),
+            "http://localhost/".to_string(),
+            "http://[::1]/".to_string(),
+            "http://169.254.169.254/latest/meta-data".to_string(),
+        ] {
+            assert!(
+                matches!(
+                    mock::resolve(&opengraph, &link).await,
+                    Err(ProviderError::PrivateAddress)
+                ),
+                "{} was read",
+                link
+            );
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::provider::{get_json, on_host, GifProvider};
+use regex::Regex;
+use serenity::async_trait;
+use shared::ProviderError;
+use std::sync::OnceLock;
+use url::Url;
+
+pub static HOSTS: &[&str] = &["tenor.com"];
+static API: &str = "https://g.tenor.com";
+
+#[derive(serde::Deserialize)]
+struct MediaObject {
+    url: String,
+}
+
+#[derive(serde::Deserialize)]
+struct GifMedia {
+    #[serde(default)]
+    gif: Option<MediaObject>,
+}
+
+#[derive(serde::Deserialize)]
+struct GifObject {
+    media: Vec<GifMedia>,
+}
+
+#[derive(serde::Deserialize)]
+struct GifsResponse {
+    results: Vec<GifObject>,
+}
+
+/// Tenor's pages, through its API. Needs an API key.
+pub struct Tenor {
+    client: reqwest::Client,
+    key: Option<String>,
+    api: String,
+}
+
+impl Tenor {
+    pub fn new(client: reqwest::Client, key: Option<String>) -> Self {
+        Self::with_api(client, key, API)
+    }
+
+    pub fn with_api(client: reqwest::Client, key: Option<String>, api: &str) -> Self {
+        Self {
+            client,
+            key,
+            api: api.to_string(),
+        }
+    }
+}
+
+/// The GIF's id, which ends the page's path: `/view/cat-dance-12345`.
+fn gif_id(url: &Url) -> Option<&str> {
+    static ID: OnceLock<Regex> = OnceLock::new();
+    let id = ID.get_or_init(|| Regex::new(r"(\d+)$").unwrap());
+
+    id.find(url.path()).map(|id| id.as_str())
+}
+
+#[async_trait]
+impl GifProvider for Tenor {
+    fn matches(&self, url: &Url) -> bool {
+        on_host(url, HOSTS)
+    }
+
+    async fn resolve(&self, url: &Url) -> Result<String, ProviderError> {
+        let key = self
+            .key
+            .as_ref()
+            .ok_or(ProviderError::Unavailable("Tenor"))?;
+        let id = gif_id(url).ok_or(ProviderError::InvalidLink)?;
+
+        let request = self.client.get(format!("{}/v1/gifs", self.api)).query(&[
+            ("ids", id),
+            ("key", key),
+            ("media_filter", "minimal"),
+            ("limit", "1"),
+        ]);
+        let response: GifsResponse = get_json(request).await?;
+
+        response
+            .results
+            .into_iter()
+            .next()
+            .ok_or(ProviderError::BadResponse("Got 0 responses"))?
+            .media
+            .into_iter()
+            .next()
+            .ok_or(ProviderError::BadResponse("Got 0 media responses"))?
+            .gif
+            .map(|gif| gif.url)
+            .ok_or(ProviderError::BadResponse("No gif"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::mock;
+
+    #[tokio::test]
+    async fn resolves_through_the_api() {
+        let api = mock::serve(vec![(
+            "/v1/gifs?ids=12345&key=k&media_filter=minimal&limit=1",
+            r#"{"next": "0", "results": [{"media": [{"gif": {"url": "https://media.tenor.com/a.gif"}}]}]}"#,
+        )])
+        .await;
+        let tenor = Tenor::with_api(reqwest::Client::new(), Some("k".to_string()), &api);
+
+        let url = Url::parse("https://tenor.com/view/cat-dance-12345").unwrap();
+        assert!(tenor.matches(&url));
+        assert_eq!(
+            tenor.resolve(&url).await.unwrap(),
+            "https://media.tenor.com/a.gif"
+        );
+
+        let url = Url::parse("https://tenor.com/view/other-999").unwrap();
+        assert!(matches!(
+            tenor.resolve(&url).await,
+            Err(ProviderError::RequestError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn needs_a_key() {
+        let tenor = Tenor::new(reqwest::Client::new(), None);
+        let url = Url::parse("https://tenor.com/view/cat-12345").unwrap();
+
+        assert!(matches!(
+            tenor.resolve(&url).await,
+            Err(ProviderError::Unavailable(_))
+        ));
+    }
+}
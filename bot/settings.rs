@@ -0,0 +1,410 @@
+use crate::access::RoleRules;
+use serenity::model::id::ChannelId;
+use shared::CommandError;
+use std::collections::HashMap;
+
+/// Commands that can't be disabled or confined to channels, so a guild can't lock itself out.
+pub static ALWAYS_ALLOWED: &[&str] = &["config", "help"];
+/// Largest file Discord takes from bots in guilds without boosts.
+static DISCORD_UPLOAD_LIMIT: u64 = 8 * 1024 * 1024;
+
+/// A per-guild setting, as named in the `config` command and the store.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Key {
+    AllowedChannels,
+    DisabledCommands,
+    CaptionStyle,
+    MaxGifSize,
+    Nsfw,
+    Suggestions,
+}
+
+impl Key {
+    pub const ALL: [Key; 6] = [
+        Key::AllowedChannels,
+        Key::DisabledCommands,
+        Key::CaptionStyle,
+        Key::MaxGifSize,
+        Key::Nsfw,
+        Key::Suggestions,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Key::AllowedChannels => "allowed_channels",
+            Key::DisabledCommands => "disabled_commands",
+            Key::CaptionStyle => "caption_style",
+            Key::MaxGifSize => "max_gif_size",
+            Key::Nsfw => "nsfw",
+            Key::Suggestions => "suggestions",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Key> {
+        Key::ALL.iter().copied().find(|key| key.name() == name)
+    }
+
+    /// What the setting does and the values it takes.
+    pub fn about(self) -> &'static str {
+        match self {
+            Key::AllowedChannels => "Channels commands work in, as #mentions. Empty allows all.",
+            Key::DisabledCommands => "Commands nobody can use here, by name.",
+            Key::CaptionStyle => "Default caption colors: `light' or `dark'.",
+            Key::MaxGifSize => "Largest image to upload, e.g. `4MB' or `500KB'. At most 8MB.",
+            Key::Nsfw => "Where image commands run: `anywhere' or `nsfw_channels'.",
+            Key::Suggestions => "Whether mistyped commands get a `did you mean': `on' or `off'.",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NsfwRule {
+    Anywhere,
+    /// Image commands only run in channels marked NSFW.
+    NsfwChannels,
+}
+
+/// A guild's settings, with the defaults filled in.
+#[derive(Clone, Debug)]
+pub struct GuildSettings {
+    pub allowed_channels: Vec<ChannelId>,
+    pub disabled_commands: Vec<String>,
+    /// Sent as the caption endpoint's `style` when the command doesn't pick one.
+    pub caption_style: String,
+    pub max_gif_size: u64,
+    pub nsfw: NsfwRule,
+    pub suggestions: bool,
+    /// Roles allowed or denied each command, by command name. Changed with the `roles`
+    /// command rather than `config`.
+    pub command_roles: HashMap<String, RoleRules>,
+}
+
+impl Default for GuildSettings {
+    fn default() -> Self {
+        Self {
+            allowed_channels: vec![],
+            disabled_commands: vec![],
+            caption_style: "light".to_string(),
+            max_gif_size: DISCORD_UPLOAD_LIMIT,
+            nsfw: NsfwRule::Anywhere,
+            suggestions: true,
+            command_roles: HashMap::new(),
+        }
+    }
+}
+
+/// Splits a list setting on commas and whitespace.
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|item| !item.is_empty())
+}
+
+fn parse_channel(value: &str) -> Option<ChannelId> {
+    let id = value
+        .strip_prefix("<#")
+        .and_then(|id| id.strip_suffix('>'))
+        .unwrap_or(value);
+    id.parse().ok().map(ChannelId)
+}
+
+/// Reads sizes like `8MB`, `500KB`, `1.5mb` or a plain number of bytes.
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim().to_ascii_uppercase();
+    let (number, unit) = match value.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => value.split_at(i),
+        None => (value.as_str(), "B"),
+    };
+    let multiplier = match unit.trim() {
+        "B" => 1.,
+        "KB" | "K" => 1024.,
+        "MB" | "M" => 1024. * 1024.,
+        _ => return None,
+    };
+
+    let size = number.trim().parse::<f64>().ok()? * multiplier;
+    match size.is_finite() && size >= 1. {
+        true => Some(size as u64),
+        false => None,
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1024 * 1024 => format!("{:.1}MB", b as f64 / (1024. * 1024.)),
+        b if b >= 1024 => format!("{:.1}KB", b as f64 / 1024.),
+        b => format!("{}B", b),
+    }
+}
+
+/// Like [`format_size`], but exact, so it reads back as the same size.
+fn exact_size(bytes: u64) -> String {
+    match bytes {
+        b if b % (1024 * 1024) == 0 => format!("{}MB", b / (1024 * 1024)),
+        b if b % 1024 == 0 => format!("{}KB", b / 1024),
+        b => format!("{}B", b),
+    }
+}
+
+/// A `disabled_commands` value with each command by its name, looked up with `lookup` so
+/// aliases are stored as the command they stand for.
+pub fn command_names(
+    value: &str,
+    lookup: impl Fn(&str) -> Option<&'static str>,
+) -> Result<String, CommandError> {
+    let names = list(value)
+        .map(|command| {
+            lookup(command).ok_or_else(|| {
+                CommandError::StringError(format!("No command named `{}'.", command))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(names.join(" "))
+}
+
+fn invalid(key: Key) -> CommandError {
+    CommandError::StringError(format!("Bad value for {}. {}", key.name(), key.about()))
+}
+
+impl GuildSettings {
+    /// Changes a setting from its text form, failing if the value isn't valid for it.
+    pub fn set(&mut self, key: Key, value: &str) -> Result<(), CommandError> {
+        let value = value.trim();
+        match key {
+            Key::AllowedChannels => {
+                self.allowed_channels = list(value)
+                    .map(|item| parse_channel(item).ok_or_else(|| invalid(key)))
+                    .collect::<Result<_, _>>()?;
+            }
+            Key::DisabledCommands => {
+                let commands: Vec<String> = list(value).map(str::to_lowercase).collect();
+                if let Some(command) = commands
+                    .iter()
+                    .find(|c| ALWAYS_ALLOWED.contains(&c.as_str()))
+                {
+                    return Err(CommandError::StringError(format!(
+                        "`{}' can't be disabled.",
+                        command
+                    )));
+                }
+                self.disabled_commands = commands;
+            }
+            Key::CaptionStyle => match value {
+                "light" | "dark" => self.caption_style = value.to_string(),
+                _ => return Err(invalid(key)),
+            },
+            Key::MaxGifSize => match parse_size(value) {
+                Some(size) if size <= DISCORD_UPLOAD_LIMIT => self.max_gif_size = size,
+                _ => return Err(invalid(key)),
+            },
+            Key::Nsfw => {
+                self.nsfw = match value {
+                    "anywhere" => NsfwRule::Anywhere,
+                    "nsfw_channels" => NsfwRule::NsfwChannels,
+                    _ => return Err(invalid(key)),
+                }
+            }
+            Key::Suggestions => {
+                self.suggestions = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(invalid(key)),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Puts a setting back to its default.
+    pub fn reset(&mut self, key: Key) {
+        let default = GuildSettings::default();
+        match key {
+            Key::AllowedChannels => self.allowed_channels = default.allowed_channels,
+            Key::DisabledCommands => self.disabled_commands = default.disabled_commands,
+            Key::CaptionStyle => self.caption_style = default.caption_style,
+            Key::MaxGifSize => self.max_gif_size = default.max_gif_size,
+            Key::Nsfw => self.nsfw = default.nsfw,
+            Key::Suggestions => self.suggestions = default.suggestions,
+        }
+    }
+
+    /// A setting in the form [`GuildSettings::set`] takes, which is also how it is stored.
+    pub fn get(&self, key: Key) -> String {
+        match key {
+            Key::AllowedChannels => self
+                .allowed_channels
+                .iter()
+                .map(|c| format!("<#{}>", c.0))
+                .collect::<Vec<_>>()
+                .join(" "),
+            Key::DisabledCommands => self.disabled_commands.join(" "),
+            Key::CaptionStyle => self.caption_style.clone(),
+            Key::MaxGifSize => exact_size(self.max_gif_size),
+            Key::Nsfw => match self.nsfw {
+                NsfwRule::Anywhere => "anywhere".to_string(),
+                NsfwRule::NsfwChannels => "nsfw_channels".to_string(),
+            },
+            Key::Suggestions => match self.suggestions {
+                true => "on".to_string(),
+                false => "off".to_string(),
+            },
+        }
+    }
+
+    /// Whether `command` may run in `channel`, and if not, whether to say why. Commands
+    /// outside the allowed channels are ignored quietly, so the bot doesn't talk over other
+    /// channels.
+    pub fn check_command(&self, command: &str, channel: ChannelId) -> Result<(), Option<String>> {
+        if ALWAYS_ALLOWED.contains(&command) {
+            return Ok(());
+        }
+        if !self.allowed_channels.is_empty() && !self.allowed_channels.contains(&channel) {
+            return Err(None);
+        }
+        if self.disabled_commands.iter().any(|c| c == command) {
+            return Err(Some(format!("`{}' is disabled on this server.", command)));
+        }
+
+        Ok(())
+    }
+
+    /// Fails if an upload of `bytes` is over the guild's limit.
+    pub fn check_size(&self, bytes: u64) -> Result<(), CommandError> {
+        match bytes > self.max_gif_size {
+            true => Err(CommandError::StringError(format!(
+                "The result is {}, over this server's limit of {}. Try a shorter range or a \
+                 lower --quality.",
+                format_size(bytes),
+                format_size(self.max_gif_size)
+            ))),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("8MB"), Some(8 * 1024 * 1024));
+        assert_eq!(parse_size("500 kb"), Some(500 * 1024));
+        assert_eq!(parse_size("1.5m"), Some(3 * 512 * 1024));
+        assert_eq!(parse_size("1234"), Some(1234));
+        for bad in ["", "0", "-1MB", "5GB", "MB", "big"] {
+            assert_eq!(parse_size(bad), None, "{}", bad);
+        }
+
+        for size in [1, 1023, 1024, 1536, 4 * 1024 * 1024] {
+            assert_eq!(parse_size(&exact_size(size)), Some(size));
+        }
+    }
+
+    #[test]
+    fn limits_the_gif_size() {
+        let mut settings = GuildSettings::default();
+
+        assert!(settings.set(Key::MaxGifSize, "4MB").is_ok());
+        assert_eq!(settings.max_gif_size, 4 * 1024 * 1024);
+        assert!(settings.set(Key::MaxGifSize, "9MB").is_err());
+        assert!(settings.check_size(4 * 1024 * 1024).is_ok());
+        assert!(settings.check_size(4 * 1024 * 1024 + 1).is_err());
+    }
+
+    #[test]
+    fn parses_channel_lists() {
+        let mut settings = GuildSettings::default();
+
+        settings.set(Key::AllowedChannels, "<#1>, 2  <#3>").unwrap();
+        assert_eq!(
+            settings.allowed_channels,
+            [ChannelId(1), ChannelId(2), ChannelId(3)]
+        );
+        assert_eq!(settings.get(Key::AllowedChannels), "<#1> <#2> <#3>");
+
+        assert!(settings.set(Key::AllowedChannels, "<#1> #general").is_err());
+        assert_eq!(settings.allowed_channels.len(), 3);
+        settings.set(Key::AllowedChannels, "").unwrap();
+        assert!(settings.allowed_channels.is_empty());
+    }
+
+    #[test]
+    fn checks_command_names() {
+        let lookup = |name: &str| match name {
+            "caption" | "cap" => Some("caption"),
+            "help" => Some("help"),
+            _ => None,
+        };
+
+        assert_eq!(command_names("cap, help", lookup).unwrap(), "caption help");
+        assert!(command_names("caption nothing", lookup).is_err());
+
+        let mut settings = GuildSettings::default();
+        assert!(settings.set(Key::DisabledCommands, "caption help").is_err());
+        settings.set(Key::DisabledCommands, "Caption").unwrap();
+        assert_eq!(settings.disabled_commands, ["caption"]);
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        let mut settings = GuildSettings::default();
+
+        for (key, value) in [
+            (Key::CaptionStyle, "blue"),
+            (Key::Nsfw, "sometimes"),
+            (Key::Suggestions, "yes"),
+        ] {
+            assert!(settings.set(key, value).is_err(), "{}", value);
+        }
+        assert_eq!(
+            settings.get(Key::CaptionStyle),
+            GuildSettings::default().get(Key::CaptionStyle)
+        );
+    }
+
+    #[test]
+    fn round_trips_settings() {
+        let default = GuildSettings::default();
+        let mut settings = GuildSettings::default();
+        let values = [
+            (Key::AllowedChannels, "<#1> <#2>"),
+            (Key::DisabledCommands, "caption reverse"),
+            (Key::CaptionStyle, "dark"),
+            (Key::MaxGifSize, "500KB"),
+            (Key::Nsfw, "nsfw_channels"),
+            (Key::Suggestions, "off"),
+        ];
+        assert_eq!(values.len(), Key::ALL.len());
+
+        for (key, value) in values {
+            settings.set(key, value).unwrap();
+            assert_eq!(settings.get(key), value);
+            assert_ne!(settings.get(key), default.get(key));
+
+            let mut stored = GuildSettings::default();
+            stored.set(key, &settings.get(key)).unwrap();
+            assert_eq!(stored.get(key), value);
+
+            settings.reset(key);
+            assert_eq!(settings.get(key), default.get(key));
+        }
+    }
+
+    #[test]
+    fn confines_commands() {
+        let mut settings = GuildSettings::default();
+        settings.set(Key::AllowedChannels, "<#1>").unwrap();
+        settings.set(Key::DisabledCommands, "caption").unwrap();
+
+        assert_eq!(settings.check_command("reverse", ChannelId(1)), Ok(()));
+        assert_eq!(settings.check_command("reverse", ChannelId(2)), Err(None));
+        assert!(matches!(
+            settings.check_command("caption", ChannelId(1)),
+            Err(Some(_))
+        ));
+        assert_eq!(settings.check_command("help", ChannelId(2)), Ok(()));
+    }
+}
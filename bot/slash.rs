@@ -0,0 +1,229 @@
+use crate::command::Command;
+use clap::{App, Arg, ArgSettings};
+use linkify::LinkFinder;
+use serde_json::Value;
+use serenity::builder::{
+    CreateApplicationCommand, CreateApplicationCommandOption, CreateApplicationCommands,
+};
+use serenity::http::Http;
+use serenity::model::id::GuildId;
+use serenity::model::interactions::application_command::{
+    ApplicationCommand, ApplicationCommandInteraction, ApplicationCommandInteractionDataOption,
+    ApplicationCommandOptionType, ApplicationCommandType,
+};
+use shared::CommandError;
+use std::collections::HashMap;
+use std::env;
+
+/// The message context-menu command captioning a message's image with its text.
+pub static CAPTION_THIS: &str = "Caption this";
+/// Longest description Discord takes for commands and options.
+static MAX_DESCRIPTION_LEN: usize = 100;
+/// Most options Discord takes per command.
+static MAX_OPTIONS: usize = 25;
+
+/// Cuts help text down to its first line, within Discord's limits.
+fn description(help: Option<&str>) -> String {
+    let line = help
+        .and_then(|help| help.lines().next())
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .unwrap_or("No description provided");
+
+    match line.chars().count() > MAX_DESCRIPTION_LEN {
+        true => {
+            line.chars()
+                .take(MAX_DESCRIPTION_LEN - 1)
+                .collect::<String>()
+                + "…"
+        }
+        false => line.to_string(),
+    }
+}
+
+fn option(arg: &Arg) -> CreateApplicationCommandOption {
+    let mut option = CreateApplicationCommandOption::default();
+    option
+        .name(arg.get_name())
+        .description(description(arg.get_help()))
+        .required(arg.is_set(ArgSettings::Required));
+
+    match arg.is_positional() || arg.is_set(ArgSettings::TakesValue) {
+        true => {
+            option.kind(ApplicationCommandOptionType::String);
+            for value in arg.get_possible_values().unwrap_or_default() {
+                option.add_string_choice(value.get_name(), value.get_name());
+            }
+        }
+        false => {
+            option.kind(ApplicationCommandOptionType::Boolean);
+        }
+    }
+
+    option
+}
+
+/// The options of a clap app: its subcommands, or else its arguments, required ones first as
+/// Discord wants.
+fn options(app: &App) -> Vec<CreateApplicationCommandOption> {
+    let subcommands: Vec<_> = app
+        .get_subcommands()
+        .map(|sub| {
+            let mut option = CreateApplicationCommandOption::default();
+            option
+                .kind(ApplicationCommandOptionType::SubCommand)
+                .name(sub.get_name())
+                .description(description(sub.get_about()));
+            for sub_option in options(sub) {
+                option.add_sub_option(sub_option);
+            }
+            option
+        })
+        .collect();
+    if !subcommands.is_empty() {
+        return subcommands;
+    }
+
+    // clap's own --help and --version make no sense as options
+    let mut args: Vec<&Arg> = app
+        .get_arguments()
+        .filter(|arg| !matches!(arg.get_name(), "help" | "version"))
+        .collect();
+    args.sort_by_key(|arg| !arg.is_set(ArgSettings::Required));
+    args.into_iter().take(MAX_OPTIONS).map(option).collect()
+}
+
+fn slash_command(name: &str, command: &Command) -> CreateApplicationCommand {
+    let app = command.app(name.to_string());
+    let mut slash = CreateApplicationCommand::default();
+    slash
+        .name(name)
+        .kind(ApplicationCommandType::ChatInput)
+        .description(description(app.get_about()))
+        .set_options(options(&app));
+
+    slash
+}
+
+fn build<'a>(
+    builder: &'a mut CreateApplicationCommands,
+    commands: &HashMap<String, Command>,
+) -> &'a mut CreateApplicationCommands {
+    for (name, command) in commands {
+        builder.add_application_command(slash_command(name, command));
+    }
+
+    builder
+        .create_application_command(|c| c.name(CAPTION_THIS).kind(ApplicationCommandType::Message))
+}
+
+/// Registers every command as a slash command, along with the context-menu commands.
+///
+/// Global commands take up to an hour to reach every guild, so while testing they can be
+/// registered to the single guild in `IMGBOT_COMMAND_GUILD` instead, where they show up
+/// straight away.
+pub async fn register(http: &Http, commands: &HashMap<String, Command>) -> serenity::Result<()> {
+    match env::var("IMGBOT_COMMAND_GUILD")
+        .ok()
+        .and_then(|id| id.parse().ok())
+    {
+        Some(guild) => {
+            GuildId(guild)
+                .set_application_commands(http, |b| build(b, commands))
+                .await?;
+        }
+        None => {
+            ApplicationCommand::set_global_application_commands(http, |b| build(b, commands))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn push_args(
+    app: &App,
+    options: &[ApplicationCommandInteractionDataOption],
+    args: &mut Vec<String>,
+) {
+    let mut positionals = vec![];
+
+    for option in options {
+        if option.kind == ApplicationCommandOptionType::SubCommand {
+            args.push(option.name.clone());
+            if let Some(sub) = app.find_subcommand(&option.name) {
+                push_args(sub, &option.options, args);
+            }
+            return;
+        }
+
+        let arg = match app
+            .get_arguments()
+            .find(|arg| arg.get_name() == option.name)
+        {
+            Some(arg) => arg,
+            None => continue,
+        };
+        let value = match &option.value {
+            Some(Value::String(value)) => value.clone(),
+            Some(Value::Null) | None => continue,
+            Some(value) => value.to_string(),
+        };
+        let long = format!("--{}", arg.get_long().unwrap_or_else(|| arg.get_name()));
+
+        if arg.is_positional() {
+            positionals.push((arg.get_index(), value));
+        } else if arg.is_set(ArgSettings::TakesValue) {
+            args.push(long);
+            args.push(value);
+        } else if value == "true" {
+            args.push(long);
+        }
+    }
+
+    if !positionals.is_empty() {
+        // so text starting with `-' isn't read as a flag
+        args.push("--".to_string());
+        positionals.sort_by_key(|(index, _)| *index);
+        args.extend(positionals.into_iter().map(|(_, value)| value));
+    }
+}
+
+/// Turns an interaction into the arguments its command would get from a message, so both run
+/// through the same clap parser.
+pub fn interaction_args(
+    commands: &HashMap<String, Command>,
+    interaction: &ApplicationCommandInteraction,
+) -> Result<Vec<String>, CommandError> {
+    let data = &interaction.data;
+
+    if data.kind == ApplicationCommandType::Message && data.name == CAPTION_THIS {
+        let target = data
+            .target_id
+            .and_then(|id| data.resolved.messages.get(&id.to_message_id()))
+            .ok_or(CommandError::GenericError("That message can't be found."))?;
+
+        // the message's own text becomes the caption, without the link to its image
+        let mut text = target.content.clone();
+        for link in LinkFinder::new().links(&target.content) {
+            text = text.replace(link.as_str(), "");
+        }
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.is_empty() {
+            return Err(CommandError::GenericError(
+                "Caption this uses the message's text as the caption, and this one has none.",
+            ));
+        }
+
+        return Ok(vec!["caption".to_string(), "--".to_string(), text]);
+    }
+
+    let command = commands
+        .get(&data.name)
+        .ok_or(CommandError::GenericError("That command no longer exists."))?;
+    let app = command.app(data.name.clone());
+
+    let mut args = vec![data.name.clone()];
+    push_args(&app, &data.options, &mut args);
+    Ok(args)
+}
@@ -0,0 +1,350 @@
+use crate::provider::is_gif_page;
+use linkify::{LinkFinder, LinkKind};
+use regex::Regex;
+use serenity::model::channel::{Message, StickerFormatType};
+use serenity::model::id::{ChannelId, GuildId, MessageId};
+use std::sync::OnceLock;
+
+/// Extensions of the files the image server reads.
+static IMAGE_EXTENSIONS: &[&str] = &["gif", "png", "jpg", "jpeg", "webp"];
+
+/// Where in a message an image was found. Earlier kinds are preferred.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum SourceKind {
+    Attachment,
+    /// A link in the text to an image, or to a page on a [GIF site](is_gif_page).
+    Link,
+    /// The image, animation or thumbnail of an embed.
+    Embed,
+    Sticker,
+    /// A custom emoji in the text.
+    Emoji,
+    /// The avatar of a mentioned user.
+    Avatar,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Source {
+    pub kind: SourceKind,
+    pub url: String,
+}
+
+/// Whether `link` points at an image, ignoring its query string, or at a page on a
+/// [GIF site](is_gif_page).
+pub fn is_media_link(link: &str) -> bool {
+    let url = match url::Url::parse(link) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    is_gif_page(&url) || has_image_extension(url.path())
+}
+
+pub fn has_image_extension(path: &str) -> bool {
+    path.rsplit_once('.').is_some_and(|(_, extension)| {
+        IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+    })
+}
+
+/// Links in `content` that [point at media](is_media_link).
+pub fn media_links(content: &str) -> Vec<String> {
+    let mut finder = LinkFinder::new();
+    finder.kinds(&[LinkKind::Url]);
+
+    finder
+        .links(content)
+        .map(|link| link.as_str().to_string())
+        .filter(|link| is_media_link(link))
+        .collect()
+}
+
+/// The guild, channel and message a Discord message link points at. Links to DMs aren't
+/// taken, as the bot can't read them.
+pub fn message_link(value: &str) -> Option<(GuildId, ChannelId, MessageId)> {
+    let url = url::Url::parse(value).ok()?;
+    let host = url.host_str()?;
+    let host = ["ptb.", "canary.", "www."]
+        .iter()
+        .find_map(|sub| host.strip_prefix(sub))
+        .unwrap_or(host);
+    if host != "discord.com" && host != "discordapp.com" {
+        return None;
+    }
+
+    let mut segments = url.path_segments()?;
+    if segments.next()? != "channels" {
+        return None;
+    }
+    let guild = segments.next()?.parse().ok()?;
+    let channel = segments.next()?.parse().ok()?;
+    let message = segments.next()?.parse().ok()?;
+    if segments.next().is_some() {
+        return None;
+    }
+
+    Some((GuildId(guild), ChannelId(channel), MessageId(message)))
+}
+
+/// Images of the custom emoji in `content`, animated ones as GIFs.
+pub fn emoji(content: &str) -> Vec<String> {
+    static EMOJI: OnceLock<Regex> = OnceLock::new();
+    let emoji = EMOJI.get_or_init(|| Regex::new(r"<(a?):\w+:(\d+)>").unwrap());
+
+    emoji
+        .captures_iter(content)
+        .map(|c| {
+            let extension = match &c[1] {
+                "a" => "gif",
+                _ => "png",
+            };
+            format!("https://cdn.discordapp.com/emojis/{}.{}", &c[2], extension)
+        })
+        .collect()
+}
+
+/// Every image in a message, best first, without repeats.
+pub fn sources(msg: &Message) -> Vec<Source> {
+    let mut sources = vec![];
+    let mut push = |kind: SourceKind, url: &str| {
+        if !sources.iter().any(|s: &Source| s.url == url) {
+            sources.push(Source {
+                kind,
+                url: url.to_string(),
+            });
+        }
+    };
+
+    for attachment in &msg.attachments {
+        let is_image = match &attachment.content_type {
+            Some(content_type) => content_type.starts_with("image"),
+            None => has_image_extension(&attachment.filename),
+        };
+        if is_image {
+            push(SourceKind::Attachment, &attachment.url);
+        }
+    }
+    for link in media_links(&msg.content) {
+        push(SourceKind::Link, &link);
+    }
+    for embed in &msg.embeds {
+        if let Some(image) = &embed.image {
+            push(SourceKind::Embed, &image.url);
+        }
+        // videos are only usable when they're really GIFs, but then beat the still thumbnail
+        if let Some(video) = embed.video.as_ref().filter(|v| is_media_link(&v.url)) {
+            push(SourceKind::Embed, &video.url);
+        }
+        if let Some(thumbnail) = &embed.thumbnail {
+            push(SourceKind::Embed, &thumbnail.url);
+        }
+    }
+    for sticker in &msg.stickers {
+        // Lottie stickers are animations in JSON, which the image server can't read
+        if matches!(
+            sticker.format_type,
+            StickerFormatType::Png | StickerFormatType::Apng
+        ) {
+            let url = format!("https://media.discordapp.net/stickers/{}.png", sticker.id.0);
+            push(SourceKind::Sticker, &url);
+        }
+    }
+    for url in emoji(&msg.content) {
+        push(SourceKind::Emoji, &url);
+    }
+    for user in &msg.mentions {
+        push(SourceKind::Avatar, &user.face());
+    }
+
+    sources
+}
+
+/// The image a command replying to or linking `msg` should use.
+pub fn first_image(msg: &Message) -> Option<String> {
+    sources(msg).into_iter().next().map(|source| source.url)
+}
+
+/// The images `msg` posted, best first. Emoji and mentioned avatars aren't counted, as they
+/// come up in conversation without anyone meaning to share an image.
+pub fn posted_images(msg: &Message) -> Vec<String> {
+    sources(msg)
+        .into_iter()
+        .filter(|source| source.kind <= SourceKind::Sticker)
+        .map(|source| source.url)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(extra: serde_json::Value) -> Message {
+        let mut msg = json!({
+            "id": "1",
+            "channel_id": "2",
+            "author": {"id": "3", "username": "a", "discriminator": "0001", "avatar": null},
+            "content": "",
+            "timestamp": "2022-01-01T00:00:00Z",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [],
+            "embeds": [],
+            "pinned": false,
+            "type": 0
+        });
+        for (key, value) in extra.as_object().unwrap() {
+            msg[key] = value.clone();
+        }
+
+        serde_json::from_value(msg).unwrap()
+    }
+
+    fn attachment(filename: &str, content_type: Option<&str>) -> serde_json::Value {
+        json!({
+            "id": "10",
+            "filename": filename,
+            "size": 100,
+            "url": format!("https://cdn.discordapp.com/attachments/{}", filename),
+            "proxy_url": format!("https://media.discordapp.net/attachments/{}", filename),
+            "content_type": content_type
+        })
+    }
+
+    #[test]
+    fn recognises_media_links() {
+        assert!(is_media_link("https://example.com/a.gif"));
+        assert!(is_media_link("https://example.com/a.JPEG"));
+        assert!(is_media_link("https://example.com/a.webp"));
+        assert!(is_media_link(
+            "https://cdn.example.com/a.png?width=400&height=300"
+        ));
+        assert!(is_media_link("https://tenor.com/view/cat-12345"));
+        assert!(is_media_link("https://www.tenor.com/view/cat-12345"));
+        assert!(is_media_link("https://giphy.com/gifs/cat-abc123"));
+        assert!(is_media_link("https://i.imgur.com/abc.gifv"));
+
+        assert!(!is_media_link("https://example.com/a.mp4"));
+        assert!(!is_media_link("https://example.com/page?file=a.png"));
+        assert!(!is_media_link("https://example.com/"));
+        assert!(!is_media_link("not a link.png"));
+    }
+
+    #[test]
+    fn finds_links_in_text() {
+        assert_eq!(
+            media_links("look https://a.com/x.png and https://a.com/page, https://a.com/y.gif?v=2"),
+            ["https://a.com/x.png", "https://a.com/y.gif?v=2"]
+        );
+        assert!(media_links("no links, mail@a.com").is_empty());
+    }
+
+    #[test]
+    fn parses_message_links() {
+        let ids = Some((GuildId(1), ChannelId(2), MessageId(3)));
+        assert_eq!(message_link("https://discord.com/channels/1/2/3"), ids);
+        assert_eq!(message_link("https://ptb.discord.com/channels/1/2/3"), ids);
+        assert_eq!(
+            message_link("https://canary.discordapp.com/channels/1/2/3"),
+            ids
+        );
+        assert_eq!(message_link("https://discord.com/channels/1/2/3?x=y"), ids);
+
+        assert_eq!(message_link("https://discord.com/channels/@me/2/3"), None);
+        assert_eq!(message_link("https://discord.com/channels/1/2"), None);
+        assert_eq!(message_link("https://discord.com/channels/1/2/3/4"), None);
+        assert_eq!(message_link("https://notdiscord.com/channels/1/2/3"), None);
+        assert_eq!(message_link("https://example.com/a.png"), None);
+    }
+
+    #[test]
+    fn finds_custom_emoji() {
+        assert_eq!(
+            emoji("hi <:kek:123> <a:dance:456> :smile: <@789>"),
+            [
+                "https://cdn.discordapp.com/emojis/123.png",
+                "https://cdn.discordapp.com/emojis/456.gif"
+            ]
+        );
+    }
+
+    #[test]
+    fn orders_sources_by_kind() {
+        let msg = message(json!({
+            "content": "<:kek:123> https://a.com/x.png <@5>",
+            "attachments": [attachment("b.txt", Some("text/plain")), attachment("c.png", None)],
+            "embeds": [{
+                "type": "gifv",
+                "video": {"url": "https://a.com/v.gif", "height": 1, "width": 1},
+                "thumbnail": {"url": "https://a.com/t.png", "proxy_url": "", "height": 1, "width": 1}
+            }],
+            "mentions": [{"id": "5", "username": "b", "discriminator": "0002", "avatar": null}]
+        }));
+
+        let kinds: Vec<_> = sources(&msg).into_iter().map(|s| (s.kind, s.url)).collect();
+        assert_eq!(
+            kinds,
+            [
+                (
+                    SourceKind::Attachment,
+                    "https://cdn.discordapp.com/attachments/c.png".to_string()
+                ),
+                (SourceKind::Link, "https://a.com/x.png".to_string()),
+                (SourceKind::Embed, "https://a.com/v.gif".to_string()),
+                (SourceKind::Embed, "https://a.com/t.png".to_string()),
+                (
+                    SourceKind::Emoji,
+                    "https://cdn.discordapp.com/emojis/123.png".to_string()
+                ),
+                (
+                    SourceKind::Avatar,
+                    "https://cdn.discordapp.com/embed/avatars/2.png".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_repeats_and_unusable_embeds() {
+        // Discord embeds image links with the link itself as the thumbnail
+        let msg = message(json!({
+            "content": "https://a.com/x.png",
+            "embeds": [
+                {
+                    "type": "image",
+                    "thumbnail": {"url": "https://a.com/x.png", "proxy_url": "", "height": 1, "width": 1}
+                },
+                {
+                    "type": "video",
+                    "video": {"url": "https://a.com/v.mp4", "height": 1, "width": 1}
+                }
+            ]
+        }));
+
+        assert_eq!(sources(&msg).len(), 1);
+        assert_eq!(first_image(&msg).as_deref(), Some("https://a.com/x.png"));
+    }
+
+    #[test]
+    fn posted_images_leave_out_emoji_and_avatars() {
+        let msg = message(json!({
+            "content": "<:kek:123> <@5>",
+            "mentions": [{"id": "5", "username": "b", "discriminator": "0002", "avatar": null}]
+        }));
+
+        assert!(posted_images(&msg).is_empty());
+        assert_eq!(
+            first_image(&msg).as_deref(),
+            Some("https://cdn.discordapp.com/emojis/123.png")
+        );
+    }
+
+    #[test]
+    fn nothing_in_plain_text() {
+        let msg = message(json!({"content": "just words"}));
+
+        assert!(sources(&msg).is_empty());
+        assert_eq!(first_image(&msg), None);
+    }
+}
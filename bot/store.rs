@@ -0,0 +1,271 @@
+use crate::access::RoleRules;
+use crate::settings::{GuildSettings, Key};
+use rusqlite::{params, Connection};
+use serenity::model::id::{GuildId, RoleId};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::warn;
+
+/// Settings that have to outlive the bot, kept in an SQLite file.
+///
+/// Everything is read once at startup and cached in [`crate::bot::BotData`]; the store is only
+/// written to when a setting changes.
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+impl Store {
+    /// Opens the database at `path`, creating it and its tables if needed.
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS guild_prefixes (
+                guild_id INTEGER PRIMARY KEY,
+                prefix TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS guild_settings (
+                guild_id INTEGER NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (guild_id, key)
+            );
+            CREATE TABLE IF NOT EXISTS command_roles (
+                guild_id INTEGER NOT NULL,
+                command TEXT NOT NULL,
+                role_id INTEGER NOT NULL,
+                allow INTEGER NOT NULL,
+                PRIMARY KEY (guild_id, command, role_id)
+            );",
+        )?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Every guild's custom prefix.
+    pub fn prefixes(&self) -> rusqlite::Result<HashMap<GuildId, String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT guild_id, prefix FROM guild_prefixes")?;
+        let rows = statement.query_map([], |row| {
+            Ok((GuildId(row.get::<_, i64>(0)? as u64), row.get(1)?))
+        })?;
+
+        rows.collect()
+    }
+
+    /// Sets a guild's prefix, or goes back to the default with `None`.
+    pub fn set_prefix(&self, guild: GuildId, prefix: Option<&str>) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        match prefix {
+            Some(prefix) => conn.execute(
+                "INSERT INTO guild_prefixes (guild_id, prefix) VALUES (?1, ?2)
+                 ON CONFLICT (guild_id) DO UPDATE SET prefix = excluded.prefix",
+                params![guild.0 as i64, prefix],
+            )?,
+            None => conn.execute(
+                "DELETE FROM guild_prefixes WHERE guild_id = ?1",
+                params![guild.0 as i64],
+            )?,
+        };
+
+        Ok(())
+    }
+
+    /// Every guild that changed a setting or set role rules, with the rest left at their
+    /// defaults.
+    pub fn settings(&self) -> rusqlite::Result<HashMap<GuildId, GuildSettings>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT guild_id, key, value FROM guild_settings")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                GuildId(row.get::<_, i64>(0)? as u64),
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?;
+
+        let mut settings: HashMap<GuildId, GuildSettings> = HashMap::new();
+        for row in rows {
+            let (guild, key, value) = row?;
+            let set =
+                Key::from_name(&key).map(|key| settings.entry(guild).or_default().set(key, &value));
+            // settings from older versions, or ones whose rules got stricter, are dropped
+            if !matches!(set, Some(Ok(()))) {
+                warn!(guild = guild.0, key = %key, value = %value, "Ignoring stored setting");
+            }
+        }
+
+        let mut statement =
+            conn.prepare("SELECT guild_id, command, role_id, allow FROM command_roles")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                GuildId(row.get::<_, i64>(0)? as u64),
+                row.get::<_, String>(1)?,
+                RoleId(row.get::<_, i64>(2)? as u64),
+                row.get::<_, bool>(3)?,
+            ))
+        })?;
+        for row in rows {
+            let (guild, command, role, allow) = row?;
+            let rules = settings
+                .entry(guild)
+                .or_default()
+                .command_roles
+                .entry(command)
+                .or_default();
+            match allow {
+                true => rules.allow.push(role),
+                false => rules.deny.push(role),
+            }
+        }
+
+        Ok(settings)
+    }
+
+    /// Stores a guild's setting in the form [`GuildSettings::get`] gives, or goes back to the
+    /// default with `None`.
+    pub fn set_setting(
+        &self,
+        guild: GuildId,
+        key: Key,
+        value: Option<&str>,
+    ) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        match value {
+            Some(value) => conn.execute(
+                "INSERT INTO guild_settings (guild_id, key, value) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (guild_id, key) DO UPDATE SET value = excluded.value",
+                params![guild.0 as i64, key.name(), value],
+            )?,
+            None => conn.execute(
+                "DELETE FROM guild_settings WHERE guild_id = ?1 AND key = ?2",
+                params![guild.0 as i64, key.name()],
+            )?,
+        };
+
+        Ok(())
+    }
+
+    /// Replaces the role rules of a guild's command.
+    pub fn set_command_roles(
+        &self,
+        guild: GuildId,
+        command: &str,
+        rules: &RoleRules,
+    ) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let transaction = conn.transaction()?;
+        transaction.execute(
+            "DELETE FROM command_roles WHERE guild_id = ?1 AND command = ?2",
+            params![guild.0 as i64, command],
+        )?;
+        let roles = rules
+            .allow
+            .iter()
+            .map(|role| (role, true))
+            .chain(rules.deny.iter().map(|role| (role, false)));
+        for (role, allow) in roles {
+            transaction.execute(
+                "INSERT INTO command_roles (guild_id, command, role_id, allow) VALUES (?1, ?2, ?3, ?4)",
+                params![guild.0 as i64, command, role.0 as i64, allow],
+            )?;
+        }
+
+        transaction.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> Store {
+        Store::open(Path::new(":memory:")).unwrap()
+    }
+
+    #[test]
+    fn stores_prefixes() {
+        let store = store();
+        store.set_prefix(GuildId(1), Some("?")).unwrap();
+        store.set_prefix(GuildId(2), Some("!")).unwrap();
+        store.set_prefix(GuildId(2), Some("$")).unwrap();
+        store.set_prefix(GuildId(1), None).unwrap();
+
+        let prefixes = store.prefixes().unwrap();
+        assert_eq!(prefixes.len(), 1);
+        assert_eq!(prefixes[&GuildId(2)], "$");
+    }
+
+    #[test]
+    fn stores_settings() {
+        let store = store();
+        store
+            .set_setting(GuildId(1), Key::CaptionStyle, Some("dark"))
+            .unwrap();
+        store
+            .set_setting(GuildId(1), Key::MaxGifSize, Some("1MB"))
+            .unwrap();
+        store
+            .set_setting(GuildId(1), Key::MaxGifSize, Some("2MB"))
+            .unwrap();
+        store
+            .set_setting(GuildId(2), Key::Suggestions, Some("off"))
+            .unwrap();
+        store
+            .set_setting(GuildId(2), Key::Suggestions, None)
+            .unwrap();
+
+        let settings = store.settings().unwrap();
+        let first = &settings[&GuildId(1)];
+        assert_eq!(first.caption_style, "dark");
+        assert_eq!(first.max_gif_size, 2 * 1024 * 1024);
+        assert!(!settings.contains_key(&GuildId(2)));
+    }
+
+    #[test]
+    fn drops_settings_it_cant_read() {
+        let store = store();
+        store
+            .set_setting(GuildId(1), Key::Nsfw, Some("nsfw_channels"))
+            .unwrap();
+        {
+            let conn = store.conn.lock().unwrap();
+            conn.execute(
+                "INSERT INTO guild_settings (guild_id, key, value) VALUES
+                    (1, 'locale', 'en-GB'),
+                    (1, 'caption_style', 'blue')",
+                [],
+            )
+            .unwrap();
+        }
+
+        let settings = &store.settings().unwrap()[&GuildId(1)];
+        assert_eq!(settings.get(Key::Nsfw), "nsfw_channels");
+        assert_eq!(settings.caption_style, "light");
+    }
+
+    #[test]
+    fn replaces_command_roles() {
+        let store = store();
+        let rules = |allow: &[u64], deny: &[u64]| RoleRules {
+            allow: allow.iter().copied().map(RoleId).collect(),
+            deny: deny.iter().copied().map(RoleId).collect(),
+        };
+        store
+            .set_command_roles(GuildId(1), "caption", &rules(&[1, 2], &[3]))
+            .unwrap();
+        store
+            .set_command_roles(GuildId(1), "caption", &rules(&[4], &[]))
+            .unwrap();
+        store
+            .set_command_roles(GuildId(1), "reverse", &rules(&[], &[5]))
+            .unwrap();
+
+        let settings = &store.settings().unwrap()[&GuildId(1)];
+        assert_eq!(settings.command_roles["caption"].allow, [RoleId(4)]);
+        assert!(settings.command_roles["caption"].deny.is_empty());
+        assert_eq!(settings.command_roles["reverse"].deny, [RoleId(5)]);
+    }
+}
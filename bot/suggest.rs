@@ -0,0 +1,84 @@
+/// Most edits a word may be away from a command for it to be suggested.
+static MAX_DISTANCE: usize = 2;
+
+/// The Levenshtein distance between `a` and `b`: how many characters have to be inserted,
+/// removed or replaced to turn one into the other.
+pub fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // distances from the part of `a` seen so far to every prefix of `b`
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let replace = diagonal + (a != *b) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = replace.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// The candidate closest to `word`, if any is close enough to be what was meant. Ties go to
+/// the first candidate.
+pub fn closest<'a>(word: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let word = word.to_lowercase();
+    let mut best: Option<(usize, &str)> = None;
+
+    for candidate in candidates {
+        let distance = distance(&word, candidate);
+        // short words are a few edits away from everything
+        if distance > MAX_DISTANCE || distance >= word.chars().count() {
+            continue;
+        }
+        if best.is_none_or(|(best, _)| distance < best) {
+            best = Some((distance, candidate));
+        }
+    }
+
+    best.map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_edits() {
+        assert_eq!(distance("caption", "caption"), 0);
+        assert_eq!(distance("capton", "caption"), 1);
+        assert_eq!(distance("catpion", "caption"), 2);
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("", "spin"), 4);
+        assert_eq!(distance("zoom", ""), 4);
+    }
+
+    #[test]
+    fn suggests_close_commands() {
+        let commands = ["caption", "crop", "spin", "shake", "zoom"];
+
+        assert_eq!(closest("captoin", commands), Some("caption"));
+        assert_eq!(closest("CAPTON", commands), Some("caption"));
+        assert_eq!(closest("spn", commands), Some("spin"));
+        assert_eq!(closest("zom", commands), Some("zoom"));
+    }
+
+    #[test]
+    fn ignores_unrelated_words() {
+        let commands = ["caption", "crop", "spin", "shake", "zoom"];
+
+        assert_eq!(closest("hello", commands), None);
+        assert_eq!(closest("x", commands), None);
+        assert_eq!(closest(")", commands), None);
+        assert_eq!(closest("", commands), None);
+    }
+
+    #[test]
+    fn prefers_the_closest() {
+        assert_eq!(closest("sha", ["shake", "sh"]), Some("sh"));
+        assert_eq!(closest("shae", ["share", "shake"]), Some("share"));
+        assert_eq!(closest("shae", ["shake", "share"]), Some("shake"));
+    }
+}
@@ -1,5 +0,0 @@
-mod client;
-mod data;
-
-pub use client::*;
-pub use data::*;
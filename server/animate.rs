@@ -0,0 +1,346 @@
+use std::f32::consts::TAU;
+use std::sync::Arc;
+
+use actix_web::*;
+use image::imageops::FilterType;
+use image::{imageops, Delay, DynamicImage, Frame, Rgba, RgbaImage};
+use imageproc::drawing::draw_polygon_mut;
+use imageproc::geometric_transformations::{rotate_about_center, translate, Interpolation};
+use imageproc::point::Point;
+use rusttype::Scale;
+use shared::ImageError;
+
+use crate::font::{DrawableFont, HorizontalGravity, VerticalGravity};
+use crate::images::AnimateImageRequest;
+use crate::queue::Job;
+use crate::{images, AppState};
+
+static DEFAULT_FRAMES: u32 = 20;
+static MAX_FRAMES: u32 = 60;
+static DEFAULT_FPS: u32 = 20;
+static MAX_FPS: u32 = 50;
+
+/// Largest side of a generated animation. Sources bigger than this are scaled down first, as
+/// every frame is a full copy of the source.
+static MAX_SIDE: u32 = 512;
+
+/// Names of the effects, as used in `/animate/{effect}`.
+pub static EFFECT_NAMES: &[&str] = &["spin", "shake", "zoom", "pulse", "speedlines", "triggered"];
+
+#[derive(Clone, Copy)]
+pub enum Effect {
+    Spin,
+    Shake,
+    Zoom,
+    Pulse,
+    SpeedLines,
+    Triggered,
+}
+
+impl Effect {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "spin" => Some(Effect::Spin),
+            "shake" => Some(Effect::Shake),
+            "zoom" => Some(Effect::Zoom),
+            "pulse" => Some(Effect::Pulse),
+            "speedlines" => Some(Effect::SpeedLines),
+            "triggered" => Some(Effect::Triggered),
+            _ => None,
+        }
+    }
+}
+
+/// Tiny xorshift generator, so shakes and speed lines differ per frame but stay reproducible.
+struct Jitter(u32);
+
+impl Jitter {
+    fn new(seed: usize) -> Self {
+        Self((seed as u32).wrapping_mul(2654435761).wrapping_add(0x9e3779b9) | 1)
+    }
+
+    /// Next value in `[-1, 1]`.
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 as f32 / u32::MAX as f32) * 2. - 1.
+    }
+}
+
+/// Scales `img` by `factor` around its center, keeping the original canvas size.
+fn scale_center(img: &RgbaImage, factor: f32) -> RgbaImage {
+    let (w, h) = img.dimensions();
+    let sw = ((w as f32 * factor) as u32).max(1);
+    let sh = ((h as f32 * factor) as u32).max(1);
+    let scaled = imageops::resize(img, sw, sh, FilterType::Triangle);
+
+    if sw >= w && sh >= h {
+        return imageops::crop_imm(&scaled, (sw - w) / 2, (sh - h) / 2, w, h).to_image();
+    }
+
+    let mut out = RgbaImage::new(w, h);
+    imageops::overlay(&mut out, &scaled, w.saturating_sub(sw) / 2, h.saturating_sub(sh) / 2);
+    out
+}
+
+fn shake(img: &RgbaImage, i: usize, amplitude: f32) -> RgbaImage {
+    let mut jitter = Jitter::new(i);
+    let dx = (jitter.next() * amplitude) as i32;
+    let dy = (jitter.next() * amplitude) as i32;
+
+    translate(img, (dx, dy))
+}
+
+fn speed_lines(img: &RgbaImage, i: usize) -> RgbaImage {
+    let mut out = img.clone();
+    let (w, h) = (img.width() as f32, img.height() as f32);
+    let (cx, cy) = (w / 2., h / 2.);
+    let outer = (cx * cx + cy * cy).sqrt();
+
+    let mut jitter = Jitter::new(i);
+    for _ in 0..24 {
+        let angle = jitter.next() * TAU;
+        let spread = 0.01 + (jitter.next() + 1.) * 0.01;
+        let inner = outer * (0.55 + jitter.next() * 0.15);
+
+        let poly = [
+            Point::new(
+                (cx + outer * (angle - spread).cos()) as i32,
+                (cy + outer * (angle - spread).sin()) as i32,
+            ),
+            Point::new(
+                (cx + outer * (angle + spread).cos()) as i32,
+                (cy + outer * (angle + spread).sin()) as i32,
+            ),
+            Point::new(
+                (cx + inner * angle.cos()) as i32,
+                (cy + inner * angle.sin()) as i32,
+            ),
+        ];
+
+        draw_polygon_mut(&mut out, &poly, Rgba([255u8, 255u8, 255u8, 255u8]));
+    }
+
+    out
+}
+
+/// The red strip along the bottom of a triggered frame, with `text` drawn on it. It's the same
+/// on every frame, so it's drawn once rather than per frame.
+fn triggered_banner(
+    font: &'static [u8],
+    text: String,
+    width: u32,
+    height: u32,
+) -> Result<RgbaImage, ImageError> {
+    let banner = (height / 6).max(1);
+    let mut out = RgbaImage::from_pixel(width, banner, Rgba([255u8, 0u8, 0u8, 255u8]));
+
+    let scale = banner as f32 * 0.8;
+    DrawableFont::from(font)
+        .lock()
+        .unwrap()
+        .text(text)
+        .scale(Scale { x: scale, y: scale })
+        .color(Rgba([255u8, 255u8, 255u8, 255u8]))
+        .extents(width, banner)
+        .gravity(
+            HorizontalGravity::CenterGravity,
+            VerticalGravity::CenterGravity,
+        )
+        .flush(&mut out, 0., 0.)
+        .map_err(images::image_error)?;
+
+    Ok(out)
+}
+
+/// The frames of `effect` played on `source`, drawing any banner with `font`.
+async fn animate_frames(
+    job: &Job,
+    effect: Effect,
+    source: RgbaImage,
+    request: &AnimateImageRequest,
+    font: &'static [u8],
+) -> Result<Vec<Frame>, ImageError> {
+    let frame_count = request.frames.unwrap_or(DEFAULT_FRAMES).clamp(2, MAX_FRAMES);
+    let fps = request.fps.unwrap_or(DEFAULT_FPS).clamp(1, MAX_FPS);
+
+    let source = match source.width().max(source.height()) > MAX_SIDE {
+        true => DynamicImage::ImageRgba8(source)
+            .thumbnail(MAX_SIDE, MAX_SIDE)
+            .into_rgba8(),
+        false => source,
+    };
+
+    let amplitude = (source.width().max(source.height()) as f32 / 25.).max(2.);
+    let delay = Delay::from_numer_denom_ms(1000, fps);
+    let banner = match effect {
+        Effect::Triggered => {
+            let text = request
+                .text
+                .clone()
+                .filter(|t| !t.trim().is_empty())
+                .unwrap_or_else(|| "TRIGGERED".to_string())
+                .to_uppercase();
+            let (width, height) = source.dimensions();
+            Some(Arc::new(triggered_banner(font, text, width, height)?))
+        }
+        _ => None,
+    };
+
+    images::generate(job, source, frame_count, delay, move |i, t, img| {
+        let img = img.into_rgba8();
+
+        let out = match effect {
+            Effect::Spin => {
+                rotate_about_center(&img, t * TAU, Interpolation::Bilinear, Rgba([0u8; 4]))
+            }
+            Effect::Shake => shake(&img, i, amplitude),
+            Effect::Zoom => scale_center(&img, 1. + t),
+            Effect::Pulse => scale_center(&img, 1. + 0.15 * (t * TAU).sin()),
+            Effect::SpeedLines => speed_lines(&img, i),
+            Effect::Triggered => {
+                let mut out = shake(&scale_center(&img, 1.15), i, amplitude);
+                if let Some(banner) = &banner {
+                    let top = out.height() - banner.height();
+                    imageops::overlay(&mut out, &**banner, 0, top);
+                }
+
+                out
+            }
+        };
+
+        Ok(DynamicImage::ImageRgba8(out))
+    })
+    .await
+}
+
+pub async fn run(
+    data: &AppState,
+    job: &Job,
+    effect: Effect,
+    request: &AnimateImageRequest,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    let image = images::source_bytes(data, &request.target_url).await?;
+
+    let (frames, _) = images::decode(image).await?;
+    let source = frames
+        .into_iter()
+        .next()
+        .ok_or(ImageError::BadImage("No frames".to_string()))?
+        .into_buffer();
+
+    let frames = animate_frames(job, effect, source, request, data.templates.caption_font).await?;
+
+    images::encode(job, frames, true, request.gif.clone()).await
+}
+
+#[post("/animate/{effect}")]
+pub async fn animate(
+    effect: web::Path<String>,
+    request: web::Json<AnimateImageRequest>,
+    data: web::Data<AppState>,
+    job: Job,
+) -> Result<HttpResponse> {
+    let effect = match Effect::from_name(effect.as_str()) {
+        Some(effect) => effect,
+        None => {
+            return Ok(HttpResponse::NotFound().body(format!("Unknown effect {}", effect)));
+        }
+    };
+
+    images::respond(run(&data, &job, effect, &request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::CancelToken;
+    use crate::config::Config;
+    use crate::queue::JobQueue;
+    use crate::templates::Templates;
+    use std::time::Duration;
+
+    async fn frames(
+        effect: &str,
+        width: u32,
+        height: u32,
+        request: serde_json::Value,
+    ) -> Vec<Frame> {
+        let queue = JobQueue::new(&Config::default());
+        let job = queue
+            .enter(Arc::default(), CancelToken::default())
+            .await
+            .unwrap();
+        let mut request = request;
+        request["target_url"] = "https://example.com/a.png".into();
+        let request: AnimateImageRequest = serde_json::from_value(request).unwrap();
+        let source = RgbaImage::from_pixel(width, height, Rgba([0, 128, 255, 255]));
+        let font = Templates::load(None).unwrap().caption_font;
+
+        animate_frames(&job, Effect::from_name(effect).unwrap(), source, &request, font)
+            .await
+            .unwrap()
+    }
+
+    fn delay(frame: &Frame) -> Duration {
+        Duration::from(frame.delay())
+    }
+
+    #[actix_rt::test]
+    async fn keeps_the_canvas() {
+        for effect in EFFECT_NAMES {
+            let request = serde_json::json!({"frames": 6, "fps": 10});
+            let frames = frames(effect, 40, 30, request).await;
+
+            assert_eq!(frames.len(), 6, "{}", effect);
+            for frame in &frames {
+                assert_eq!(frame.buffer().dimensions(), (40, 30), "{}", effect);
+                assert_eq!(delay(frame), Duration::from_millis(100), "{}", effect);
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn clamps_frames_and_fps() {
+        let defaults = frames("spin", 8, 8, serde_json::json!({})).await;
+        assert_eq!(defaults.len(), DEFAULT_FRAMES as usize);
+        assert_eq!(delay(&defaults[0]), Duration::from_millis(50));
+
+        let few = frames("spin", 8, 8, serde_json::json!({"frames": 1, "fps": 0})).await;
+        assert_eq!(few.len(), 2);
+        assert_eq!(delay(&few[0]), Duration::from_secs(1));
+
+        let request = serde_json::json!({"frames": 500, "fps": 500});
+        let many = frames("spin", 8, 8, request).await;
+        assert_eq!(many.len(), MAX_FRAMES as usize);
+        assert_eq!(delay(&many[0]), Duration::from_millis(20));
+    }
+
+    #[actix_rt::test]
+    async fn scales_big_sources_down() {
+        let frames = frames("zoom", 1024, 256, serde_json::json!({"frames": 2})).await;
+
+        assert_eq!(frames[0].buffer().dimensions(), (MAX_SIDE, 128));
+    }
+
+    #[actix_rt::test]
+    async fn pulses_the_image() {
+        let frames = frames("pulse", 40, 40, serde_json::json!({"frames": 4})).await;
+
+        // three quarters of the way through, the pulse is at its smallest
+        assert_eq!(frames[0].buffer().get_pixel(0, 0).0, [0, 128, 255, 255]);
+        assert_eq!(frames[3].buffer().get_pixel(0, 0).0, [0, 0, 0, 0]);
+        assert_eq!(frames[3].buffer().get_pixel(20, 20).0, [0, 128, 255, 255]);
+    }
+
+    #[actix_rt::test]
+    async fn draws_the_triggered_banner() {
+        let frames = frames("triggered", 60, 60, serde_json::json!({"frames": 3})).await;
+
+        for frame in &frames {
+            let bottom = frame.buffer().height() - 1;
+            assert_eq!(frame.buffer().get_pixel(0, bottom).0, [255, 0, 0, 255]);
+        }
+    }
+}
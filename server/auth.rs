@@ -0,0 +1,535 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, Error, FromRequest, HttpResponse, ResponseError};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use shared::AuthError;
+
+use crate::config::AuthConfig;
+use crate::AppState;
+
+/// Paths polled by k8s and Prometheus, and the API docs, which don't take keys.
+static OPEN_PATHS: &[&str] = &[
+    "/health",
+    "/livez",
+    "/readyz",
+    "/metrics",
+    "/openapi.json",
+    "/playground",
+];
+/// Headers of a signed request.
+pub static KEY_ID_HEADER: &str = "X-Key-Id";
+pub static TIMESTAMP_HEADER: &str = "X-Timestamp";
+pub static SIGNATURE_HEADER: &str = "X-Signature";
+
+static DAY_SECS: u64 = 24 * 60 * 60;
+
+/// How much a key has used of its limits.
+struct Usage {
+    /// Submissions left in the rate limit bucket, refilled continuously.
+    tokens: f64,
+    refilled: Instant,
+    /// UTC day `used` counts towards, in days since the epoch.
+    day: u64,
+    used: u64,
+}
+
+struct ApiKey {
+    name: String,
+    secret: Vec<u8>,
+    rate_per_minute: Option<u32>,
+    daily_quota: Option<u64>,
+    usage: Mutex<Usage>,
+}
+
+impl ApiKey {
+    /// Counts a job submission against the key's limits, failing if it has none left.
+    fn charge(&self) -> Result<(), AuthError> {
+        self.charge_at(unix_secs(), Instant::now())
+    }
+
+    fn charge_at(&self, now: u64, instant: Instant) -> Result<(), AuthError> {
+        let mut usage = self.usage.lock().unwrap();
+
+        if now / DAY_SECS != usage.day {
+            usage.day = now / DAY_SECS;
+            usage.used = 0;
+        }
+        if let Some(quota) = self.daily_quota {
+            if usage.used >= quota {
+                return Err(AuthError::QuotaExceeded(DAY_SECS - now % DAY_SECS));
+            }
+        }
+
+        if let Some(rate) = self.rate_per_minute {
+            let per_sec = rate as f64 / 60.;
+            let elapsed = instant.saturating_duration_since(usage.refilled);
+            usage.tokens = (usage.tokens + elapsed.as_secs_f64() * per_sec).min(rate as f64);
+            usage.refilled = instant;
+
+            if usage.tokens < 1. {
+                let wait = ((1. - usage.tokens) / per_sec).ceil() as u64;
+                return Err(AuthError::RateLimited(wait.max(1)));
+            }
+            usage.tokens -= 1.;
+        }
+
+        usage.used += 1;
+        Ok(())
+    }
+
+    /// Gives back a charge for a submission that was turned away without doing any work.
+    fn refund(&self) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.used = usage.used.saturating_sub(1);
+        if let Some(rate) = self.rate_per_minute {
+            usage.tokens = (usage.tokens + 1.).min(rate as f64);
+        }
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The API keys clients identify themselves with.
+///
+/// A request either sends a key as `Authorization: Bearer <secret>`, or signs itself with one:
+/// [`SIGNATURE_HEADER`] holds the hex HMAC-SHA256, under the key's secret, of
+/// `<timestamp>\n<method>\n<path and query>\n<hex SHA-256 of the body>`, with the key's name in
+/// [`KEY_ID_HEADER`] and the unix timestamp in [`TIMESTAMP_HEADER`]. Signing keeps the secret
+/// off the wire, for clients outside the cluster.
+///
+/// A signature is only taken once, and remembered for as long as its timestamp is within the
+/// allowed clock skew, so a captured request can't be sent again. Each replica keeps its own
+/// list, though: within that window, a copy sent to another replica is still taken.
+pub struct Auth {
+    keys: Vec<ApiKey>,
+    by_name: HashMap<String, usize>,
+    /// Keys by the SHA-256 of their secret, so looking up a bearer token doesn't leak how
+    /// much of it matched.
+    by_digest: HashMap<Vec<u8>, usize>,
+    max_clock_skew: u64,
+    /// Signatures already taken, with their timestamps.
+    seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl Auth {
+    pub fn new(config: &AuthConfig) -> Self {
+        let keys: Vec<ApiKey> = config
+            .keys
+            .iter()
+            .map(|key| ApiKey {
+                name: key.name.clone(),
+                secret: key.secret.as_bytes().to_vec(),
+                rate_per_minute: key.rate_per_minute,
+                daily_quota: key.daily_quota,
+                usage: Mutex::new(Usage {
+                    tokens: key.rate_per_minute.unwrap_or(0) as f64,
+                    refilled: Instant::now(),
+                    day: unix_secs() / DAY_SECS,
+                    used: 0,
+                }),
+            })
+            .collect();
+
+        Self {
+            by_name: keys
+                .iter()
+                .enumerate()
+                .map(|(i, key)| (key.name.clone(), i))
+                .collect(),
+            by_digest: keys
+                .iter()
+                .enumerate()
+                .map(|(i, key)| (Sha256::digest(&key.secret).to_vec(), i))
+                .collect(),
+            keys,
+            max_clock_skew: config.max_clock_skew_secs,
+            seen: Mutex::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    fn bearer(&self, token: &str) -> Result<&ApiKey, AuthError> {
+        self.by_digest
+            .get(Sha256::digest(token.as_bytes()).as_slice())
+            .map(|&i| &self.keys[i])
+            .ok_or(AuthError::UnknownKey)
+    }
+
+    fn signed(
+        &self,
+        name: &str,
+        timestamp: &str,
+        signature: &str,
+        method: &Method,
+        path: &str,
+        body: &[u8],
+    ) -> Result<&ApiKey, AuthError> {
+        let key = self
+            .by_name
+            .get(name)
+            .map(|&i| &self.keys[i])
+            .ok_or(AuthError::UnknownKey)?;
+
+        let now = unix_secs();
+        let sent = timestamp.parse::<u64>().map_err(|_| AuthError::StaleTimestamp)?;
+        if now.abs_diff(sent) > self.max_clock_skew {
+            return Err(AuthError::StaleTimestamp);
+        }
+
+        let signature = hex::decode(signature).map_err(|_| AuthError::BadSignature)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key.secret)
+            .map_err(|_| AuthError::BadSignature)?;
+        mac.update(canonical(timestamp, method, path, body).as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| AuthError::BadSignature)?;
+
+        let mut seen = self.seen.lock().unwrap();
+        // anything older would be turned away as stale anyway
+        seen.retain(|_, sent| now.abs_diff(*sent) <= self.max_clock_skew);
+        if seen.insert(signature, sent).is_some() {
+            return Err(AuthError::Replayed);
+        }
+
+        Ok(key)
+    }
+
+    fn key(&self, i: usize) -> &ApiKey {
+        &self.keys[i]
+    }
+
+    fn index_of(&self, key: &ApiKey) -> usize {
+        self.by_name[&key.name]
+    }
+}
+
+/// What a signed request's signature covers.
+fn canonical(timestamp: &str, method: &Method, path: &str, body: &[u8]) -> String {
+    format!(
+        "{}\n{}\n{}\n{}",
+        timestamp,
+        method.as_str(),
+        path,
+        hex::encode(Sha256::digest(body))
+    )
+}
+
+fn header<'a>(req: &'a ServiceRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|h| h.to_str().ok())
+}
+
+/// A job submission counted against a key, which can be given back.
+struct Charge(web::Data<AppState>, usize);
+
+impl Charge {
+    fn refund(self) {
+        self.0.auth.key(self.1).refund();
+    }
+}
+
+/// Checks a request's credentials and charges job submissions to its key.
+async fn authenticate(req: &mut ServiceRequest) -> Result<Option<Charge>, AuthError> {
+    let data = match req.app_data::<web::Data<AppState>>() {
+        Some(data) if data.auth.enabled() => data.clone(),
+        _ => return Ok(None),
+    };
+    if OPEN_PATHS.contains(&req.path()) {
+        return Ok(None);
+    }
+
+    let bearer = header(req, AUTHORIZATION.as_str())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::to_string);
+
+    let key = match bearer {
+        Some(token) => data.auth.bearer(token.trim())?,
+        None => {
+            let name = header(req, KEY_ID_HEADER).ok_or(AuthError::Missing)?.to_string();
+            let timestamp = header(req, TIMESTAMP_HEADER)
+                .ok_or(AuthError::Missing)?
+                .to_string();
+            let signature = header(req, SIGNATURE_HEADER)
+                .ok_or(AuthError::Missing)?
+                .to_string();
+            let path = req
+                .uri()
+                .path_and_query()
+                .map(|p| p.as_str().to_string())
+                .unwrap_or_default();
+            let method = req.method().clone();
+
+            // the body is read to check it, then put back for the handler
+            let (http_req, payload) = req.parts_mut();
+            let body = web::Bytes::from_request(http_req, payload)
+                .await
+                .map_err(|_| AuthError::BadSignature)?;
+            let (_, mut restored) = actix_http::h1::Payload::create(true);
+            restored.unread_data(body.clone());
+            req.set_payload(Payload::from(restored));
+
+            data.auth
+                .signed(&name, &timestamp, &signature, &method, &path, &body)?
+        }
+    };
+
+    tracing::Span::current().record("client", key.name.as_str());
+
+    // only submitting work counts against the limits, not polling for it
+    if *req.method() != Method::POST {
+        return Ok(None);
+    }
+    key.charge()?;
+    let key = data.auth.index_of(key);
+
+    Ok(Some(Charge(data, key)))
+}
+
+/// An [`AuthError`] turned into a response.
+#[derive(Debug)]
+pub struct AuthFailure(pub AuthError);
+
+impl std::fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl ResponseError for AuthFailure {
+    fn status_code(&self) -> StatusCode {
+        match self.0 {
+            AuthError::RateLimited(_) | AuthError::QuotaExceeded(_) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        match self.0 {
+            AuthError::RateLimited(secs) | AuthError::QuotaExceeded(secs) => {
+                response.append_header(("Retry-After", secs.to_string()));
+            }
+            _ => {
+                response.append_header((WWW_AUTHENTICATE, "Bearer"));
+            }
+        }
+        response.body(self.0.to_string())
+    }
+}
+
+/// Middleware turning away requests without a valid API key, when keys are configured.
+pub struct Authenticate;
+
+impl<S, B> Transform<S, ServiceRequest> for Authenticate
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AuthenticateMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticateMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AuthenticateMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticateMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let charge = authenticate(&mut req).await.map_err(AuthFailure)?;
+            let response = service.call(req).await?;
+
+            // a full or draining queue turned the job away before any work was done
+            if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+                if let Some(charge) = charge {
+                    charge.refund();
+                }
+            }
+
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KeyConfig;
+    use std::time::Duration;
+
+    fn auth(rate_per_minute: Option<u32>, daily_quota: Option<u64>) -> Auth {
+        Auth::new(&AuthConfig {
+            keys: vec![
+                KeyConfig {
+                    name: "bot".to_string(),
+                    secret: "bot secret, long enough".to_string(),
+                    rate_per_minute,
+                    daily_quota,
+                },
+                KeyConfig {
+                    name: "other".to_string(),
+                    secret: "other secret, long enough".to_string(),
+                    rate_per_minute: None,
+                    daily_quota: None,
+                },
+            ],
+            max_clock_skew_secs: 300,
+        })
+    }
+
+    fn sign(secret: &str, timestamp: &str, method: &Method, path: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(canonical(timestamp, method, path, body).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn builds_the_canonical_string() {
+        assert_eq!(
+            canonical("1700000000", &Method::POST, "/jobs?x=1", b"{}"),
+            "1700000000\nPOST\n/jobs?x=1\n\
+             44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        );
+    }
+
+    #[test]
+    fn looks_up_bearer_tokens() {
+        let auth = auth(None, None);
+
+        assert_eq!(auth.bearer("other secret, long enough").unwrap().name, "other");
+        assert!(matches!(
+            auth.bearer("other secret"),
+            Err(AuthError::UnknownKey)
+        ));
+        assert!(matches!(auth.bearer(""), Err(AuthError::UnknownKey)));
+    }
+
+    #[test]
+    fn checks_signatures() {
+        let auth = auth(None, None);
+        let secret = "bot secret, long enough";
+        let now = unix_secs().to_string();
+        let (method, path, body) = (Method::POST, "/jobs", br#"{"endpoint": "/caption"}"#);
+        let signature = sign(secret, &now, &method, path, body);
+
+        let signed = |name, timestamp: &str, signature: &str, body: &[u8]| {
+            auth.signed(name, timestamp, signature, &method, path, body)
+                .map(|key| key.name.clone())
+        };
+
+        assert!(matches!(
+            signed("bot", &now, &signature, b"{}"),
+            Err(AuthError::BadSignature)
+        ));
+        assert!(matches!(
+            signed("other", &now, &signature, body),
+            Err(AuthError::BadSignature)
+        ));
+        assert!(matches!(
+            signed("nobody", &now, &signature, body),
+            Err(AuthError::UnknownKey)
+        ));
+        assert!(matches!(
+            signed("bot", &now, "not hex", body),
+            Err(AuthError::BadSignature)
+        ));
+        assert_eq!(signed("bot", &now, &signature, body).unwrap(), "bot");
+        assert!(matches!(
+            signed("bot", &now, &signature, body),
+            Err(AuthError::Replayed)
+        ));
+
+        let stale = (unix_secs() - 301).to_string();
+        let signature = sign(secret, &stale, &method, path, body);
+        assert!(matches!(
+            signed("bot", &stale, &signature, body),
+            Err(AuthError::StaleTimestamp)
+        ));
+        assert!(matches!(
+            signed("bot", "yesterday", &signature, body),
+            Err(AuthError::StaleTimestamp)
+        ));
+    }
+
+    #[test]
+    fn refills_the_rate_limit() {
+        let auth = auth(Some(2), None);
+        let key = auth.bearer("bot secret, long enough").unwrap();
+        let (now, start) = (unix_secs(), Instant::now());
+
+        assert!(key.charge_at(now, start).is_ok());
+        assert!(key.charge_at(now, start).is_ok());
+        assert!(matches!(
+            key.charge_at(now, start),
+            Err(AuthError::RateLimited(30))
+        ));
+        assert!(matches!(
+            key.charge_at(now, start + Duration::from_secs(15)),
+            Err(AuthError::RateLimited(15))
+        ));
+        assert!(key.charge_at(now, start + Duration::from_secs(30)).is_ok());
+
+        key.refund();
+        assert!(key.charge_at(now, start + Duration::from_secs(30)).is_ok());
+    }
+
+    #[test]
+    fn resets_the_quota_daily() {
+        let auth = auth(None, Some(2));
+        let key = auth.bearer("bot secret, long enough").unwrap();
+        let (now, start) = (unix_secs(), Instant::now());
+
+        assert!(key.charge_at(now, start).is_ok());
+        assert!(key.charge_at(now, start).is_ok());
+        match key.charge_at(now, start) {
+            Err(AuthError::QuotaExceeded(secs)) => assert_eq!(secs, DAY_SECS - now % DAY_SECS),
+            other => panic!("quota should be used up, got {:?}", other),
+        }
+
+        key.refund();
+        assert!(key.charge_at(now, start).is_ok());
+        assert!(key.charge_at(now, start).is_err());
+
+        let tomorrow = now + DAY_SECS;
+        assert!(key.charge_at(tomorrow, start).is_ok());
+        assert!(key.charge_at(tomorrow, start).is_ok());
+        assert!(key.charge_at(tomorrow, start).is_err());
+    }
+}
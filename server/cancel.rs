@@ -0,0 +1,282 @@
+use std::any::Any;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::Extensions;
+use actix_web::{rt, HttpRequest};
+use shared::ImageError;
+use tokio::sync::Notify;
+
+use crate::config::Limits;
+
+/// How often a waiting client's connection is checked.
+static PEER_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+/// Header a client can set to the milliseconds it is willing to wait for its job.
+pub static DEADLINE_HEADER: &str = "X-Deadline-Ms";
+
+/// Why a job was told to stop, stored in [`Cancelled::reason`].
+static RUNNING: u8 = 0;
+static CANCELLED: u8 = 1;
+static SHUTTING_DOWN: u8 = 2;
+
+#[derive(Default)]
+struct Cancelled {
+    reason: AtomicU8,
+    notify: Notify,
+}
+
+/// Tells a job to stop early, because its deadline passed, nobody wants its result anymore or
+/// the server is shutting down. Checked between frames, so work already handed to a frame still
+/// finishes.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<Cancelled>,
+    deadline: Option<Instant>,
+}
+
+impl CancelToken {
+    pub fn with_deadline(deadline: Instant) -> Self {
+        Self {
+            cancelled: Arc::default(),
+            deadline: Some(deadline),
+        }
+    }
+
+    /// Reads a request's deadline from [`DEADLINE_HEADER`], within `limits`.
+    pub fn from_request(req: &HttpRequest, limits: &Limits) -> Result<Self, ImageError> {
+        let budget = match req.headers().get(DEADLINE_HEADER) {
+            Some(ms) => ms
+                .to_str()
+                .ok()
+                .and_then(|ms| ms.parse::<u64>().ok())
+                .map(Duration::from_millis)
+                .ok_or_else(|| ImageError::BadRequest(format!("Bad {} header", DEADLINE_HEADER)))?,
+            None => limits.default_deadline(),
+        };
+
+        Ok(Self::with_deadline(Instant::now() + budget.min(limits.max_deadline())))
+    }
+
+    fn stop(&self, reason: u8) {
+        // the first reason given sticks
+        let _ = self.cancelled.reason.compare_exchange(
+            RUNNING,
+            reason,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        self.cancelled.notify.notify_waiters();
+    }
+
+    pub fn cancel(&self) {
+        self.stop(CANCELLED);
+    }
+
+    /// Stops the job because the server is going away, so its client knows to retry elsewhere.
+    pub fn shut_down(&self) {
+        self.stop(SHUTTING_DOWN);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.reason.load(Ordering::Relaxed) != RUNNING
+    }
+
+    fn reason(&self) -> ImageError {
+        match self.cancelled.reason.load(Ordering::Relaxed) {
+            r if r == SHUTTING_DOWN => ImageError::ShuttingDown,
+            _ => ImageError::Cancelled,
+        }
+    }
+
+    /// Fails if the job should stop.
+    pub fn check(&self) -> Result<(), ImageError> {
+        if self.is_cancelled() {
+            return Err(self.reason());
+        }
+
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(ImageError::Timeout),
+            _ => Ok(()),
+        }
+    }
+
+    async fn wait_cancelled(&self) {
+        loop {
+            let notified = self.cancelled.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Resolves once the job should stop, with the reason.
+    pub async fn stopped(&self) -> ImageError {
+        match self.deadline {
+            Some(deadline) => {
+                match tokio::time::timeout_at(deadline.into(), self.wait_cancelled()).await {
+                    Ok(()) => self.reason(),
+                    Err(_) => ImageError::Timeout,
+                }
+            }
+            None => {
+                self.wait_cancelled().await;
+                self.reason()
+            }
+        }
+    }
+}
+
+/// The socket a request came in on, so its job can notice the client hanging up. A duplicate
+/// of the server's descriptor, so it can't be closed and reused for another connection while a
+/// job still watches it.
+#[cfg(unix)]
+#[derive(Clone)]
+struct Peer(Arc<std::os::unix::io::OwnedFd>);
+
+/// Remembers each connection's socket. Passed to `HttpServer::on_connect`.
+pub fn on_connect(connection: &dyn Any, extensions: &mut Extensions) {
+    #[cfg(unix)]
+    if let Some(socket) = connection.downcast_ref::<rt::net::TcpStream>() {
+        use std::os::unix::io::{AsRawFd, BorrowedFd};
+
+        // the socket is open for as long as `connection' is borrowed
+        let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+        match fd.try_clone_to_owned() {
+            Ok(fd) => {
+                extensions.insert(Peer(Arc::new(fd)));
+            }
+            Err(e) => tracing::warn!("cannot watch connection: {}", e),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn peer_closed(peer: &Peer) -> bool {
+    use std::io::ErrorKind;
+    use std::mem::ManuallyDrop;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    // borrow the socket; `peer' keeps its descriptor open
+    let fd = peer.0.as_raw_fd();
+    let socket = ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_fd(fd) });
+
+    match socket.peek(&mut [0u8; 1]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != ErrorKind::WouldBlock,
+    }
+}
+
+/// Cancels `cancel` if the client that sent `req` disconnects before it is.
+///
+/// actix keeps running a handler after its client is gone, so the connection is polled instead.
+pub fn watch_peer(req: &HttpRequest, cancel: CancelToken) {
+    #[cfg(unix)]
+    if let Some(peer) = req.conn_data::<Peer>() {
+        let peer = peer.clone();
+
+        rt::spawn(async move {
+            while !cancel.is_cancelled() {
+                rt::time::sleep(PEER_CHECK_INTERVAL).await;
+
+                if !cancel.is_cancelled() && peer_closed(&peer) {
+                    cancel.cancel();
+                }
+            }
+        });
+    }
+
+    #[cfg(not(unix))]
+    let _ = (req, cancel);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use actix_web::test::TestRequest;
+
+    fn limits() -> Limits {
+        let mut limits = Config::default().limits;
+        limits.default_deadline_secs = 30;
+        limits.max_deadline_secs = 60;
+        limits
+    }
+
+    fn budget(token: &CancelToken) -> Duration {
+        token.deadline.unwrap() - Instant::now()
+    }
+
+    #[test]
+    fn keeps_the_first_reason() {
+        let token = CancelToken::default();
+        assert!(token.check().is_ok());
+
+        token.shut_down();
+        token.cancel();
+        assert!(matches!(token.check(), Err(ImageError::ShuttingDown)));
+
+        let token = CancelToken::default();
+        token.clone().cancel();
+        token.shut_down();
+        assert!(matches!(token.check(), Err(ImageError::Cancelled)));
+    }
+
+    #[test]
+    fn checks_the_deadline() {
+        let token = CancelToken::with_deadline(Instant::now() + Duration::from_secs(60));
+        assert!(token.check().is_ok());
+
+        let token = CancelToken::with_deadline(Instant::now());
+        assert!(matches!(token.check(), Err(ImageError::Timeout)));
+
+        // being cancelled says more than running late
+        token.cancel();
+        assert!(matches!(token.check(), Err(ImageError::Cancelled)));
+    }
+
+    #[actix_rt::test]
+    async fn stops_waiters() {
+        let token = CancelToken::default();
+        let cancel = token.clone();
+        rt::spawn(async move { cancel.shut_down() });
+        assert!(matches!(token.stopped().await, ImageError::ShuttingDown));
+
+        let token = CancelToken::with_deadline(Instant::now() + Duration::from_millis(10));
+        assert!(matches!(token.stopped().await, ImageError::Timeout));
+    }
+
+    #[test]
+    fn reads_the_deadline_header() {
+        let limits = limits();
+
+        let req = TestRequest::default().to_http_request();
+        let token = CancelToken::from_request(&req, &limits).unwrap();
+        assert!(budget(&token) > Duration::from_secs(29));
+        assert!(budget(&token) <= Duration::from_secs(30));
+
+        let req = TestRequest::default()
+            .insert_header((DEADLINE_HEADER, "1500"))
+            .to_http_request();
+        let token = CancelToken::from_request(&req, &limits).unwrap();
+        assert!(budget(&token) > Duration::from_millis(1000));
+        assert!(budget(&token) <= Duration::from_millis(1500));
+
+        let req = TestRequest::default()
+            .insert_header((DEADLINE_HEADER, "3600000"))
+            .to_http_request();
+        let token = CancelToken::from_request(&req, &limits).unwrap();
+        assert!(budget(&token) > Duration::from_secs(59));
+        assert!(budget(&token) <= Duration::from_secs(60));
+
+        for bad in ["soon", "-5", "1.5"] {
+            let req = TestRequest::default()
+                .insert_header((DEADLINE_HEADER, bad))
+                .to_http_request();
+            let token = CancelToken::from_request(&req, &limits);
+            assert!(matches!(token, Err(ImageError::BadRequest(_))), "{}", bad);
+        }
+    }
+}
@@ -3,30 +3,55 @@ use image::{DynamicImage, Rgba};
 use imageproc::drawing::{draw_filled_rect_mut, Canvas};
 use imageproc::rect::Rect;
 use rusttype::{Scale};
+use shared::ImageError;
 
 use crate::font::{DrawableFont, HorizontalGravity, VerticalGravity};
 use crate::images::GenericImageRequest;
-use crate::{images, AppState};
-
-static CAPTION_FONT: &[u8] = include_bytes!("pack/caption.otf");
-
-#[post("/caption")]
-pub async fn caption(
-    request: web::Json<GenericImageRequest>,
-    data: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    let image = images::get_bytes(&data.client, &request.target_url).await;
+use crate::queue::Job;
+use crate::{crop, images, AppState};
+
+/// Colors of the caption bar.
+#[derive(serde::Deserialize, schemars::JsonSchema, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptionStyle {
+    /// Black text on white, like the classic meme.
+    #[default]
+    Light,
+    /// White text on black.
+    Dark,
+}
 
-    if let Err(e) = image {
-        return Ok(HttpResponse::BadRequest().body(e.to_string()));
+impl CaptionStyle {
+    /// Text and bar colors.
+    fn colors(self) -> (Rgba<u8>, Rgba<u8>) {
+        let black = Rgba([0u8, 0u8, 0u8, 255u8]);
+        let white = Rgba([255u8, 255u8, 255u8, 255u8]);
+        match self {
+            CaptionStyle::Light => (black, white),
+            CaptionStyle::Dark => (white, black),
+        }
     }
+}
 
-    let image = image.unwrap();
-    let font = DrawableFont::from(CAPTION_FONT);
+/// Captions an image, smart cropping it first if the request asks for an aspect ratio.
+pub async fn run(
+    data: &AppState,
+    job: &Job,
+    request: &GenericImageRequest,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    let image = images::source_bytes(data, &request.target_url).await?;
+    let (frames, is_gif) = images::decode(image).await?;
+    let frames = match &request.aspect {
+        Some(aspect) => crop::smart_crop(job, frames, crop::parse_aspect(aspect)?).await?,
+        None => frames,
+    };
+
+    let font = DrawableFont::from(data.templates.caption_font);
 
     let text = request.text.clone();
+    let (text_color, bar_color) = request.style.colors();
 
-    let result = images::process(image, move |img| {
+    images::process_frames(job, frames, is_gif, request.gif.clone(), move |img| {
         let img = img.into_rgba8();
 
         let scale = (img.width() / 13) as f32;
@@ -39,7 +64,7 @@ pub async fn caption(
 
         font.text(text.clone())
             .scale(scale)
-            .color(Rgba([0u8, 0u8, 0u8, 255u8]))
+            .color(text_color)
             .extents(img.width(), img.height())
             .gravity(
                 HorizontalGravity::CenterGravity,
@@ -53,7 +78,7 @@ pub async fn caption(
         let rect = Rect::at(0, 0).of_size(img.width(), offset as u32);
         font.extents(img.width(), offset as u32);
 
-        draw_filled_rect_mut(&mut new_img, rect, Rgba([255u8, 255u8, 255u8, 255u8]));
+        draw_filled_rect_mut(&mut new_img, rect, bar_color);
         font.flush(&mut new_img, 0., 0.)?;
 
         for (x, y, pixel) in img.enumerate_pixels() {
@@ -62,20 +87,14 @@ pub async fn caption(
 
         Ok(DynamicImage::ImageRgba8(new_img))
     })
-    .await;
-
-    if let Err(e) = result {
-        return Ok(
-            HttpResponse::BadRequest().body(format!("Failed to modify image. {}", e.to_string()))
-        );
-    }
-
-    let (result, is_gif) = result.unwrap();
+    .await
+}
 
-    Ok(HttpResponse::Ok()
-        .append_header(http::header::ContentType(match is_gif {
-            false => mime::IMAGE_PNG,
-            true => mime::IMAGE_GIF,
-        }))
-        .body(result))
+#[post("/caption")]
+pub async fn caption(
+    request: web::Json<GenericImageRequest>,
+    data: web::Data<AppState>,
+    job: Job,
+) -> Result<HttpResponse> {
+    images::respond(run(&data, &job, &request).await)
 }
@@ -0,0 +1,403 @@
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use shared::ConfigError;
+
+/// Config file read when `IMG_SERVER_CONFIG` doesn't name one. Optional.
+static DEFAULT_CONFIG_PATH: &str = "img_server.toml";
+/// Prefix of the environment variables overriding the config file, e.g. `IMG_SERVER_BIND`.
+static ENV_PREFIX: &str = "IMG_SERVER_";
+
+/// Server settings, read from a TOML file and then overridden from the environment.
+///
+/// Every setting has a default, so the file is only needed to change them.
+#[derive(serde::Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address to listen on.
+    pub bind: String,
+    /// HTTP worker threads. Defaults to one per core.
+    pub workers: Option<usize>,
+    /// Sent when fetching source images.
+    pub user_agent: String,
+    /// Where images are written while being encoded.
+    pub temp_dir: PathBuf,
+    /// Directory of fonts and templates replacing the built in ones. Files that aren't there
+    /// fall back to the built in version.
+    pub templates_dir: Option<PathBuf>,
+    /// Hosts source images may be fetched from, including their subdomains. Empty allows any.
+    pub allowed_hosts: Vec<String>,
+    /// Base URL this replica can be reached at directly, for job URLs.
+    pub advertise_url: Option<String>,
+    /// How long running jobs get to finish once the server is told to stop. Stopping takes up to
+    /// 7s more, so keep it that far below k8s' `terminationGracePeriodSeconds`.
+    pub shutdown_grace_secs: u64,
+    pub limits: Limits,
+    pub auth: AuthConfig,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Jobs processed at once. Defaults to one per core.
+    pub max_running: Option<usize>,
+    /// Jobs allowed to wait for a slot before new ones are turned away.
+    pub max_queued: usize,
+    /// Frames of a single job processed in parallel.
+    pub frame_concurrency: usize,
+    /// Deadline for requests that don't ask for one.
+    pub default_deadline_secs: u64,
+    /// Longest deadline a request may ask for.
+    pub max_deadline_secs: u64,
+    /// How long a finished job's result is kept around to be collected.
+    pub result_ttl_secs: u64,
+    /// Largest source image that will be downloaded.
+    pub max_source_bytes: usize,
+}
+
+/// API keys clients must present. With none configured, anyone can use the server.
+#[derive(serde::Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub keys: Vec<KeyConfig>,
+    /// How far a signed request's timestamp may be from the server's clock.
+    pub max_clock_skew_secs: u64,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyConfig {
+    /// Shown in logs, and used to pick the key for signed requests.
+    pub name: String,
+    pub secret: String,
+    /// Jobs the key may submit per minute. Unlimited if unset.
+    #[serde(default)]
+    pub rate_per_minute: Option<u32>,
+    /// Jobs the key may submit per UTC day. Unlimited if unset.
+    #[serde(default)]
+    pub daily_quota: Option<u64>,
+}
+
+impl std::fmt::Debug for KeyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyConfig")
+            .field("name", &self.name)
+            .field("rate_per_minute", &self.rate_per_minute)
+            .field("daily_quota", &self.daily_quota)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:8080".to_string(),
+            workers: None,
+            user_agent: "imgBot-server".to_string(),
+            temp_dir: env::temp_dir(),
+            templates_dir: None,
+            allowed_hosts: Vec::new(),
+            advertise_url: None,
+            shutdown_grace_secs: 20,
+            limits: Limits::default(),
+            auth: AuthConfig::default(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_running: None,
+            max_queued: 16,
+            frame_concurrency: 4,
+            default_deadline_secs: 120,
+            max_deadline_secs: 600,
+            result_ttl_secs: 10 * 60,
+            max_source_bytes: 32 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            max_clock_skew_secs: 5 * 60,
+        }
+    }
+}
+
+/// Reads `IMG_SERVER_{name}`, if set.
+fn env_var<T: FromStr>(name: &'static str) -> Result<Option<T>, ConfigError> {
+    match env::var(format!("{}{}", ENV_PREFIX, name)) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::BadEnv(format!("{}{}", ENV_PREFIX, name), value)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn set<T: FromStr>(field: &mut T, name: &'static str) -> Result<(), ConfigError> {
+    if let Some(value) = env_var(name)? {
+        *field = value;
+    }
+    Ok(())
+}
+
+fn set_opt<T: FromStr>(field: &mut Option<T>, name: &'static str) -> Result<(), ConfigError> {
+    if let Some(value) = env_var(name)? {
+        *field = Some(value);
+    }
+    Ok(())
+}
+
+impl Config {
+    /// Loads the config from `IMG_SERVER_CONFIG`, or `img_server.toml` if it exists, applies
+    /// environment overrides and checks the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match env::var(format!("{}CONFIG", ENV_PREFIX)) {
+            Ok(path) => Self::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            Err(_) => Self::default(),
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| ConfigError::Unreadable(path.display().to_string(), e.to_string()))?;
+
+        toml::from_str(&text)
+            .map_err(|e| ConfigError::Malformed(path.display().to_string(), e.to_string()))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        set(&mut self.bind, "BIND")?;
+        set_opt(&mut self.workers, "WORKERS")?;
+        set(&mut self.user_agent, "USER_AGENT")?;
+        set(&mut self.temp_dir, "TEMP_DIR")?;
+        set_opt(&mut self.templates_dir, "TEMPLATES_DIR")?;
+        set_opt(&mut self.advertise_url, "ADVERTISE_URL")?;
+        set(&mut self.shutdown_grace_secs, "SHUTDOWN_GRACE_SECS")?;
+        if let Some(hosts) = env_var::<String>("ALLOWED_HOSTS")? {
+            self.allowed_hosts = hosts
+                .split(',')
+                .map(|h| h.trim().to_string())
+                .filter(|h| !h.is_empty())
+                .collect();
+        }
+
+        let limits = &mut self.limits;
+        set_opt(&mut limits.max_running, "MAX_RUNNING")?;
+        set(&mut limits.max_queued, "MAX_QUEUED")?;
+        set(&mut limits.frame_concurrency, "FRAME_CONCURRENCY")?;
+        set(&mut limits.default_deadline_secs, "DEFAULT_DEADLINE_SECS")?;
+        set(&mut limits.max_deadline_secs, "MAX_DEADLINE_SECS")?;
+        set(&mut limits.result_ttl_secs, "RESULT_TTL_SECS")?;
+        set(&mut limits.max_source_bytes, "MAX_SOURCE_BYTES")?;
+
+        // `name:secret,name:secret`, so keys can come from a k8s secret
+        if let Some(keys) = env_var::<String>("API_KEYS")? {
+            for key in keys.split(',').map(str::trim).filter(|k| !k.is_empty()) {
+                let (name, secret) = key.split_once(':').ok_or_else(|| {
+                    ConfigError::BadEnv(format!("{}API_KEYS", ENV_PREFIX), "<hidden>".to_string())
+                })?;
+                self.auth.keys.push(KeyConfig {
+                    name: name.to_string(),
+                    secret: secret.to_string(),
+                    rate_per_minute: None,
+                    daily_quota: None,
+                });
+            }
+        }
+
+        if self.advertise_url.is_none() {
+            // set through the downward API in k8s
+            if let (Ok(ip), Ok(bind)) = (env::var("POD_IP"), self.bind.parse::<SocketAddr>()) {
+                self.advertise_url = Some(format!("http://{}:{}", ip, bind.port()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks every setting, reporting all the problems at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        let limits = &self.limits;
+
+        if self.bind.parse::<SocketAddr>().is_err() {
+            problems.push(format!("bind `{}` is not an address like 0.0.0.0:8080", self.bind));
+        }
+        if self.workers == Some(0) {
+            problems.push("workers must be at least 1".to_string());
+        }
+        if limits.max_running == Some(0) {
+            problems.push("limits.max_running must be at least 1".to_string());
+        }
+        if limits.frame_concurrency == 0 {
+            problems.push("limits.frame_concurrency must be at least 1".to_string());
+        }
+        if limits.default_deadline_secs == 0 || limits.max_deadline_secs == 0 {
+            problems.push("limits deadlines must be at least 1s".to_string());
+        }
+        if limits.default_deadline_secs > limits.max_deadline_secs {
+            problems.push(
+                "limits.default_deadline_secs is longer than limits.max_deadline_secs".to_string(),
+            );
+        }
+        if limits.max_source_bytes == 0 {
+            problems.push("limits.max_source_bytes must be more than 0".to_string());
+        }
+
+        if !self.temp_dir.is_dir() {
+            problems.push(format!(
+                "temp_dir `{}` is not a directory",
+                self.temp_dir.display()
+            ));
+        }
+        if let Some(dir) = &self.templates_dir {
+            if !dir.is_dir() {
+                problems.push(format!("templates_dir `{}` is not a directory", dir.display()));
+            }
+        }
+
+        for host in &self.allowed_hosts {
+            if host.is_empty() || host.contains(|c: char| c == '/' || c == ':' || c.is_whitespace())
+            {
+                problems.push(format!(
+                    "allowed host `{}` should be a bare host name, like cdn.discordapp.com",
+                    host
+                ));
+            }
+        }
+
+        let mut names = std::collections::HashSet::new();
+        for key in &self.auth.keys {
+            if key.name.is_empty() || !names.insert(key.name.as_str()) {
+                problems.push(format!("auth key name `{}` is empty or used twice", key.name));
+            }
+            if key.secret.len() < 16 {
+                problems.push(format!(
+                    "auth key `{}` has a secret shorter than 16 characters",
+                    key.name
+                ));
+            }
+            if key.rate_per_minute == Some(0) {
+                problems.push(format!("auth key `{}` has a rate_per_minute of 0", key.name));
+            }
+        }
+
+        if let Some(url) = &self.advertise_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                problems.push(format!("advertise_url `{}` is not an http(s) URL", url));
+            }
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError::Invalid(problems)),
+        }
+    }
+
+    pub fn max_running(&self) -> usize {
+        self.limits.max_running.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(2)
+        })
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+
+    /// Whether source images may be fetched from `host`.
+    pub fn host_allowed(&self, host: &str) -> bool {
+        host_allowed(&self.allowed_hosts, host)
+    }
+}
+
+/// Whether `host` is one of `allowed`, or a subdomain of one. An empty list allows any host.
+pub fn host_allowed(allowed: &[String], host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    allowed.is_empty()
+        || allowed.iter().any(|allowed| {
+            let allowed = allowed.to_ascii_lowercase();
+            host == allowed || host.ends_with(&format!(".{}", allowed))
+        })
+}
+
+impl Limits {
+    pub fn default_deadline(&self) -> Duration {
+        Duration::from_secs(self.default_deadline_secs)
+    }
+
+    pub fn max_deadline(&self) -> Duration {
+        Duration::from_secs(self.max_deadline_secs)
+    }
+
+    pub fn result_ttl(&self) -> Duration {
+        Duration::from_secs(self.result_ttl_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_hosts_and_subdomains() {
+        let allowed = vec!["discordapp.com".to_string(), "Tenor.com".to_string()];
+
+        assert!(host_allowed(&allowed, "discordapp.com"));
+        assert!(host_allowed(&allowed, "cdn.discordapp.com"));
+        assert!(host_allowed(&allowed, "media.tenor.com"));
+        assert!(!host_allowed(&allowed, "notdiscordapp.com"));
+        assert!(!host_allowed(&allowed, "discordapp.com.evil.net"));
+        assert!(host_allowed(&[], "anything.example"));
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let config: Config = toml::from_str(
+            r#"
+            bind = "nowhere"
+            allowed_hosts = ["https://cdn.discordapp.com"]
+
+            [limits]
+            frame_concurrency = 0
+            default_deadline_secs = 900
+            "#,
+        )
+        .unwrap();
+
+        match config.validate() {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(problems.len(), 4),
+            _ => panic!("config should be invalid"),
+        }
+        assert!(Config::default().validate().is_ok());
+        assert!(toml::from_str::<Config>("port = 80").is_err());
+    }
+
+    #[test]
+    fn stops_within_the_pod_grace_period() {
+        use crate::shutdown::{COLLECT_WINDOW, STOP_TIMEOUT};
+
+        let stopping = Config::default().shutdown_grace() + COLLECT_WINDOW + STOP_TIMEOUT;
+        assert!(stopping < Duration::from_secs(30));
+    }
+}
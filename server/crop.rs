@@ -0,0 +1,293 @@
+use actix_web::*;
+use image::imageops::FilterType;
+use image::{imageops, DynamicImage, Frame, GrayImage};
+use imageproc::gradients::sobel_gradients;
+use shared::ImageError;
+
+use crate::images::CropImageRequest;
+use crate::queue::Job;
+use crate::{images, AppState};
+
+/// Saliency is computed on a thumbnail this size, which is plenty to find a region.
+static SALIENCY_SIDE: u32 = 160;
+/// At most this many frames of an animation are looked at, spread evenly.
+static SALIENCY_FRAMES: usize = 8;
+/// Side of the blocks local entropy is measured over, in thumbnail pixels.
+static ENTROPY_BLOCK: u32 = 8;
+
+/// Parses an aspect ratio like `16:9`, `4/3`, `1.5` or `square` into width over height.
+pub fn parse_aspect(aspect: &str) -> Result<f32, ImageError> {
+    let bad = || ImageError::BadRequest(format!("Bad aspect ratio {}", aspect));
+    let aspect = aspect.trim();
+
+    let ratio = match aspect {
+        "square" => 1.,
+        _ => match aspect.split_once(&[':', '/', 'x'][..]) {
+            Some((w, h)) => {
+                let w = w.trim().parse::<f32>().map_err(|_| bad())?;
+                let h = h.trim().parse::<f32>().map_err(|_| bad())?;
+                w / h
+            }
+            None => aspect.parse::<f32>().map_err(|_| bad())?,
+        },
+    };
+
+    match ratio.is_finite() && ratio > 0.01 && ratio < 100. {
+        true => Ok(ratio),
+        false => Err(bad()),
+    }
+}
+
+/// Shannon entropy of the luma histogram in every block, spread over the block's pixels.
+fn entropy_map(gray: &GrayImage) -> Vec<f32> {
+    let (w, h) = gray.dimensions();
+    let mut out = vec![0f32; (w * h) as usize];
+
+    for by in (0..h).step_by(ENTROPY_BLOCK as usize) {
+        for bx in (0..w).step_by(ENTROPY_BLOCK as usize) {
+            let bw = ENTROPY_BLOCK.min(w - bx);
+            let bh = ENTROPY_BLOCK.min(h - by);
+
+            let mut histogram = [0u32; 16];
+            for y in by..by + bh {
+                for x in bx..bx + bw {
+                    histogram[(gray.get_pixel(x, y).0[0] >> 4) as usize] += 1;
+                }
+            }
+
+            let total = (bw * bh) as f32;
+            let entropy: f32 = histogram
+                .iter()
+                .filter(|n| **n > 0)
+                .map(|n| {
+                    let p = *n as f32 / total;
+                    -p * p.log2()
+                })
+                .sum();
+
+            for y in by..by + bh {
+                for x in bx..bx + bw {
+                    out[(y * w + x) as usize] = entropy;
+                }
+            }
+        }
+    }
+
+    out
+}
+
+fn normalize(values: &mut [f32]) {
+    let max = values.iter().cloned().fold(0f32, f32::max);
+    if max > 0. {
+        values.iter_mut().for_each(|v| *v /= max);
+    }
+}
+
+/// The frames saliency is measured on, spread evenly. Sampling a sample gives it back whole.
+fn sampled(frames: &[Frame]) -> impl Iterator<Item = &Frame> {
+    let step = (frames.len() / SALIENCY_FRAMES).max(1);
+    frames.iter().step_by(step)
+}
+
+/// Per-pixel "interestingness" of a thumbnail of the frames: edge strength plus local entropy,
+/// averaged over a sample of frames.
+fn saliency(frames: &[Frame], width: u32, height: u32) -> Vec<f32> {
+    let mut total = vec![0f32; (width * height) as usize];
+
+    for frame in sampled(frames) {
+        let gray = imageops::resize(
+            &DynamicImage::ImageRgba8(frame.buffer().clone()).into_luma8(),
+            width,
+            height,
+            FilterType::Triangle,
+        );
+
+        let mut edges: Vec<f32> = sobel_gradients(&gray)
+            .pixels()
+            .map(|p| p.0[0] as f32)
+            .collect();
+        let mut entropy = entropy_map(&gray);
+        normalize(&mut edges);
+        normalize(&mut entropy);
+
+        for (i, t) in total.iter_mut().enumerate() {
+            *t += edges[i] + entropy[i];
+        }
+    }
+
+    total
+}
+
+/// Offset of the window of `size` with the highest sum in `line`.
+fn best_window(line: &[f32], size: usize) -> usize {
+    let mut sum: f32 = line[..size].iter().sum();
+    let (mut best, mut best_sum) = (0, sum);
+
+    for start in 1..=line.len() - size {
+        sum += line[start + size - 1] - line[start - 1];
+        if sum > best_sum {
+            best = start;
+            best_sum = sum;
+        }
+    }
+
+    best
+}
+
+/// Finds the largest `aspect` region of the frames with the most detail, as
+/// `(left, top, width, height)` in full resolution pixels.
+pub fn find_crop(frames: &[Frame], aspect: f32) -> Option<(u32, u32, u32, u32)> {
+    let (width, height) = frames.first()?.buffer().dimensions();
+
+    let (crop_w, crop_h) = match (width as f32 / height as f32) > aspect {
+        true => (((height as f32 * aspect) as u32).clamp(1, width), height),
+        false => (width, ((width as f32 / aspect) as u32).clamp(1, height)),
+    };
+
+    if (crop_w, crop_h) == (width, height) {
+        return Some((0, 0, width, height));
+    }
+
+    let scale = (SALIENCY_SIDE as f32 / width.max(height) as f32).min(1.);
+    let (small_w, small_h) = (
+        ((width as f32 * scale) as u32).max(1),
+        ((height as f32 * scale) as u32).max(1),
+    );
+    let map = saliency(frames, small_w, small_h);
+
+    if crop_w < width {
+        // slide horizontally
+        let columns: Vec<f32> = (0..small_w)
+            .map(|x| (0..small_h).map(|y| map[(y * small_w + x) as usize]).sum())
+            .collect();
+        let size = ((crop_w as f32 * scale) as usize).clamp(1, small_w as usize);
+        let left = (best_window(&columns, size) as f32 / scale) as u32;
+
+        Some((left.min(width - crop_w), 0, crop_w, crop_h))
+    } else {
+        // slide vertically
+        let rows: Vec<f32> = (0..small_h)
+            .map(|y| (0..small_w).map(|x| map[(y * small_w + x) as usize]).sum())
+            .collect();
+        let size = ((crop_h as f32 * scale) as usize).clamp(1, small_h as usize);
+        let top = (best_window(&rows, size) as f32 / scale) as u32;
+
+        Some((0, top.min(height - crop_h), crop_w, crop_h))
+    }
+}
+
+/// Crops every frame to the same content-aware region.
+pub async fn smart_crop(
+    job: &Job,
+    frames: Vec<Frame>,
+    aspect: f32,
+) -> Result<Vec<Frame>, ImageError> {
+    // only the sampled frames are needed to find the region, so don't copy the rest
+    let sample: Vec<Frame> = sampled(&frames).cloned().collect();
+    let (left, top, width, height) = web::block(move || find_crop(&sample, aspect))
+        .await
+        .map_err(|e| ImageError::ProcessingFailure(e.to_string()))?
+        .ok_or_else(|| ImageError::BadImage("No frames".to_string()))?;
+
+    images::transform(job, frames, move |img| {
+        Ok(img.crop_imm(left, top, width, height))
+    })
+    .await
+}
+
+pub async fn run(
+    data: &AppState,
+    job: &Job,
+    request: &CropImageRequest,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    let aspect = parse_aspect(&request.aspect)?;
+
+    let bytes = images::source_bytes(data, &request.target_url).await?;
+    let (frames, is_gif) = images::decode(bytes).await?;
+    let frames = smart_crop(job, frames, aspect).await?;
+
+    images::encode(job, frames, is_gif, request.gif.clone()).await
+}
+
+#[post("/crop")]
+pub async fn crop(
+    request: web::Json<CropImageRequest>,
+    data: web::Data<AppState>,
+    job: Job,
+) -> Result<HttpResponse> {
+    images::respond(run(&data, &job, &request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{best_window, entropy_map, find_crop, parse_aspect};
+    use image::{Frame, GrayImage, Luma, Rgba, RgbaImage};
+
+    /// A black image with a fine checkerboard, the only detail in it, filling `region`.
+    fn busy(width: u32, height: u32, region: (u32, u32, u32, u32)) -> Vec<Frame> {
+        let (left, top, right, bottom) = region;
+        let image = RgbaImage::from_fn(width, height, |x, y| {
+            let inside = (left..right).contains(&x) && (top..bottom).contains(&y);
+            match inside && (x / 2 + y / 2) % 2 == 0 {
+                true => Rgba([255, 255, 255, 255]),
+                false => Rgba([0, 0, 0, 255]),
+            }
+        });
+        vec![Frame::new(image)]
+    }
+
+    #[test]
+    fn parses_aspects() {
+        assert_eq!(parse_aspect("16:9").unwrap(), 16. / 9.);
+        assert_eq!(parse_aspect(" 4 / 3 ").unwrap(), 4. / 3.);
+        assert_eq!(parse_aspect("2x1").unwrap(), 2.);
+        assert_eq!(parse_aspect("1").unwrap(), 1.);
+        assert_eq!(parse_aspect("1.5").unwrap(), 1.5);
+        assert_eq!(parse_aspect("square").unwrap(), 1.);
+        for bad in ["0:1", "1:0", "-1", "1000", "", "wide", "16:nine"] {
+            assert!(parse_aspect(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn picks_the_best_window() {
+        let line = [0., 1., 5., 5., 1., 0.];
+
+        assert_eq!(best_window(&line, 2), 2);
+        assert_eq!(best_window(&line, 1), 2);
+        assert_eq!(best_window(&line, 6), 0);
+        assert_eq!(best_window(&[1., 1., 1.], 2), 0);
+    }
+
+    #[test]
+    fn measures_entropy_in_blocks() {
+        let flat = GrayImage::from_pixel(16, 16, Luma([128]));
+        assert!(entropy_map(&flat).iter().all(|e| *e == 0.));
+
+        let half = GrayImage::from_fn(16, 8, |x, _| Luma([if x < 8 { 0 } else { (x * 16) as u8 }]));
+        let entropy = entropy_map(&half);
+        assert_eq!(entropy[0], 0.);
+        assert_eq!(entropy[8], 3.);
+        assert_eq!(entropy[8], entropy[16 * 8 - 1]);
+    }
+
+    #[test]
+    fn keeps_images_already_in_shape() {
+        let frames = busy(160, 90, (0, 0, 10, 10));
+
+        assert_eq!(find_crop(&frames, 16. / 9.), Some((0, 0, 160, 90)));
+        assert_eq!(find_crop(&[], 1.), None);
+    }
+
+    #[test]
+    fn crops_to_the_detail() {
+        let (left, top, width, height) =
+            find_crop(&busy(200, 100, (140, 0, 190, 100)), 1.).unwrap();
+        assert_eq!((top, width, height), (0, 100, 100));
+        assert!(left <= 140 && left + width >= 190, "left {}", left);
+
+        let (left, top, width, height) = find_crop(&busy(100, 200, (0, 20, 100, 60)), 1.).unwrap();
+        assert_eq!((left, width, height), (0, 100, 100));
+        assert!(top <= 20 && top + height >= 60, "top {}", top);
+    }
+}
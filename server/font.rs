@@ -125,7 +125,6 @@ impl<'a> DrawableFont<'a> {
         let height = std::mem::take(&mut self.height) as f32;
 
         let (w, _) = images::get_glyph_size(scale, &self.inner, TEST_GLYPH);
-        dbg!(w);
         let w = w as f32;
         let cols = (width / w) - 1.;
 
@@ -0,0 +1,324 @@
+use std::time::Duration;
+
+use actix_web::*;
+use bytes::Bytes;
+use image::imageops::FilterType;
+use image::{imageops, Frame, RgbaImage};
+use shared::{ImageError, RangeBound};
+
+use crate::images::FrameImageRequest;
+use crate::palette::GifOptions;
+use crate::queue::Job;
+use crate::{images, AppState};
+
+/// Largest side of a generated spritesheet. Cells are scaled down to fit.
+static MAX_SHEET_SIDE: u32 = 4096;
+
+#[derive(serde::Serialize, schemars::JsonSchema)]
+pub struct ImageInfo {
+    pub format: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub frame_count: usize,
+    pub duration_ms: u64,
+    pub delays_ms: Vec<u64>,
+    /// How often the animation repeats. `Some(0)` loops forever, `None` plays once.
+    pub loop_count: Option<u16>,
+}
+
+fn delay_ms(frame: &Frame) -> u64 {
+    let (numer, denom) = frame.delay().numer_denom_ms();
+    (numer / denom.max(1)) as u64
+}
+
+/// Bytes in the color table a GIF's packed field describes, if it has one.
+fn color_table_size(packed: u8) -> usize {
+    match packed & 0x80 {
+        0 => 0,
+        _ => 3 << ((packed & 0x07) + 1),
+    }
+}
+
+/// Where the data sub-blocks starting at `at` end, after their terminator.
+fn skip_sub_blocks(bytes: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let size = *bytes.get(at)? as usize;
+        at += 1 + size;
+        if size == 0 {
+            return Some(at);
+        }
+    }
+}
+
+/// Reads the repeat count from a GIF's NETSCAPE2.0 application extension, which the
+/// decoder does not expose. The file's blocks are walked, so the same bytes in a comment or
+/// in image data aren't mistaken for it.
+pub fn gif_loop_count(bytes: &[u8]) -> Option<u16> {
+    if !bytes.starts_with(b"GIF") {
+        return None;
+    }
+
+    // header and logical screen descriptor, then the global color table
+    let mut at = 13 + color_table_size(*bytes.get(10)?);
+    loop {
+        match *bytes.get(at)? {
+            0x21 => {
+                let label = *bytes.get(at + 1)?;
+                if label == 0xff && bytes.get(at + 2..at + 14)? == b"\x0bNETSCAPE2.0" {
+                    if let [3, 1, lo, hi] = *bytes.get(at + 14..at + 18)? {
+                        return Some(u16::from_le_bytes([lo, hi]));
+                    }
+                }
+                at = skip_sub_blocks(bytes, at + 2)?;
+            }
+            0x2c => {
+                let packed = *bytes.get(at + 9)?;
+                // the descriptor and its color table, then the LZW code size before the data
+                at = skip_sub_blocks(bytes, at + 10 + color_table_size(packed) + 1)?;
+            }
+            // the trailer, or not a GIF after all
+            _ => return None,
+        }
+    }
+}
+
+/// Finds the frame shown at `bound`.
+fn find_frame(frames: &[Frame], bound: RangeBound) -> Option<usize> {
+    match bound {
+        RangeBound::Frame(i) if i < frames.len() => Some(i),
+        RangeBound::Frame(_) => None,
+        RangeBound::Time(t) => {
+            let mut elapsed = Duration::ZERO;
+            frames.iter().position(|f| {
+                elapsed += Duration::from_millis(delay_ms(f));
+                elapsed > t
+            })
+        }
+    }
+}
+
+pub fn spritesheet(frames: &[Frame], columns: Option<u32>) -> Option<RgbaImage> {
+    let count = frames.len() as u32;
+    let (width, height) = frames.first()?.buffer().dimensions();
+
+    let columns = columns
+        .unwrap_or_else(|| (count as f32).sqrt().ceil() as u32)
+        .clamp(1, count);
+    let rows = count.div_ceil(columns);
+
+    let scale = (MAX_SHEET_SIDE as f32 / (width * columns) as f32)
+        .min(MAX_SHEET_SIDE as f32 / (height * rows) as f32)
+        .min(1.);
+    let cell_w = ((width as f32 * scale) as u32).max(1);
+    let cell_h = ((height as f32 * scale) as u32).max(1);
+
+    let mut sheet = RgbaImage::new(cell_w * columns, cell_h * rows);
+    for (i, frame) in frames.iter().enumerate() {
+        let i = i as u32;
+        let cell = match scale < 1. {
+            true => imageops::resize(frame.buffer(), cell_w, cell_h, FilterType::Triangle),
+            false => frame.buffer().clone(),
+        };
+        imageops::overlay(&mut sheet, &cell, (i % columns) * cell_w, (i / columns) * cell_h);
+    }
+
+    Some(sheet)
+}
+
+async fn fetch(data: &AppState, url: &String) -> Result<(Bytes, Vec<Frame>, bool), ImageError> {
+    let bytes = images::source_bytes(data, url).await?;
+    let (frames, is_gif) = images::decode(bytes.clone()).await?;
+    Ok((bytes, frames, is_gif))
+}
+
+pub async fn run_frame(
+    data: &AppState,
+    job: &Job,
+    request: &FrameImageRequest,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    let bound = match (&request.time, request.index) {
+        (Some(time), _) => time
+            .parse::<RangeBound>()
+            .map_err(|e| ImageError::BadRequest(e.to_string()))?,
+        (None, Some(index)) => RangeBound::Frame(index),
+        (None, None) => RangeBound::Frame(0),
+    };
+
+    let (_, frames, _) = fetch(data, &request.target_url).await?;
+    let index = find_frame(&frames, bound).ok_or_else(|| {
+        ImageError::BadRequest(format!("No such frame, image has {} frames", frames.len()))
+    })?;
+    let frame = frames.into_iter().nth(index).unwrap();
+
+    images::encode(job, vec![frame], false, GifOptions::default()).await
+}
+
+pub async fn run_spritesheet(
+    data: &AppState,
+    job: &Job,
+    request: &FrameImageRequest,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    let (_, frames, _) = fetch(data, &request.target_url).await?;
+    let columns = request.columns;
+    let sheet = web::block(move || spritesheet(&frames, columns))
+        .await
+        .map_err(|e| ImageError::ProcessingFailure(e.to_string()))?
+        .ok_or_else(|| ImageError::BadImage("No frames".to_string()))?;
+
+    images::encode(job, vec![Frame::new(sheet)], false, GifOptions::default()).await
+}
+
+#[post("/frame")]
+pub async fn frame_png(
+    request: web::Json<FrameImageRequest>,
+    data: web::Data<AppState>,
+    job: Job,
+) -> Result<HttpResponse> {
+    images::respond(run_frame(&data, &job, &request).await)
+}
+
+#[post("/spritesheet")]
+pub async fn spritesheet_png(
+    request: web::Json<FrameImageRequest>,
+    data: web::Data<AppState>,
+    job: Job,
+) -> Result<HttpResponse> {
+    images::respond(run_spritesheet(&data, &job, &request).await)
+}
+
+#[post("/info")]
+pub async fn info_json(
+    request: web::Json<FrameImageRequest>,
+    data: web::Data<AppState>,
+    _job: Job,
+) -> Result<HttpResponse> {
+    let result = async {
+        let (bytes, frames, is_gif) = fetch(&data, &request.target_url).await?;
+        let (width, height) = frames
+            .first()
+            .map(|f| f.buffer().dimensions())
+            .unwrap_or((0, 0));
+        let delays_ms: Vec<u64> = frames.iter().map(delay_ms).collect();
+
+        Ok::<_, ImageError>(ImageInfo {
+            format: match is_gif {
+                true => "gif",
+                false => "still",
+            },
+            width,
+            height,
+            frame_count: frames.len(),
+            duration_ms: delays_ms.iter().sum(),
+            delays_ms,
+            loop_count: match is_gif {
+                true => gif_loop_count(&bytes),
+                false => None,
+            },
+        })
+    }
+    .await;
+
+    match result {
+        Ok(info) => Ok(HttpResponse::Ok().json(info)),
+        Err(e) => Ok(images::failure(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_frame, gif_loop_count, spritesheet, MAX_SHEET_SIDE};
+    use crate::cancel::CancelToken;
+    use crate::palette::{encode_gif, GifOptions};
+    use crate::queue::Progress;
+    use image::{Delay, Frame, Rgba, RgbaImage};
+    use shared::RangeBound;
+    use std::time::Duration;
+
+    fn frame(width: u32, height: u32, color: [u8; 4], delay_ms: u32) -> Frame {
+        let buffer = RgbaImage::from_pixel(width, height, Rgba(color));
+        Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(delay_ms, 1))
+    }
+
+    fn gif(loop_count: Option<u16>) -> Vec<u8> {
+        let options = GifOptions {
+            loop_count,
+            ..GifOptions::default()
+        };
+        let frames = vec![frame(4, 4, [255, 0, 0, 255], 100); 2];
+        encode_gif(
+            frames,
+            &options,
+            &Progress::default(),
+            &CancelToken::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_the_loop_count() {
+        assert_eq!(gif_loop_count(&gif(Some(0))), Some(0));
+        assert_eq!(gif_loop_count(&gif(Some(3))), Some(3));
+        assert_eq!(gif_loop_count(&gif(None)), None);
+        assert_eq!(gif_loop_count(b"\x89PNG NETSCAPE2.0\x03\x01\x05\x00"), None);
+    }
+
+    #[test]
+    fn ignores_the_marker_outside_its_extension() {
+        // a comment extension holding what looks like a loop count, before the trailer
+        let mut bytes = gif(None);
+        let trailer = bytes.pop().unwrap();
+        bytes.extend_from_slice(b"\x21\xfe\x0fNETSCAPE2.0\x03\x01\x05\x00\x00");
+        bytes.push(trailer);
+
+        assert_eq!(gif_loop_count(&bytes), None);
+        assert!(bytes.windows(11).any(|w| w == b"NETSCAPE2.0"));
+    }
+
+    #[test]
+    fn finds_frames() {
+        let frames = vec![
+            frame(1, 1, [0, 0, 0, 255], 100),
+            frame(1, 1, [0, 0, 0, 255], 100),
+            frame(1, 1, [0, 0, 0, 255], 100),
+        ];
+        let find = |bound| find_frame(&frames, bound);
+        let ms = |ms| RangeBound::Time(Duration::from_millis(ms));
+
+        assert_eq!(find(RangeBound::Frame(0)), Some(0));
+        assert_eq!(find(RangeBound::Frame(2)), Some(2));
+        assert_eq!(find(RangeBound::Frame(3)), None);
+        assert_eq!(find(ms(0)), Some(0));
+        assert_eq!(find(ms(99)), Some(0));
+        assert_eq!(find(ms(100)), Some(1));
+        assert_eq!(find(ms(250)), Some(2));
+        assert_eq!(find(ms(300)), None);
+        assert_eq!(find_frame(&[], RangeBound::Frame(0)), None);
+    }
+
+    #[test]
+    fn lays_out_spritesheets() {
+        let colors: Vec<[u8; 4]> = (1..=5).map(|n| [n, 0, 0, 255]).collect();
+        let frames: Vec<Frame> = colors.iter().map(|c| frame(10, 20, *c, 100)).collect();
+
+        // a square-ish grid by default, filled row by row
+        let sheet = spritesheet(&frames, None).unwrap();
+        assert_eq!(sheet.dimensions(), (30, 40));
+        assert_eq!(sheet.get_pixel(25, 5).0, colors[2]);
+        assert_eq!(sheet.get_pixel(15, 25).0, colors[4]);
+        assert_eq!(sheet.get_pixel(25, 25).0, [0, 0, 0, 0]);
+
+        let size = |columns| spritesheet(&frames, columns).unwrap().dimensions();
+        assert_eq!(size(Some(1)), (10, 100));
+        assert_eq!(size(Some(9)), (50, 20));
+        assert!(spritesheet(&[], None).is_none());
+    }
+
+    #[test]
+    fn caps_the_spritesheet_size() {
+        let frames = vec![frame(3000, 100, [1, 2, 3, 255], 100); 4];
+
+        // 12000 pixels wide, so every cell is scaled by the same 4096/12000
+        let sheet = spritesheet(&frames, Some(4)).unwrap();
+        assert_eq!(sheet.dimensions(), (MAX_SHEET_SIDE, 34));
+    }
+}
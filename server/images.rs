@@ -1,34 +1,107 @@
 use std::cmp::max;
 use std::collections::HashMap;
-use std::env;
 use std::io::Cursor;
-use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use actix_web::error::BlockingError;
-use actix_web::web;
-use bytes::Bytes;
+use actix_web::{http, web, HttpResponse};
+use bytes::{Bytes, BytesMut};
 use err_context::AnyError;
+use futures::stream::{self, StreamExt};
 use image::codecs::gif;
-use image::codecs::gif::Repeat;
-use image::{AnimationDecoder, DynamicImage, Frame, ImageFormat};
+use image::{AnimationDecoder, Delay, DynamicImage, Frame, ImageFormat, RgbaImage};
 use rusttype::{point, Font, Scale, IntoGlyphId, OutlineBuilder};
 
 use itertools::Itertools;
 use shared::ImageError;
+use tracing::{debug, warn};
 
-#[derive(serde::Deserialize)]
+use crate::caption::CaptionStyle;
+use crate::palette::{self, GifOptions};
+use crate::metrics::METRICS;
+use crate::queue::{self, Job, Stage};
+use crate::AppState;
+
+#[derive(serde::Deserialize, schemars::JsonSchema)]
 pub struct GenericImageRequest {
+    /// Image to work on. PNG, JPEG, GIF and other common formats are accepted.
     pub target_url: String,
     pub text: String,
+    /// Content-aware crop to this aspect ratio before processing, e.g. `16:9`.
+    #[serde(default)]
+    pub aspect: Option<String>,
+    #[serde(default)]
+    pub style: CaptionStyle,
+    #[serde(default)]
+    pub gif: GifOptions,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, schemars::JsonSchema)]
 pub struct ExploitableImageRequest {
     pub text: String,
 }
 
+#[derive(serde::Deserialize, schemars::JsonSchema)]
+pub struct AnimateImageRequest {
+    /// Image to animate. Only its first frame is used.
+    pub target_url: String,
+    /// Banner text, for effects that draw one. Defaults to `TRIGGERED`.
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Frames to generate, from 2 to 60. Defaults to 20.
+    #[serde(default)]
+    pub frames: Option<u32>,
+    /// Frames per second, from 1 to 50. Defaults to 20.
+    #[serde(default)]
+    pub fps: Option<u32>,
+    #[serde(default)]
+    pub gif: GifOptions,
+}
+
+#[derive(serde::Deserialize, schemars::JsonSchema)]
+pub struct TimelineImageRequest {
+    pub target_url: String,
+    /// Frames to keep, by index (`5-20`) or time (`1.2s-3s`, `500ms-`). Defaults to all.
+    #[serde(default)]
+    pub range: Option<String>,
+    #[serde(default)]
+    pub gif: GifOptions,
+}
+
+#[derive(serde::Deserialize, schemars::JsonSchema)]
+pub struct ConcatImageRequest {
+    pub target_url: String,
+    /// Image played after `target_url`.
+    pub other_url: String,
+    #[serde(default)]
+    pub gif: GifOptions,
+}
+
+#[derive(serde::Deserialize, schemars::JsonSchema)]
+pub struct CropImageRequest {
+    pub target_url: String,
+    /// Aspect ratio to crop to, e.g. `16:9` or `1:1`.
+    pub aspect: String,
+    #[serde(default)]
+    pub gif: GifOptions,
+}
+
+#[derive(serde::Deserialize, schemars::JsonSchema)]
+pub struct FrameImageRequest {
+    pub target_url: String,
+    /// Frame to extract, by index. Defaults to the first.
+    #[serde(default)]
+    pub index: Option<usize>,
+    /// Frame to extract, by time, e.g. `1.5s`. Overrides `index`.
+    #[serde(default)]
+    pub time: Option<String>,
+    /// Columns of a spritesheet. Defaults to a square-ish grid.
+    #[serde(default)]
+    pub columns: Option<u32>,
+}
+
 struct MaxOutlineBuilder {
     pub x: f32,
     pub y: f32,
@@ -64,20 +137,65 @@ impl MaxOutlineBuilder {
     }
 }
 
-pub async fn get_bytes(client: &reqwest::Client, target_url: &String) -> Result<Bytes, ImageError> {
+/// Downloads `target_url`, giving up once it is larger than `max_bytes`.
+pub async fn get_bytes(
+    client: &reqwest::Client,
+    target_url: &String,
+    max_bytes: usize,
+) -> Result<Bytes, ImageError> {
     let response = client.get(target_url).send().await;
 
     if let Err(e) = response {
         return Err(ImageError::BadRequest(e.to_string()));
     }
 
-    let response = response.unwrap().bytes().await;
+    let mut response = response.unwrap();
+    let too_large = || ImageError::BadImage(format!("Image is larger than {} bytes", max_bytes));
 
-    if let Err(e) = response {
-        return Err(ImageError::BadImage(e.to_string()));
+    if response.content_length().unwrap_or(0) > max_bytes as u64 {
+        return Err(too_large());
+    }
+
+    // the length can be missing or wrong, so count while reading too
+    let mut bytes = BytesMut::new();
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) if bytes.len() + chunk.len() > max_bytes => return Err(too_large()),
+            Ok(Some(chunk)) => bytes.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(e) => return Err(ImageError::BadImage(e.to_string())),
+        }
+    }
+
+    Ok(bytes.freeze())
+}
+
+/// Fails unless source images may be fetched from `target_url`'s host.
+fn check_host(data: &AppState, target_url: &str) -> Result<(), ImageError> {
+    let url = reqwest::Url::parse(target_url)
+        .map_err(|e| ImageError::BadRequest(format!("Bad url: {}", e)))?;
+
+    match url.host_str() {
+        Some(host) if data.config.host_allowed(host) => Ok(()),
+        Some(host) => Err(ImageError::BadRequest(format!(
+            "Images from {} are not allowed",
+            host
+        ))),
+        None => Err(ImageError::BadRequest("Url has no host".to_string())),
     }
+}
+
+/// Fetches a source image.
+#[tracing::instrument(name = "fetch", skip(data))]
+pub async fn source_bytes(data: &AppState, target_url: &String) -> Result<Bytes, ImageError> {
+    check_host(data, target_url)?;
+
+    let start = Instant::now();
+    let bytes = get_bytes(&data.client, target_url, data.config.limits.max_source_bytes).await?;
+    METRICS.phase("fetch", start.elapsed(), 0);
+    METRICS.bytes_in(bytes.len());
 
-    Ok(response.unwrap())
+    Ok(bytes)
 }
 
 pub fn get_text_size(scale: Scale, font: &Font, text: &str) -> (i32, i32) {
@@ -108,11 +226,9 @@ pub fn get_glyph_size<C: IntoGlyphId>(scale: Scale, font: &Font, glyph: C) -> (i
     (outline.x as i32, outline.y as i32)
 }
 
-pub async fn process(
-    bytes: Bytes,
-    f: impl FnMut(DynamicImage) -> Result<DynamicImage, AnyError> + Send + Sync + Clone + 'static,
-) -> Result<(Vec<u8>, bool), ImageError> {
-    println!("Downloading...");
+#[tracing::instrument(skip_all, fields(bytes = bytes.len()))]
+pub async fn decode(bytes: Bytes) -> Result<(Vec<Frame>, bool), ImageError> {
+    let start = Instant::now();
 
     let is_gif = Arc::new(AtomicBool::new(false));
     let copy = is_gif.clone();
@@ -152,69 +268,123 @@ pub async fn process(
         return Err(ImageError::ProcessingFailure(e.to_string()));
     }
 
-    println!("Executing job...");
-
     let frames = get_frames.unwrap();
+    METRICS.phase("decode", start.elapsed(), frames.len());
+    debug!(frames = frames.len(), elapsed_ms = start.elapsed().as_millis() as u64, "decoded");
+
+    Ok((frames, is_gif.load(Ordering::Relaxed)))
+}
+
+pub async fn transform(
+    job: &Job,
+    frames: Vec<Frame>,
+    mut f: impl FnMut(DynamicImage) -> Result<DynamicImage, AnyError> + Send + Sync + Clone + 'static,
+) -> Result<Vec<Frame>, ImageError> {
+    transform_indexed(job, frames, move |_, img| f(img)).await
+}
+
+/// Like [`transform`], but also hands the frame's index to `f`.
+///
+/// At most `job.frame_concurrency` frames are worked on at once, so one long GIF can't take
+/// over the whole blocking pool.
+#[tracing::instrument(name = "transform", skip_all, fields(frames = frames.len()))]
+pub async fn transform_indexed(
+    job: &Job,
+    frames: Vec<Frame>,
+    f: impl FnMut(usize, DynamicImage) -> Result<DynamicImage, AnyError> + Send + Sync + Clone + 'static,
+) -> Result<Vec<Frame>, ImageError> {
+    job.progress.begin(Stage::Processing, frames.len());
+    let (start, count) = (Instant::now(), frames.len());
 
     let new_frames = Arc::new(Mutex::new(HashMap::with_capacity(frames.len())));
-    let mut joinables = Vec::new();
-    for (i, frame) in frames.iter().enumerate() {
+    let joinables = frames.into_iter().enumerate().map(|(i, frame)| {
         let new_frames = new_frames.clone();
-        let frame = frame.clone();
+        let progress = job.progress.clone();
+        let cancel = job.cancel.clone();
         let mut f = f.clone();
-        joinables.push(web::block(move || {
-            let mut new_frames = new_frames.lock().unwrap();
-            let image = frame.buffer();
-            let image = f(DynamicImage::ImageRgba8(image.clone()))
-                .unwrap()
-                .to_rgba8();
+        web::block(move || -> Result<(), AnyError> {
+            cancel.check()?;
 
-            new_frames.insert(
-                i,
-                Frame::from_parts(image, frame.left(), frame.top(), frame.delay()),
-            );
-        }));
+            let (left, top, delay) = (frame.left(), frame.top(), frame.delay());
+            let image = f(i, DynamicImage::ImageRgba8(frame.into_buffer()))?.to_rgba8();
+
+            new_frames
+                .lock()
+                .unwrap()
+                .insert(i, Frame::from_parts(image, left, top, delay));
+            progress.advance();
+            Ok(())
+        })
+    });
+
+    let mut joined = stream::iter(joinables).buffer_unordered(job.frame_concurrency);
+    while let Some(joined) = joined.next().await {
+        match joined {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => return Err(image_error(e)),
+            Err(e) => return Err(ImageError::ProcessingFailure(e.to_string())),
+        }
     }
 
-    futures::future::join_all(joinables).await;
+    let new_frames = std::mem::take(&mut *new_frames.lock().unwrap());
+    METRICS.phase("transform", start.elapsed(), count);
+    debug!(elapsed_ms = start.elapsed().as_millis() as u64, "transformed");
+
+    Ok(new_frames
+        .into_iter()
+        .sorted_by_key(|k| k.0)
+        .map(|(_, frame)| frame)
+        .collect())
+}
 
-    let is_gif = is_gif.load(Ordering::Relaxed);
+/// Turns a single still image into `count` animation frames, each `delay` long.
+///
+/// `f` receives the frame index and the animation progress in `[0, 1)` for that frame.
+pub async fn generate(
+    job: &Job,
+    source: RgbaImage,
+    count: u32,
+    delay: Delay,
+    f: impl Fn(usize, f32, DynamicImage) -> Result<DynamicImage, AnyError> + Send + Sync + Clone + 'static,
+) -> Result<Vec<Frame>, ImageError> {
+    let frames = (0..count)
+        .map(|_| Frame::from_parts(source.clone(), 0, 0, delay))
+        .collect();
+
+    transform_indexed(job, frames, move |i, img| f(i, i as f32 / count as f32, img)).await
+}
 
-    println!("Start encoding...");
+#[tracing::instrument(skip_all, fields(frames = frames.len(), is_gif))]
+pub async fn encode(
+    job: &Job,
+    frames: Vec<Frame>,
+    is_gif: bool,
+    options: GifOptions,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    job.cancel.check()?;
+
+    let progress = job.progress.clone();
+    let cancel = job.cancel.clone();
+    let tmp = job.temp_dir.clone();
+    let (start, count) = (Instant::now(), frames.len());
+    progress.begin(
+        Stage::Encoding,
+        match is_gif {
+            true => frames.len(),
+            false => 1,
+        },
+    );
 
     let try_image: std::result::Result<
         std::result::Result<(Vec<u8>, bool), AnyError>,
         BlockingError,
     > = web::block(move || {
-        let new_frames = new_frames.lock().unwrap();
-
-        let tmp = match env::var("KUBERNETES_SERVICE_HOST") {
-            Ok(_) => {
-                // we are running in k8s
-                "/tmp/"
-            }
-            Err(_) => "./tmp",
-        };
-
-        let buf = PathBuf::from(format!("{}{}", tmp, uuid::Uuid::new_v4().to_string()));
+        let buf = tmp.join(uuid::Uuid::new_v4().to_string());
 
         if is_gif {
-            let mut out = Vec::new();
-            let mut encoder = gif::GifEncoder::new_with_speed(&mut out, 20);
-            for (_, frame) in new_frames.iter().sorted_by_key(|k| k.0) {
-                encoder.encode_frame(frame.clone())?;
-            }
-            encoder.set_repeat(Repeat::Infinite)?;
-
-            drop(encoder);
-
-            return Ok((out, true));
-        } else if new_frames.len() > 0 {
-            if new_frames.len() > 1 {
-                println!("Residual frames detected");
-            }
-            let image = new_frames.get(&0).unwrap();
-            let image = DynamicImage::ImageRgba8(image.buffer().clone());
+            return Ok((palette::encode_gif(frames, &options, &progress, &cancel)?, true));
+        } else if let Some(image) = frames.into_iter().next() {
+            let image = DynamicImage::ImageRgba8(image.into_buffer());
             match image {
                 DynamicImage::ImageRgba8(e) => e.save_with_format(&buf, ImageFormat::Png)?,
                 _ => return Err(ImageError::BadRequest("Unsupported format!".to_string()).into()),
@@ -223,6 +393,7 @@ pub async fn process(
             let bytes = std::fs::read(&buf)?;
 
             std::fs::remove_file(&buf)?;
+            progress.advance();
 
             return Ok((bytes, false));
         }
@@ -238,8 +409,75 @@ pub async fn process(
     let try_image = try_image.unwrap();
 
     if let Err(e) = try_image {
-        return Err(ImageError::ProcessingFailure(e.to_string()));
+        return Err(image_error(e));
+    }
+
+    let (bytes, is_gif) = try_image.unwrap();
+    METRICS.phase("encode", start.elapsed(), count);
+    METRICS.bytes_out(bytes.len());
+    debug!(bytes = bytes.len(), elapsed_ms = start.elapsed().as_millis() as u64, "encoded");
+
+    Ok((bytes, is_gif))
+}
+
+pub async fn process(
+    job: &Job,
+    bytes: Bytes,
+    options: GifOptions,
+    f: impl FnMut(DynamicImage) -> Result<DynamicImage, AnyError> + Send + Sync + Clone + 'static,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    let (frames, is_gif) = decode(bytes).await?;
+
+    process_frames(job, frames, is_gif, options, f).await
+}
+
+pub async fn process_frames(
+    job: &Job,
+    frames: Vec<Frame>,
+    is_gif: bool,
+    options: GifOptions,
+    f: impl FnMut(DynamicImage) -> Result<DynamicImage, AnyError> + Send + Sync + Clone + 'static,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    if !is_gif && frames.len() > 1 {
+        warn!(frames = frames.len(), "Residual frames detected");
     }
 
-    Ok(try_image.unwrap())
+    let frames = transform(job, frames, f).await?;
+
+    encode(job, frames, is_gif, options).await
+}
+
+/// Recovers an [`ImageError`] that went through a stage as an [`AnyError`], so timeouts and
+/// cancellations keep their meaning.
+pub fn image_error(e: AnyError) -> ImageError {
+    match e.downcast::<ImageError>() {
+        Ok(e) => *e,
+        Err(e) => ImageError::ProcessingFailure(e.to_string()),
+    }
+}
+
+/// Answers a failed job with the status for its error, and when to retry if it's worth it.
+pub fn failure(e: ImageError) -> HttpResponse {
+    warn!(error = %e, "Job failed");
+    METRICS.error(&e);
+    let mut response = HttpResponse::build(queue::status_code(&e));
+    if let Some(secs) = queue::retry_after(&e) {
+        response.append_header(("Retry-After", secs.to_string()));
+    }
+    response.body(format!("Failed to modify image. {}", e))
+}
+
+/// Turns the output of a job into a response, typed by whether it came out as a GIF.
+pub fn respond(result: Result<(Vec<u8>, bool), ImageError>) -> actix_web::Result<HttpResponse> {
+    let (result, is_gif) = match result {
+        Ok(result) => result,
+        Err(e) => return Ok(failure(e)),
+    };
+
+    Ok(HttpResponse::Ok()
+        .append_header(http::header::ContentType(match is_gif {
+            false => mime::IMAGE_PNG,
+            true => mime::IMAGE_GIF,
+        }))
+        .body(result))
 }
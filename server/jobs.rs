@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::LOCATION;
+use actix_web::http::StatusCode;
+use actix_web::*;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use shared::ImageError;
+use tracing::{info_span, warn, Instrument};
+use uuid::Uuid;
+
+use crate::animate::Effect;
+use crate::images::{
+    AnimateImageRequest, ConcatImageRequest, CropImageRequest, ExploitableImageRequest,
+    FrameImageRequest, GenericImageRequest, TimelineImageRequest,
+};
+use crate::cancel::CancelToken;
+use crate::config::Config;
+use crate::metrics::METRICS;
+use crate::queue::{self, Job, JobError, Progress, Stage};
+use crate::{animate, caption, crop, frames, images, severed, timeline, AppState};
+
+#[derive(serde::Deserialize, schemars::JsonSchema)]
+pub struct JobRequest {
+    /// The endpoint to run, as it would be called synchronously, e.g. `/caption` or
+    /// `/animate/spin`.
+    pub endpoint: String,
+    /// The body that endpoint takes.
+    pub request: Value,
+}
+
+/// The work of a job, with its request already checked.
+enum Task {
+    Caption(GenericImageRequest),
+    Severed(ExploitableImageRequest),
+    Animate(Effect, AnimateImageRequest),
+    Reverse(TimelineImageRequest),
+    Boomerang(TimelineImageRequest),
+    Trim(TimelineImageRequest),
+    Concat(ConcatImageRequest),
+    Frame(FrameImageRequest),
+    Spritesheet(FrameImageRequest),
+    Crop(CropImageRequest),
+}
+
+fn body<T: DeserializeOwned>(request: Value) -> Result<T, ImageError> {
+    serde_json::from_value(request).map_err(|e| ImageError::BadRequest(e.to_string()))
+}
+
+impl Task {
+    fn parse(endpoint: &str, request: Value) -> Result<Self, ImageError> {
+        Ok(match endpoint {
+            "/caption" => Task::Caption(body(request)?),
+            "/severed" => Task::Severed(body(request)?),
+            "/reverse" => Task::Reverse(body(request)?),
+            "/boomerang" => Task::Boomerang(body(request)?),
+            "/trim" => Task::Trim(body(request)?),
+            "/concat" => Task::Concat(body(request)?),
+            "/frame" => Task::Frame(body(request)?),
+            "/spritesheet" => Task::Spritesheet(body(request)?),
+            "/crop" => Task::Crop(body(request)?),
+            _ => match endpoint.strip_prefix("/animate/").and_then(Effect::from_name) {
+                Some(effect) => Task::Animate(effect, body(request)?),
+                None => {
+                    return Err(ImageError::BadRequest(format!(
+                        "Unknown endpoint {}",
+                        endpoint
+                    )))
+                }
+            },
+        })
+    }
+
+    async fn run(&self, data: &AppState, job: &Job) -> Result<(Vec<u8>, bool), ImageError> {
+        match self {
+            Task::Caption(request) => caption::run(data, job, request).await,
+            Task::Severed(request) => severed::run(data, job, request).await,
+            Task::Animate(effect, request) => animate::run(data, job, *effect, request).await,
+            Task::Reverse(request) => timeline::run_reverse(data, job, request).await,
+            Task::Boomerang(request) => timeline::run_boomerang(data, job, request).await,
+            Task::Trim(request) => timeline::run_trim(data, job, request).await,
+            Task::Concat(request) => timeline::run_concat(data, job, request).await,
+            Task::Frame(request) => frames::run_frame(data, job, request).await,
+            Task::Spritesheet(request) => frames::run_spritesheet(data, job, request).await,
+            Task::Crop(request) => crop::run(data, job, request).await,
+        }
+    }
+}
+
+enum Outcome {
+    Pending,
+    Done(Vec<u8>, bool),
+    /// Status to answer with, seconds to retry after if worth retrying, and the error.
+    Failed(StatusCode, Option<u64>, String),
+}
+
+struct Entry {
+    progress: Arc<Progress>,
+    cancel: CancelToken,
+    outcome: Mutex<Outcome>,
+    /// When the outcome was set. Results are kept for a while from then, however long the job
+    /// took to get there.
+    finished: Mutex<Option<Instant>>,
+}
+
+impl Entry {
+    fn finish(&self, outcome: Outcome) {
+        *self.outcome.lock().unwrap() = outcome;
+        *self.finished.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn expired(&self, ttl: Duration) -> bool {
+        self.finished
+            .lock()
+            .unwrap()
+            .is_some_and(|finished| finished.elapsed() >= ttl)
+    }
+}
+
+#[derive(serde::Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(serde::Serialize, schemars::JsonSchema)]
+pub struct JobStatus {
+    pub id: String,
+    /// Where to poll this job. Absolute when the server knows its own address, as each
+    /// replica only knows about its own jobs.
+    pub url: String,
+    pub state: JobState,
+    /// Jobs waiting ahead of this one, while queued.
+    pub ahead: Option<usize>,
+    pub stage: Stage,
+    pub frames_done: usize,
+    pub frames_total: usize,
+    pub error: Option<String>,
+}
+
+/// Jobs submitted through `/jobs`, kept until their results expire.
+pub struct JobStore {
+    jobs: Mutex<HashMap<Uuid, Arc<Entry>>>,
+    /// Base URL this replica can be reached at directly.
+    advertise_url: Option<String>,
+    /// How long a finished job's result is kept around to be collected.
+    result_ttl: Duration,
+}
+
+impl JobStore {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            advertise_url: config.advertise_url.clone(),
+            result_ttl: config.limits.result_ttl(),
+        }
+    }
+
+    fn insert(&self, id: Uuid, cancel: CancelToken) -> Arc<Entry> {
+        let entry = Arc::new(Entry {
+            progress: Arc::default(),
+            cancel,
+            outcome: Mutex::new(Outcome::Pending),
+            finished: Mutex::new(None),
+        });
+
+        let mut jobs = self.jobs.lock().unwrap();
+        self.sweep(&mut jobs);
+        jobs.insert(id, entry.clone());
+
+        entry
+    }
+
+    /// Forgets the finished jobs whose results have been kept long enough.
+    fn sweep(&self, jobs: &mut HashMap<Uuid, Arc<Entry>>) {
+        jobs.retain(|_, e| !e.expired(self.result_ttl));
+    }
+
+    fn get(&self, id: &Uuid) -> Option<Arc<Entry>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.sweep(&mut jobs);
+        jobs.get(id).cloned()
+    }
+
+    fn remove(&self, id: &Uuid) -> Option<Arc<Entry>> {
+        self.jobs.lock().unwrap().remove(id)
+    }
+
+    fn status(&self, data: &AppState, id: Uuid, entry: &Entry) -> JobStatus {
+        let (stage, frames_done, frames_total) = entry.progress.snapshot();
+        let ahead = entry
+            .progress
+            .ticket()
+            .and_then(|ticket| data.queue.ahead_of(ticket));
+
+        let (state, error) = match &*entry.outcome.lock().unwrap() {
+            Outcome::Pending if ahead.is_some() => (JobState::Queued, None),
+            Outcome::Pending => (JobState::Running, None),
+            Outcome::Done(_, _) => (JobState::Done, None),
+            Outcome::Failed(_, _, e) => (JobState::Failed, Some(e.clone())),
+        };
+
+        JobStatus {
+            id: id.to_string(),
+            url: format!(
+                "{}/jobs/{}",
+                self.advertise_url.as_deref().unwrap_or(""),
+                id
+            ),
+            state,
+            ahead,
+            stage,
+            frames_done,
+            frames_total,
+            error,
+        }
+    }
+}
+
+fn parse_id(id: &str) -> Option<Uuid> {
+    Uuid::parse_str(id).ok()
+}
+
+#[post("/jobs")]
+pub async fn submit(
+    req: HttpRequest,
+    request: web::Json<JobRequest>,
+    data: web::Data<AppState>,
+) -> std::result::Result<HttpResponse, JobError> {
+    let request = request.into_inner();
+    let task = Task::parse(&request.endpoint, request.request).map_err(JobError)?;
+    let cancel = CancelToken::from_request(&req, &data.config.limits).map_err(JobError)?;
+    data.queue.check_capacity().map_err(JobError)?;
+
+    let id = Uuid::new_v4();
+    let entry = data.jobs.insert(id, cancel);
+    let status = data.jobs.status(&data, id, &entry);
+
+    // keeps the submitting request's id on everything the job logs
+    let span = info_span!("job", id = %id);
+    rt::spawn(
+        async move {
+            let result = async {
+                let job = data
+                    .queue
+                    .enter(entry.progress.clone(), entry.cancel.clone())
+                    .await?;
+                task.run(&data, &job).await
+            }
+            .await;
+
+            entry.finish(match result {
+                Ok((bytes, is_gif)) => Outcome::Done(bytes, is_gif),
+                Err(e) => {
+                    warn!(error = %e, "Job failed");
+                    METRICS.error(&e);
+                    Outcome::Failed(queue::status_code(&e), queue::retry_after(&e), e.to_string())
+                }
+            });
+        }
+        .instrument(span),
+    );
+
+    Ok(HttpResponse::Accepted()
+        .append_header((LOCATION, status.url.clone()))
+        .json(status))
+}
+
+#[get("/jobs/{id}")]
+pub async fn job_status(id: web::Path<String>, data: web::Data<AppState>) -> HttpResponse {
+    let id = match parse_id(&id) {
+        Some(id) => id,
+        None => return HttpResponse::NotFound().body("No such job"),
+    };
+
+    match data.jobs.get(&id) {
+        Some(entry) => HttpResponse::Ok().json(data.jobs.status(&data, id, &entry)),
+        None => HttpResponse::NotFound().body("No such job"),
+    }
+}
+
+#[get("/jobs/{id}/result")]
+pub async fn job_result(id: web::Path<String>, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let entry = match parse_id(&id).and_then(|id| data.jobs.get(&id)) {
+        Some(entry) => entry,
+        None => return Ok(HttpResponse::NotFound().body("No such job")),
+    };
+
+    let outcome = entry.outcome.lock().unwrap();
+    match &*outcome {
+        Outcome::Pending => Ok(HttpResponse::Conflict().body("Job has not finished")),
+        Outcome::Done(bytes, is_gif) => images::respond(Ok((bytes.clone(), *is_gif))),
+        Outcome::Failed(status, retry, e) => {
+            let mut response = HttpResponse::build(*status);
+            if let Some(secs) = retry {
+                response.append_header(("Retry-After", secs.to_string()));
+            }
+            Ok(response.body(format!("Failed to modify image. {}", e)))
+        }
+    }
+}
+
+/// Cancels a job, or throws away the result of a finished one.
+#[delete("/jobs/{id}")]
+pub async fn cancel_job(id: web::Path<String>, data: web::Data<AppState>) -> HttpResponse {
+    match parse_id(&id).and_then(|id| data.jobs.remove(&id)) {
+        Some(entry) => {
+            entry.cancel.cancel();
+            HttpResponse::NoContent().finish()
+        }
+        None => HttpResponse::NotFound().body("No such job"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{self, TestRequest};
+
+    async fn call(data: &web::Data<AppState>, request: TestRequest) -> (StatusCode, web::Bytes) {
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .service(submit)
+                .service(job_status)
+                .service(job_result)
+                .service(cancel_job),
+        )
+        .await;
+        let response = test::call_service(&app, request.to_request()).await;
+
+        (response.status(), test::read_body(response).await)
+    }
+
+    async fn status(data: &web::Data<AppState>, id: impl std::fmt::Display) -> (StatusCode, Value) {
+        let (code, body) = call(data, TestRequest::get().uri(&format!("/jobs/{}", id))).await;
+        (code, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[actix_rt::test]
+    async fn submits_jobs() {
+        let data = AppState::for_tests(Config {
+            allowed_hosts: vec!["example.com".to_string()],
+            ..Config::default()
+        });
+
+        let request = |endpoint| {
+            TestRequest::post()
+                .uri("/jobs")
+                .set_json(serde_json::json!({
+                    "endpoint": endpoint,
+                    "request": {"target_url": "http://localhost/a.png", "text": "hi"},
+                }))
+        };
+        let (code, _) = call(&data, request("/nothing")).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+
+        let (code, body) = call(&data, request("/caption")).await;
+        assert_eq!(code, StatusCode::ACCEPTED);
+        let submitted: Value = serde_json::from_slice(&body).unwrap();
+        let id = submitted["id"].as_str().unwrap();
+        assert_eq!(submitted["url"], format!("/jobs/{}", id));
+
+        // localhost isn't an allowed host, so the job fails as soon as it runs
+        let mut state = Value::Null;
+        for _ in 0..50 {
+            state = status(&data, id).await.1["state"].clone();
+            if state != "queued" && state != "running" {
+                break;
+            }
+            actix_rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(state, "failed");
+
+        let result = TestRequest::get().uri(&format!("/jobs/{}/result", id));
+        assert_eq!(call(&data, result).await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn reports_progress() {
+        let data = AppState::for_tests(Config::default());
+        let id = Uuid::new_v4();
+        let entry = data.jobs.insert(id, CancelToken::default());
+        entry.progress.begin(Stage::Encoding, 10);
+        for _ in 0..3 {
+            entry.progress.advance();
+        }
+
+        let (code, running) = status(&data, id).await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(running["state"], "running");
+        assert_eq!(running["stage"], "encoding");
+        assert_eq!(running["frames_done"], 3);
+        assert_eq!(running["frames_total"], 10);
+
+        entry.finish(Outcome::Done(b"GIF89a".to_vec(), true));
+        assert_eq!(status(&data, id).await.1["state"], "done");
+        let result = TestRequest::get().uri(&format!("/jobs/{}/result", id));
+        assert_eq!(
+            call(&data, result).await,
+            (StatusCode::OK, web::Bytes::from_static(b"GIF89a"))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn pending_results_conflict() {
+        let data = AppState::for_tests(Config::default());
+        let id = Uuid::new_v4();
+        data.jobs.insert(id, CancelToken::default());
+
+        let result = TestRequest::get().uri(&format!("/jobs/{}/result", id));
+        assert_eq!(call(&data, result).await.0, StatusCode::CONFLICT);
+        let missing = TestRequest::get().uri(&format!("/jobs/{}/result", Uuid::new_v4()));
+        assert_eq!(call(&data, missing).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn cancels_pending_jobs() {
+        let data = AppState::for_tests(Config::default());
+        let id = Uuid::new_v4();
+        let entry = data.jobs.insert(id, CancelToken::default());
+
+        let cancel = || TestRequest::delete().uri(&format!("/jobs/{}", id));
+        assert_eq!(call(&data, cancel()).await.0, StatusCode::NO_CONTENT);
+        assert!(entry.cancel.is_cancelled());
+        assert_eq!(status(&data, id).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(call(&data, cancel()).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn keeps_results_from_when_they_finish() {
+        let mut store = JobStore::new(&Config::default());
+        store.result_ttl = Duration::from_millis(50);
+        let (slow, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let entry = store.insert(slow, CancelToken::default());
+
+        // running for longer than results are kept doesn't count against the result
+        actix_rt::time::sleep(Duration::from_millis(80)).await;
+        entry.finish(Outcome::Done(vec![], false));
+        assert!(store.get(&slow).is_some());
+
+        actix_rt::time::sleep(Duration::from_millis(80)).await;
+        store.insert(other, CancelToken::default());
+        assert!(store.get(&other).is_some());
+        assert!(store.get(&slow).is_none());
+        assert_eq!(store.jobs.lock().unwrap().len(), 1);
+    }
+}
@@ -1,40 +1,151 @@
 use std::io;
 
 use actix_web::*;
+use shared::ConfigError;
 
+mod animate;
+mod auth;
+mod cancel;
 mod caption;
+mod config;
+mod crop;
 mod font;
+mod frames;
 mod images;
+mod jobs;
+mod metrics;
+mod openapi;
+mod palette;
+mod probes;
+mod queue;
 mod severed;
+mod shutdown;
+mod templates;
+mod timeline;
+mod trace;
+
+use crate::auth::Auth;
+use crate::config::Config;
+use crate::jobs::JobStore;
+use crate::probes::Assets;
+use crate::queue::JobQueue;
+use crate::templates::Templates;
 
 pub struct AppState {
+    config: Config,
+    templates: Templates,
     client: reqwest::Client,
+    queue: JobQueue,
+    jobs: JobStore,
+    assets: Assets,
+    auth: Auth,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State for testing handlers, with the built-in templates.
+    fn for_tests(config: Config) -> web::Data<Self> {
+        let templates = Templates::load(None).unwrap();
+        web::Data::new(AppState {
+            client: reqwest::Client::new(),
+            queue: JobQueue::new(&config),
+            jobs: JobStore::new(&config),
+            assets: Assets::load(&templates),
+            auth: Auth::new(&config.auth),
+            templates,
+            config,
+        })
+    }
 }
 
+/// Kept for older bots; the probes use `/livez` and `/readyz`.
 #[get("/health")]
 async fn health() -> Result<HttpResponse, error::Error> {
     Ok(HttpResponse::Ok().body("200 OK"))
 }
 
+fn startup_failure(e: ConfigError) -> ! {
+    tracing::error!(error = %e, "Cannot start server");
+    std::process::exit(1)
+}
+
 #[actix_rt::main]
 async fn main() -> io::Result<()> {
-    let host = "0.0.0.0:8080";
+    shared::init_logging();
+
+    let config = Config::load().unwrap_or_else(|e| startup_failure(e));
+    let templates =
+        Templates::load(config.templates_dir.as_deref()).unwrap_or_else(|e| startup_failure(e));
+
+    // redirects must stay on allowed hosts too
+    let allowed = config.allowed_hosts.clone();
+    let client = reqwest::Client::builder()
+        .user_agent(config.user_agent.as_str())
+        .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+            match attempt.url().host_str() {
+                Some(host) if config::host_allowed(&allowed, host) => attempt.follow(),
+                _ => attempt.stop(),
+            }
+        }))
+        .build()
+        .unwrap();
+
+    // shared between workers, so the queue limits the whole server
+    let state = web::Data::new(AppState {
+        client,
+        queue: JobQueue::new(&config),
+        jobs: JobStore::new(&config),
+        assets: Assets::load(&templates),
+        auth: Auth::new(&config.auth),
+        templates,
+        config,
+    });
+    let (bind, workers) = (state.config.bind.clone(), state.config.workers);
+    let max_running = state.queue.status().max_running;
+    let auth = state.auth.enabled();
+    let shutdown_state = state.clone();
 
-    let server = HttpServer::new(|| {
+    let server = HttpServer::new(move || {
         App::new()
-            .wrap(middleware::Logger::default())
+            .wrap(crate::auth::Authenticate)
+            .wrap_fn(crate::metrics::track)
+            .wrap_fn(crate::trace::request_span)
             .service(health)
+            .service(crate::probes::livez)
+            .service(crate::probes::readyz)
+            .service(crate::queue::queue_status)
+            .service(crate::metrics::metrics)
+            .service(crate::openapi::openapi_json)
+            .service(crate::openapi::playground)
             .service(crate::caption::caption)
             .service(crate::severed::severed)
-            .app_data(web::Data::new(AppState {
-                client: reqwest::Client::builder()
-                    .user_agent("imgBot-server")
-                    .build()
-                    .unwrap(),
-            }))
+            .service(crate::animate::animate)
+            .service(crate::timeline::reverse_gif)
+            .service(crate::timeline::boomerang_gif)
+            .service(crate::timeline::trim_gif)
+            .service(crate::timeline::concat_gif)
+            .service(crate::frames::frame_png)
+            .service(crate::frames::spritesheet_png)
+            .service(crate::frames::info_json)
+            .service(crate::crop::crop)
+            .service(crate::jobs::submit)
+            .service(crate::jobs::job_status)
+            .service(crate::jobs::job_result)
+            .service(crate::jobs::cancel_job)
+            .app_data(state.clone())
     })
-    .bind(host)?;
+    .on_connect(crate::cancel::on_connect)
+    // jobs are drained first, see `shutdown::on_signal`
+    .disable_signals()
+    .shutdown_timeout(crate::shutdown::STOP_TIMEOUT.as_secs())
+    .bind(&bind)?;
+    let server = match workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
 
-    println!("Starting server!");
-    server.run().await
+    tracing::info!(%bind, max_running, auth, "Starting server!");
+    let server = server.run();
+    rt::spawn(crate::shutdown::on_signal(shutdown_state, server.handle()));
+    server.await
 }
@@ -0,0 +1,247 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::*;
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use shared::ImageError;
+
+use crate::AppState;
+
+/// Buckets for whole requests and their phases, in seconds. Big GIFs take tens of seconds.
+static LATENCY_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30., 60., 120.];
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_seconds: HistogramVec,
+    phase_seconds: HistogramVec,
+    frames: IntCounterVec,
+    bytes_in: IntCounter,
+    bytes_out: IntCounter,
+    errors: IntCounterVec,
+    jobs_running: IntGauge,
+    jobs_queued: IntGauge,
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(registry: &Registry, metric: T) -> T {
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("metric registration failure");
+    metric
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("imgserver".to_string()), None)
+            .expect("metric registry failure");
+
+        let histogram = |name: &str, help: &str, labels: &[&str]| {
+            HistogramVec::new(
+                HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec()),
+                labels,
+            )
+            .unwrap()
+        };
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            IntCounterVec::new(Opts::new(name, help), labels).unwrap()
+        };
+
+        Self {
+            requests: register(
+                &registry,
+                counter("requests_total", "Requests answered, by endpoint and status.", &["endpoint", "status"]),
+            ),
+            request_seconds: register(
+                &registry,
+                histogram("request_seconds", "Time taken to answer a request.", &["endpoint"]),
+            ),
+            phase_seconds: register(
+                &registry,
+                histogram("phase_seconds", "Time spent in each phase of an image job.", &["phase"]),
+            ),
+            frames: register(
+                &registry,
+                counter("frames_total", "Frames handled, by phase.", &["phase"]),
+            ),
+            bytes_in: register(
+                &registry,
+                IntCounter::new("source_bytes_total", "Bytes of source images fetched.").unwrap(),
+            ),
+            bytes_out: register(
+                &registry,
+                IntCounter::new("output_bytes_total", "Bytes of images produced.").unwrap(),
+            ),
+            errors: register(
+                &registry,
+                counter("errors_total", "Failed jobs, by error.", &["error"]),
+            ),
+            jobs_running: register(
+                &registry,
+                IntGauge::new("jobs_running", "Jobs being processed.").unwrap(),
+            ),
+            jobs_queued: register(
+                &registry,
+                IntGauge::new("jobs_queued", "Jobs waiting for a slot.").unwrap(),
+            ),
+            registry,
+        }
+    }
+
+    /// Records how long `phase` of a job took, and how many frames it went through.
+    pub fn phase(&self, phase: &str, took: Duration, frames: usize) {
+        self.phase_seconds
+            .with_label_values(&[phase])
+            .observe(took.as_secs_f64());
+        if frames > 0 {
+            self.frames
+                .with_label_values(&[phase])
+                .inc_by(frames as u64);
+        }
+    }
+
+    pub fn bytes_in(&self, bytes: usize) {
+        self.bytes_in.inc_by(bytes as u64);
+    }
+
+    pub fn bytes_out(&self, bytes: usize) {
+        self.bytes_out.inc_by(bytes as u64);
+    }
+
+    pub fn error(&self, e: &ImageError) {
+        self.errors.with_label_values(&[e.name()]).inc();
+    }
+}
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+/// Middleware counting and timing requests by the route they matched. Used with `wrap_fn`.
+pub fn track<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    let start = Instant::now();
+    // route patterns, not paths, to keep the number of series bounded
+    let endpoint = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    let response = srv.call(req);
+
+    async move {
+        let response = response.await;
+        let status = match &response {
+            Ok(response) => response.status().as_u16().to_string(),
+            Err(e) => e.as_response_error().status_code().as_u16().to_string(),
+        };
+
+        METRICS
+            .requests
+            .with_label_values(&[&endpoint, &status])
+            .inc();
+        METRICS
+            .request_seconds
+            .with_label_values(&[&endpoint])
+            .observe(start.elapsed().as_secs_f64());
+
+        response
+    }
+}
+
+#[get("/metrics")]
+pub async fn metrics(data: web::Data<AppState>) -> HttpResponse {
+    let queue = data.queue.status();
+    METRICS.jobs_running.set(queue.running as i64);
+    METRICS.jobs_queued.set(queue.queued as i64);
+
+    let mut out = Vec::new();
+    let encoder = TextEncoder::new();
+    match encoder.encode(&METRICS.registry.gather(), &mut out) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(out),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{metrics, track, Metrics, METRICS};
+    use crate::config::Config;
+    use crate::AppState;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use prometheus::Encoder;
+    use shared::ImageError;
+
+    fn text(registry: &Metrics) -> String {
+        let mut out = Vec::new();
+        prometheus::TextEncoder::new()
+            .encode(&registry.registry.gather(), &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn exposes_metrics() {
+        let app = init_service(
+            App::new()
+                .app_data(AppState::for_tests(Config::default()))
+                .service(metrics),
+        )
+        .await;
+
+        let response = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        assert!(response.status().is_success());
+        let content_type = response.headers().get("content-type").unwrap().to_str().unwrap();
+        assert!(content_type.starts_with("text/plain"));
+        let body = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        assert!(body.contains("# TYPE imgserver_jobs_running gauge"));
+        assert!(body.contains("imgserver_jobs_running 0"));
+        assert!(body.contains("imgserver_jobs_queued 0"));
+    }
+
+    #[actix_rt::test]
+    async fn labels_requests_by_route() {
+        let app = init_service(
+            App::new().wrap_fn(track).route(
+                "/tracked/{id}",
+                web::get().to(|| async { HttpResponse::NotFound().finish() }),
+            ),
+        )
+        .await;
+
+        for id in 1..=3 {
+            let uri = format!("/tracked/{}", id);
+            call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        }
+        call_service(&app, TestRequest::get().uri("/untracked").to_request()).await;
+
+        let body = text(&METRICS);
+        let tracked = r#"imgserver_requests_total{endpoint="/tracked/{id}",status="404"} 3"#;
+        assert!(body.contains(tracked));
+        assert!(!body.contains(r#"endpoint="/tracked/1""#));
+        assert!(body.contains(r#"endpoint="unmatched",status="404""#));
+    }
+
+    #[test]
+    fn counts_errors_by_name() {
+        let counted = Metrics::new();
+        counted.error(&ImageError::Timeout);
+        counted.error(&ImageError::BadImage("not a PNG".to_string()));
+        counted.error(&ImageError::BadImage("not a GIF".to_string()));
+
+        let body = text(&counted);
+        assert!(body.contains(r#"imgserver_errors_total{error="bad_image"} 2"#));
+        assert!(body.contains(r#"imgserver_errors_total{error="timeout"} 1"#));
+        assert!(!body.contains("not a PNG"));
+    }
+}
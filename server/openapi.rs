@@ -0,0 +1,317 @@
+use actix_web::{get, HttpResponse};
+use once_cell::sync::Lazy;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use serde_json::{json, Map, Value};
+
+use crate::animate::EFFECT_NAMES;
+use crate::cancel::DEADLINE_HEADER;
+use crate::frames::ImageInfo;
+use crate::images::{
+    AnimateImageRequest, ConcatImageRequest, CropImageRequest, ExploitableImageRequest,
+    FrameImageRequest, GenericImageRequest, TimelineImageRequest,
+};
+use crate::jobs::{JobRequest, JobStatus};
+use crate::queue::QueueStatus;
+
+/// The playground page, which reads `/openapi.json` to build its form.
+static PLAYGROUND: &str = include_str!("pack/playground.html");
+
+/// What an endpoint answers with when it works.
+enum Answer {
+    /// An image, as PNG or GIF depending on the input.
+    Image,
+    /// Always a PNG.
+    Png,
+    Json(Value),
+    /// A job was started, described by this JSON.
+    Accepted(Value),
+    Empty,
+}
+
+struct Endpoint {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    body: Option<Value>,
+    answer: Answer,
+    /// Whether the endpoint does image work, so takes a deadline and can be turned away.
+    work: bool,
+}
+
+fn image(path: &'static str, summary: &'static str, body: Value) -> Endpoint {
+    Endpoint {
+        method: "post",
+        path,
+        summary,
+        body: Some(body),
+        answer: Answer::Image,
+        work: true,
+    }
+}
+
+fn endpoints(gen: &mut SchemaGenerator) -> Vec<Endpoint> {
+    let generic = gen.subschema_for::<GenericImageRequest>();
+    let timeline = gen.subschema_for::<TimelineImageRequest>();
+    let frame = gen.subschema_for::<FrameImageRequest>();
+    let status = gen.subschema_for::<JobStatus>();
+
+    vec![
+        image("/caption", "Caption an image", json!(generic)),
+        image(
+            "/severed",
+            "Draw text on the severed template",
+            json!(gen.subschema_for::<ExploitableImageRequest>()),
+        ),
+        image(
+            "/animate/{effect}",
+            "Animate a still image",
+            json!(gen.subschema_for::<AnimateImageRequest>()),
+        ),
+        image("/reverse", "Play a GIF backwards", json!(timeline)),
+        image(
+            "/boomerang",
+            "Play a GIF forwards then backwards",
+            json!(timeline),
+        ),
+        image(
+            "/trim",
+            "Cut a GIF down to a range of frames",
+            json!(timeline),
+        ),
+        image(
+            "/concat",
+            "Play two images one after the other",
+            json!(gen.subschema_for::<ConcatImageRequest>()),
+        ),
+        image(
+            "/crop",
+            "Crop to an aspect ratio around the interesting part",
+            json!(gen.subschema_for::<CropImageRequest>()),
+        ),
+        Endpoint {
+            answer: Answer::Png,
+            ..image("/frame", "Extract a single frame", json!(frame))
+        },
+        Endpoint {
+            answer: Answer::Png,
+            ..image(
+                "/spritesheet",
+                "Lay out every frame in a grid",
+                json!(frame),
+            )
+        },
+        Endpoint {
+            answer: Answer::Json(json!(gen.subschema_for::<ImageInfo>())),
+            ..image(
+                "/info",
+                "Describe an image without changing it",
+                json!(frame),
+            )
+        },
+        Endpoint {
+            answer: Answer::Accepted(json!(status)),
+            ..image(
+                "/jobs",
+                "Run any of the image endpoints in the background",
+                json!(gen.subschema_for::<JobRequest>()),
+            )
+        },
+        Endpoint {
+            method: "get",
+            path: "/jobs/{id}",
+            summary: "Poll a job",
+            body: None,
+            answer: Answer::Json(json!(status)),
+            work: false,
+        },
+        Endpoint {
+            method: "get",
+            path: "/jobs/{id}/result",
+            summary: "Fetch the image a job made",
+            body: None,
+            answer: Answer::Image,
+            work: false,
+        },
+        Endpoint {
+            method: "delete",
+            path: "/jobs/{id}",
+            summary: "Cancel a job, or forget a finished one",
+            body: None,
+            answer: Answer::Empty,
+            work: false,
+        },
+        Endpoint {
+            method: "get",
+            path: "/queue",
+            summary: "How busy the server is",
+            body: None,
+            answer: Answer::Json(json!(gen.subschema_for::<QueueStatus>())),
+            work: false,
+        },
+    ]
+}
+
+fn error(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "text/plain": { "schema": { "type": "string" } } },
+    })
+}
+
+fn operation(endpoint: &Endpoint) -> Value {
+    let mut parameters = vec![];
+    if endpoint.path.contains("{effect}") {
+        parameters.push(json!({
+            "name": "effect",
+            "in": "path",
+            "required": true,
+            "schema": { "type": "string", "enum": EFFECT_NAMES },
+        }));
+    }
+    if endpoint.path.contains("{id}") {
+        parameters.push(json!({
+            "name": "id",
+            "in": "path",
+            "required": true,
+            "schema": { "type": "string" },
+        }));
+    }
+    if endpoint.work {
+        parameters.push(json!({
+            "name": DEADLINE_HEADER,
+            "in": "header",
+            "description": "Milliseconds the job may take, within the server's limit.",
+            "schema": { "type": "integer", "minimum": 1 },
+        }));
+    }
+    parameters.push(json!({ "$ref": "#/components/parameters/RequestId" }));
+
+    let (code, ok) = match &endpoint.answer {
+        Answer::Image => (
+            "200",
+            json!({
+                "description": "The image, as a GIF if it is animated and a PNG otherwise",
+                "content": {
+                    "image/png": { "schema": { "type": "string", "format": "binary" } },
+                    "image/gif": { "schema": { "type": "string", "format": "binary" } },
+                },
+            }),
+        ),
+        Answer::Png => (
+            "200",
+            json!({
+                "description": "The image",
+                "content": { "image/png": { "schema": { "type": "string", "format": "binary" } } },
+            }),
+        ),
+        Answer::Json(schema) => (
+            "200",
+            json!({
+                "description": "OK",
+                "content": { "application/json": { "schema": schema } },
+            }),
+        ),
+        Answer::Accepted(schema) => (
+            "202",
+            json!({
+                "description": "Started; poll the job's `url`",
+                "content": { "application/json": { "schema": schema } },
+            }),
+        ),
+        Answer::Empty => ("204", json!({ "description": "Done" })),
+    };
+
+    let mut responses = Map::new();
+    responses.insert(code.into(), ok);
+    if endpoint.work {
+        responses.insert(
+            "400".into(),
+            error("The request or its image can't be used"),
+        );
+    }
+    if endpoint.path.contains("{id}") {
+        responses.insert("404".into(), error("No such job, or it has expired"));
+    }
+    responses.insert("401".into(), error("Missing or unknown API key"));
+    if endpoint.method == "post" {
+        responses.insert(
+            "429".into(),
+            error("Rate limit or daily quota used up; see Retry-After"),
+        );
+    }
+    if endpoint.work || endpoint.path.ends_with("/result") {
+        responses.insert(
+            "503".into(),
+            error("Too busy or restarting; see Retry-After"),
+        );
+        responses.insert("504".into(), error("The deadline passed"));
+    }
+
+    let mut operation = json!({
+        "summary": endpoint.summary,
+        "parameters": parameters,
+        "responses": responses,
+    });
+    if let Some(body) = &endpoint.body {
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": { "application/json": { "schema": body } },
+        });
+    }
+    operation
+}
+
+fn document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let endpoints = endpoints(&mut gen);
+
+    let mut paths = Map::new();
+    for endpoint in &endpoints {
+        let item = paths.entry(endpoint.path).or_insert_with(|| json!({}));
+        item[endpoint.method] = operation(endpoint);
+    }
+
+    json!({
+        "openapi": "3.0.0",
+        "info": {
+            "title": "imgBot image server",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Image effects used by imgBot, for any HTTP client. Image endpoints \
+                answer once the image is made; `/jobs` runs the same work in the background.",
+        },
+        "paths": paths,
+        "components": {
+            "schemas": gen.take_definitions(),
+            "parameters": {
+                "RequestId": {
+                    "name": shared::REQUEST_ID_HEADER,
+                    "in": "header",
+                    "description": "Tags the server's logs for this request. Echoed back.",
+                    "schema": { "type": "string", "maxLength": 64 },
+                },
+            },
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+            },
+        },
+        // keys are only needed when the server has some configured
+        "security": [{}, { "bearer": [] }],
+    })
+}
+
+/// Generated once; the request types can't change while the server runs.
+static DOCUMENT: Lazy<String> = Lazy::new(|| document().to_string());
+
+#[get("/openapi.json")]
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(DOCUMENT.as_str())
+}
+
+#[get("/playground")]
+pub async fn playground() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(PLAYGROUND)
+}
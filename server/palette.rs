@@ -0,0 +1,519 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use color_quant::NeuQuant;
+use err_context::AnyError;
+use image::{Frame, RgbaImage};
+use shared::ImageError;
+
+use crate::cancel::CancelToken;
+use crate::queue::Progress;
+
+/// Roughly how many pixels a global palette is trained on. Longer animations are sampled.
+static GLOBAL_SAMPLE_PIXELS: usize = 1_000_000;
+
+static BAYER_4X4: [[f32; 4]; 4] = [
+    [0., 8., 2., 10.],
+    [12., 4., 14., 6.],
+    [3., 11., 1., 9.],
+    [15., 7., 13., 5.],
+];
+
+#[derive(serde::Deserialize, schemars::JsonSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PaletteMode {
+    /// One palette shared by every frame. Smaller, and avoids colors flickering between frames.
+    Global,
+    /// A palette per frame. Better colors for animations whose scenes change.
+    Local,
+}
+
+#[derive(serde::Deserialize, schemars::JsonSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Dither {
+    None,
+    FloydSteinberg,
+    Ordered,
+}
+
+/// Controls how animations are encoded to GIF.
+#[derive(serde::Deserialize, schemars::JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct GifOptions {
+    pub palette: PaletteMode,
+    /// Quantizer quality, from 1 (fastest) to 100 (best). Defaults to 35, the speed the
+    /// encoder always used before this was configurable.
+    pub quality: u8,
+    pub dither: Dither,
+    /// Keep transparent pixels transparent. Otherwise alpha is dropped.
+    pub transparency: bool,
+    /// How often the animation repeats. `0` loops forever, `null` plays once.
+    pub loop_count: Option<u16>,
+}
+
+impl Default for GifOptions {
+    fn default() -> Self {
+        Self {
+            palette: PaletteMode::Local,
+            quality: 35,
+            dither: Dither::None,
+            transparency: true,
+            loop_count: Some(0),
+        }
+    }
+}
+
+impl GifOptions {
+    /// NeuQuant sampling factor: 1 looks at every pixel, 30 at every 30th.
+    fn sample_factor(&self) -> i32 {
+        let quality = self.quality.clamp(1, 100) as i32;
+        1 + (100 - quality) * 29 / 99
+    }
+}
+
+enum Palette {
+    /// Few enough colors to index them exactly.
+    Exact(HashMap<[u8; 3], u8>, Vec<u8>),
+    Quantized(NeuQuant, Vec<u8>),
+}
+
+impl Palette {
+    /// Builds a palette of at most `size` colors for the opaque pixels in `pixels`.
+    fn build(pixels: &[[u8; 3]], size: usize, sample_factor: i32) -> Self {
+        let mut exact = HashMap::new();
+        for pixel in pixels {
+            if !exact.contains_key(pixel) {
+                if exact.len() == size {
+                    break;
+                }
+                exact.insert(*pixel, exact.len() as u8);
+            }
+        }
+
+        if exact.len() < size || pixels.iter().all(|p| exact.contains_key(p)) {
+            let mut rgb = vec![0u8; exact.len() * 3];
+            for (color, i) in &exact {
+                let i = *i as usize * 3;
+                rgb[i..i + 3].copy_from_slice(color);
+            }
+            return Palette::Exact(exact, rgb);
+        }
+
+        let rgba: Vec<u8> = pixels
+            .iter()
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect();
+        let quant = NeuQuant::new(sample_factor, size, &rgba);
+        let rgb = quant.color_map_rgb();
+
+        Palette::Quantized(quant, rgb)
+    }
+
+    fn rgb(&self) -> &[u8] {
+        match self {
+            Palette::Exact(_, rgb) => rgb,
+            Palette::Quantized(_, rgb) => rgb,
+        }
+    }
+
+    fn index_of(&self, pixel: [u8; 3]) -> u8 {
+        match self {
+            Palette::Exact(map, rgb) => match map.get(&pixel) {
+                Some(i) => *i,
+                None => nearest(rgb, pixel),
+            },
+            Palette::Quantized(quant, _) => quant.index_of(&[pixel[0], pixel[1], pixel[2], 255]) as u8,
+        }
+    }
+
+    fn color(&self, index: u8) -> [f32; 3] {
+        let i = index as usize * 3;
+        let rgb = self.rgb();
+        [rgb[i] as f32, rgb[i + 1] as f32, rgb[i + 2] as f32]
+    }
+
+    /// Dithering only makes sense when colors were actually lost.
+    fn is_lossy(&self) -> bool {
+        matches!(self, Palette::Quantized(_, _))
+    }
+}
+
+fn nearest(rgb: &[u8], pixel: [u8; 3]) -> u8 {
+    rgb.chunks_exact(3)
+        .enumerate()
+        .min_by_key(|(_, c)| {
+            c.iter()
+                .zip(pixel.iter())
+                .map(|(a, b)| (*a as i32 - *b as i32).pow(2))
+                .sum::<i32>()
+        })
+        .map(|(i, _)| i as u8)
+        .unwrap_or(0)
+}
+
+fn is_transparent(pixel: &[u8], options: &GifOptions) -> bool {
+    options.transparency && pixel[3] < 128
+}
+
+fn opaque_pixels<'a>(
+    image: &'a RgbaImage,
+    options: &'a GifOptions,
+    step: usize,
+) -> impl Iterator<Item = [u8; 3]> + 'a {
+    image
+        .as_raw()
+        .chunks_exact(4)
+        .step_by(step)
+        .filter(move |p| !is_transparent(p, options))
+        .map(|p| [p[0], p[1], p[2]])
+}
+
+/// Maps every pixel of `image` to a palette index, dithering as asked.
+fn index_pixels(
+    image: &RgbaImage,
+    palette: &Palette,
+    transparent: Option<u8>,
+    options: &GifOptions,
+) -> Vec<u8> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let dither = match palette.is_lossy() {
+        true => options.dither,
+        false => Dither::None,
+    };
+
+    let mut out = Vec::with_capacity(width * height);
+    // Floyd–Steinberg error for the current and next row
+    let mut error = vec![[0f32; 3]; width + 2];
+    let mut next_error = vec![[0f32; 3]; width + 2];
+
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = image.get_pixel(x as u32, y as u32).0;
+
+            if is_transparent(&pixel, options) {
+                out.push(transparent.unwrap_or(0));
+                continue;
+            }
+
+            let mut color = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32];
+            match dither {
+                Dither::None => {}
+                Dither::Ordered => {
+                    let offset = (BAYER_4X4[y % 4][x % 4] / 16. - 0.5) * 32.;
+                    color.iter_mut().for_each(|c| *c += offset);
+                }
+                Dither::FloydSteinberg => {
+                    color
+                        .iter_mut()
+                        .zip(error[x + 1].iter())
+                        .for_each(|(c, e)| *c += e);
+                }
+            }
+
+            let clamped = [
+                color[0].clamp(0., 255.) as u8,
+                color[1].clamp(0., 255.) as u8,
+                color[2].clamp(0., 255.) as u8,
+            ];
+            let index = palette.index_of(clamped);
+            out.push(index);
+
+            if dither == Dither::FloydSteinberg {
+                let chosen = palette.color(index);
+                for c in 0..3 {
+                    let e = color[c] - chosen[c];
+                    error[x + 2][c] += e * 7. / 16.;
+                    next_error[x][c] += e * 3. / 16.;
+                    next_error[x + 1][c] += e * 5. / 16.;
+                    next_error[x + 2][c] += e * 1. / 16.;
+                }
+            }
+        }
+
+        std::mem::swap(&mut error, &mut next_error);
+        next_error.iter_mut().for_each(|e| *e = [0.; 3]);
+    }
+
+    out
+}
+
+/// Pads a palette to a full 256 colors, so a transparent slot can be appended.
+fn padded(rgb: &[u8], len: usize) -> Vec<u8> {
+    let mut rgb = rgb.to_vec();
+    rgb.resize(len * 3, 0);
+    rgb
+}
+
+pub fn encode_gif(
+    frames: Vec<Frame>,
+    options: &GifOptions,
+    progress: &Progress,
+    cancel: &CancelToken,
+) -> Result<Vec<u8>, AnyError> {
+    let (width, height) = match frames.first() {
+        Some(frame) => frame.buffer().dimensions(),
+        None => return Err(ImageError::ProcessingFailure("No frames created".to_string()).into()),
+    };
+    let (width, height) = (u16::try_from(width)?, u16::try_from(height)?);
+
+    // reserve the last slot for transparency
+    let (colors, transparent) = match options.transparency {
+        true => (255, Some(255u8)),
+        false => (256, None),
+    };
+    let dispose = match options.transparency {
+        // frames are full canvases, so clear the previous one instead of drawing over it
+        true => gif::DisposalMethod::Background,
+        false => gif::DisposalMethod::Keep,
+    };
+
+    let global = match options.palette {
+        PaletteMode::Global => {
+            let total: usize = frames.iter().map(|f| f.buffer().len() / 4).sum();
+            let step = (total / GLOBAL_SAMPLE_PIXELS).max(1);
+            let sample: Vec<[u8; 3]> = frames
+                .iter()
+                .flat_map(|f| opaque_pixels(f.buffer(), options, step))
+                .collect();
+
+            Some(Palette::build(&sample, colors, options.sample_factor()))
+        }
+        PaletteMode::Local => None,
+    };
+
+    let mut out = Vec::new();
+    {
+        let global_rgb = match &global {
+            Some(palette) => padded(palette.rgb(), 256),
+            None => Vec::new(),
+        };
+        let mut encoder = gif::Encoder::new(&mut out, width, height, &global_rgb)?;
+
+        match options.loop_count {
+            Some(0) => encoder.set_repeat(gif::Repeat::Infinite)?,
+            Some(n) => encoder.set_repeat(gif::Repeat::Finite(n))?,
+            None => {}
+        }
+
+        for frame in frames {
+            cancel.check()?;
+
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            let delay = (numer / denom.max(1) / 10).min(u16::MAX as u32) as u16;
+            let (left, top) = (u16::try_from(frame.left())?, u16::try_from(frame.top())?);
+            let buffer = frame.into_buffer();
+
+            let local = match &global {
+                Some(_) => None,
+                None => {
+                    let pixels: Vec<[u8; 3]> = opaque_pixels(&buffer, options, 1).collect();
+                    Some(Palette::build(&pixels, colors, options.sample_factor()))
+                }
+            };
+            let palette = global.as_ref().or(local.as_ref()).unwrap();
+            let indices = index_pixels(&buffer, palette, transparent, options);
+
+            encoder.write_frame(&gif::Frame {
+                width: u16::try_from(buffer.width())?,
+                height: u16::try_from(buffer.height())?,
+                left,
+                top,
+                delay,
+                dispose,
+                transparent,
+                palette: local.map(|p| padded(p.rgb(), 256)),
+                buffer: Cow::Owned(indices),
+                ..gif::Frame::default()
+            })?;
+            progress.advance();
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    struct Decoded {
+        global: Option<Vec<u8>>,
+        frames: Vec<gif::Frame<'static>>,
+    }
+
+    impl Decoded {
+        /// The color of pixel `i` of `frame`, or `None` where it's transparent.
+        fn color(&self, frame: usize, i: usize) -> Option<[u8; 3]> {
+            let frame = &self.frames[frame];
+            let index = frame.buffer[i];
+            if frame.transparent == Some(index) {
+                return None;
+            }
+            let palette = frame.palette.as_ref().or(self.global.as_ref()).unwrap();
+            let i = index as usize * 3;
+            Some([palette[i], palette[i + 1], palette[i + 2]])
+        }
+    }
+
+    fn frame(width: u32, height: u32, pixel: impl Fn(u32, u32) -> [u8; 4]) -> Frame {
+        Frame::new(RgbaImage::from_fn(width, height, |x, y| Rgba(pixel(x, y))))
+    }
+
+    /// Enough colors that the palette has to be quantized.
+    fn gradient() -> Frame {
+        frame(32, 32, |x, y| {
+            [x as u8 * 8, y as u8 * 8, (x + y) as u8 * 4, 255]
+        })
+    }
+
+    fn encode(frames: Vec<Frame>, options: &GifOptions) -> Vec<u8> {
+        encode_gif(
+            frames,
+            options,
+            &Progress::default(),
+            &CancelToken::default(),
+        )
+        .unwrap()
+    }
+
+    fn decode(data: &[u8]) -> Decoded {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(data).unwrap();
+
+        let global = decoder.global_palette().map(<[u8]>::to_vec);
+        let mut frames = vec![];
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push(frame.clone());
+        }
+
+        Decoded { global, frames }
+    }
+
+    /// The repeat count of the NETSCAPE2.0 extension, if there is one.
+    fn repeat(data: &[u8]) -> Option<u16> {
+        let at = data.windows(11).position(|w| w == b"NETSCAPE2.0")?;
+        let block = &data[at + 11..at + 15];
+        assert_eq!(block[..2], [3, 1]);
+        Some(u16::from_le_bytes([block[2], block[3]]))
+    }
+
+    #[test]
+    fn shares_a_global_palette() {
+        let options = GifOptions {
+            palette: PaletteMode::Global,
+            transparency: false,
+            ..GifOptions::default()
+        };
+        let frames = vec![
+            frame(4, 4, |_, _| [255, 0, 0, 255]),
+            frame(4, 4, |_, _| [0, 0, 255, 255]),
+        ];
+
+        let gif = decode(&encode(frames, &options));
+        assert_eq!(gif.global.as_ref().map(Vec::len), Some(256 * 3));
+        assert_eq!(gif.frames.len(), 2);
+        assert!(gif.frames.iter().all(|f| f.palette.is_none()));
+        assert_eq!(gif.color(0, 0), Some([255, 0, 0]));
+        assert_eq!(gif.color(1, 15), Some([0, 0, 255]));
+    }
+
+    #[test]
+    fn gives_each_frame_its_palette() {
+        let frames = vec![
+            frame(4, 4, |x, _| [x as u8 * 60, 0, 0, 255]),
+            frame(4, 4, |_, y| [0, y as u8 * 60, 0, 255]),
+        ];
+
+        let gif = decode(&encode(frames, &GifOptions::default()));
+        // gif 0.11 always writes a global table, so only the frames' own tables can be checked
+        assert!(gif.frames.iter().all(|f| f.palette.is_some()));
+        for i in 0..16 {
+            let (x, y) = (i % 4, i / 4);
+            assert_eq!(gif.color(0, i), Some([x as u8 * 60, 0, 0]));
+            assert_eq!(gif.color(1, i), Some([0, y as u8 * 60, 0]));
+        }
+    }
+
+    #[test]
+    fn keeps_the_last_slot_for_transparency() {
+        let pixels = |x, _| match x % 2 {
+            0 => [10, 20, 30, 255],
+            _ => [200, 0, 0, 0],
+        };
+
+        let gif = decode(&encode(vec![frame(4, 2, pixels)], &GifOptions::default()));
+        assert_eq!(gif.frames[0].transparent, Some(255));
+        assert_eq!(gif.frames[0].dispose, gif::DisposalMethod::Background);
+        assert_eq!(gif.frames[0].buffer[1], 255);
+        assert_eq!(gif.color(0, 0), Some([10, 20, 30]));
+        assert_eq!(gif.color(0, 1), None);
+
+        let opaque = GifOptions {
+            transparency: false,
+            ..GifOptions::default()
+        };
+        let gif = decode(&encode(vec![frame(4, 2, pixels)], &opaque));
+        assert_eq!(gif.frames[0].transparent, None);
+        assert_eq!(gif.color(0, 1), Some([200, 0, 0]));
+    }
+
+    #[test]
+    fn writes_the_loop_count() {
+        let encoded = |loop_count| {
+            let options = GifOptions {
+                loop_count,
+                ..GifOptions::default()
+            };
+            encode(vec![frame(2, 2, |_, _| [0, 0, 0, 255])], &options)
+        };
+
+        assert_eq!(repeat(&encoded(Some(0))), Some(0));
+        assert_eq!(repeat(&encoded(Some(3))), Some(3));
+        assert_eq!(repeat(&encoded(None)), None);
+    }
+
+    #[test]
+    fn dithers_quantized_palettes() {
+        let encoded = |dither| {
+            let options = GifOptions {
+                dither,
+                ..GifOptions::default()
+            };
+            decode(&encode(vec![gradient()], &options))
+        };
+
+        let plain = encoded(Dither::None);
+        for dither in [Dither::FloydSteinberg, Dither::Ordered] {
+            let dithered = encoded(dither);
+            assert_eq!(
+                (dithered.frames[0].width, dithered.frames[0].height),
+                (32, 32)
+            );
+            assert_ne!(
+                dithered.frames[0].buffer, plain.frames[0].buffer,
+                "{:?}",
+                dither
+            );
+        }
+    }
+
+    #[test]
+    fn never_dithers_exact_palettes() {
+        let pixels = |x, y| [(x * 50) as u8, (y * 50) as u8, 0, 255];
+        for dither in [Dither::FloydSteinberg, Dither::Ordered] {
+            let options = GifOptions {
+                dither,
+                ..GifOptions::default()
+            };
+
+            let gif = decode(&encode(vec![frame(4, 4, pixels)], &options));
+            for i in 0..16 {
+                let (x, y) = (i as u32 % 4, i as u32 / 4);
+                let [r, g, b, _] = pixels(x, y);
+                assert_eq!(gif.color(0, i), Some([r, g, b]));
+            }
+        }
+    }
+}
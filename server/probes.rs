@@ -0,0 +1,226 @@
+use std::fs;
+
+use actix_web::*;
+use rusttype::Font;
+
+use crate::templates::Templates;
+use crate::AppState;
+
+/// Fraction of the memory limit in use past which a replica stops taking new work.
+static MEMORY_HIGH_WATER: f64 = 0.9;
+
+/// Whether the fonts and templates loaded, checked once at startup.
+///
+/// A bad build or templates directory would otherwise only show up as a panic in the first
+/// request that used them.
+pub struct Assets(Result<(), String>);
+
+impl Assets {
+    pub fn load(templates: &Templates) -> Self {
+        let fonts = [
+            ("caption font", templates.caption_font),
+            ("severed font", templates.severed_font),
+        ];
+        for (name, data) in fonts {
+            if Font::try_from_bytes(data).is_none() {
+                return Self(Err(format!("Cannot load {}", name)));
+            }
+        }
+
+        if let Err(e) = image::load_from_memory(templates.severed_img) {
+            return Self(Err(format!("Cannot load severed template: {}", e)));
+        }
+
+        Self(Ok(()))
+    }
+}
+
+fn read_bytes(path: &str) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Bytes of memory in use and the most that may be used, from the container's cgroup or, failing
+/// that, the whole machine.
+fn memory() -> Option<(u64, u64)> {
+    // cgroup v2, then v1. An unlimited v1 group reports an absurdly large limit.
+    let cgroup = [
+        ("/sys/fs/cgroup/memory.current", "/sys/fs/cgroup/memory.max"),
+        (
+            "/sys/fs/cgroup/memory/memory.usage_in_bytes",
+            "/sys/fs/cgroup/memory/memory.limit_in_bytes",
+        ),
+    ];
+    for (usage, limit) in cgroup {
+        if let (Some(usage), Some(limit)) = (read_bytes(usage), read_bytes(limit)) {
+            if limit < u64::MAX / 2 {
+                return Some((usage, limit));
+            }
+        }
+    }
+
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|rest| rest.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+    };
+    let total = field("MemTotal:")?;
+    let available = field("MemAvailable:")?;
+
+    Some((total.saturating_sub(available), total))
+}
+
+#[derive(serde::Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl Check {
+    fn from(result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => Self { ok: true, reason: None },
+            Err(reason) => Self {
+                ok: false,
+                reason: Some(reason),
+            },
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Readiness {
+    ready: bool,
+    draining: Check,
+    queue: Check,
+    assets: Check,
+    memory: Check,
+}
+
+/// Answers as long as the server can handle requests at all. Restarting won't fix a busy
+/// server, so nothing else is checked.
+#[get("/livez")]
+pub async fn livez() -> HttpResponse {
+    HttpResponse::Ok().body("200 OK")
+}
+
+/// Whether this replica should be sent new work.
+#[get("/readyz")]
+pub async fn readyz(data: web::Data<AppState>) -> HttpResponse {
+    let draining = match data.queue.is_draining() {
+        true => Err("Shutting down".to_string()),
+        false => Ok(()),
+    };
+
+    let queue = data.queue.status();
+    let queue = match queue.running >= queue.max_running && queue.queued >= queue.max_queued {
+        true => Err(format!(
+            "Queue is full ({} running, {} queued)",
+            queue.running, queue.queued
+        )),
+        false => Ok(()),
+    };
+
+    let memory = match memory() {
+        Some((used, limit)) if used as f64 > limit as f64 * MEMORY_HIGH_WATER => Err(format!(
+            "Using {}MiB of {}MiB",
+            used / (1024 * 1024),
+            limit / (1024 * 1024)
+        )),
+        // not knowing is no reason to turn traffic away
+        _ => Ok(()),
+    };
+
+    let (draining, queue, assets, memory) = (
+        Check::from(draining),
+        Check::from(queue),
+        Check::from(data.assets.0.clone()),
+        Check::from(memory),
+    );
+    let readiness = Readiness {
+        ready: draining.ok && queue.ok && assets.ok && memory.ok,
+        draining,
+        queue,
+        assets,
+        memory,
+    };
+
+    match readiness.ready {
+        true => HttpResponse::Ok().json(readiness),
+        false => HttpResponse::ServiceUnavailable().json(readiness),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{readyz, Assets};
+    use crate::cancel::CancelToken;
+    use crate::config::Config;
+    use crate::AppState;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App};
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    async fn ready(data: web::Data<AppState>) -> (StatusCode, Value) {
+        let app = init_service(App::new().app_data(data).service(readyz)).await;
+        let response = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+        let status = response.status();
+
+        (status, read_body_json(response).await)
+    }
+
+    #[actix_rt::test]
+    async fn is_ready_by_default() {
+        let (_, readiness) = ready(AppState::for_tests(Config::default())).await;
+
+        // memory depends on the machine the tests run on
+        for check in ["draining", "queue", "assets"] {
+            assert_eq!(readiness[check]["ok"], true, "{}", check);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn is_unready_while_draining() {
+        let data = AppState::for_tests(Config::default());
+        data.queue.drain();
+        let (status, readiness) = ready(data).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(readiness["ready"], false);
+        assert_eq!(readiness["draining"]["reason"], "Shutting down");
+    }
+
+    #[actix_rt::test]
+    async fn is_unready_when_the_queue_is_full() {
+        let mut config = Config::default();
+        config.limits.max_running = Some(1);
+        config.limits.max_queued = 0;
+        let data = AppState::for_tests(config);
+        let _job = data
+            .queue
+            .enter(Arc::default(), CancelToken::default())
+            .await
+            .unwrap();
+        let (status, readiness) = ready(data).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(readiness["queue"]["ok"], false);
+        assert_eq!(readiness["draining"]["ok"], true);
+    }
+
+    #[actix_rt::test]
+    async fn is_unready_without_assets() {
+        let mut data = AppState::for_tests(Config::default()).into_inner();
+        let missing = Assets(Err("Cannot load caption font".to_string()));
+        Arc::get_mut(&mut data).unwrap().assets = missing;
+        let (status, readiness) = ready(web::Data::from(data)).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(readiness["assets"]["reason"], "Cannot load caption font");
+    }
+}
@@ -0,0 +1,517 @@
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use futures::future::{self, Either};
+use shared::ImageError;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::cancel::{self, CancelToken};
+use crate::config::Config;
+use crate::metrics::METRICS;
+use crate::AppState;
+
+/// Seconds a client is told to wait before retrying a rejected job.
+static RETRY_AFTER_SECS: u64 = 5;
+
+/// Server-wide scheduler for image jobs.
+///
+/// At most `max_running` jobs process at once; up to `max_queued` more wait their turn in
+/// order. Anything past that is turned away with a 503, instead of piling onto the blocking
+/// pool and stalling every other request.
+///
+/// Once draining for shutdown, every new job is turned away.
+pub struct JobQueue {
+    slots: Arc<Semaphore>,
+    max_running: usize,
+    max_queued: usize,
+    frame_concurrency: usize,
+    temp_dir: PathBuf,
+    next_ticket: AtomicU64,
+    waiting: Mutex<BTreeSet<u64>>,
+    /// Every job waiting or running, so they can be stopped on shutdown.
+    active: Arc<Mutex<HashMap<u64, CancelToken>>>,
+    next_id: AtomicU64,
+    draining: AtomicBool,
+}
+
+#[derive(serde::Serialize, schemars::JsonSchema)]
+pub struct QueueStatus {
+    pub running: usize,
+    pub queued: usize,
+    pub max_running: usize,
+    pub max_queued: usize,
+    pub draining: bool,
+}
+
+impl JobQueue {
+    pub fn new(config: &Config) -> Self {
+        let max_running = config.max_running();
+        Self {
+            slots: Arc::new(Semaphore::new(max_running)),
+            max_running,
+            max_queued: config.limits.max_queued,
+            frame_concurrency: config.limits.frame_concurrency.max(1),
+            temp_dir: config.temp_dir.clone(),
+            next_ticket: AtomicU64::new(1),
+            waiting: Mutex::new(BTreeSet::new()),
+            active: Arc::default(),
+            next_id: AtomicU64::new(0),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        QueueStatus {
+            running: self.max_running - self.slots.available_permits(),
+            queued: self.waiting.lock().unwrap().len(),
+            max_running: self.max_running,
+            max_queued: self.max_queued,
+            draining: self.is_draining(),
+        }
+    }
+
+    /// Turns away every job from now on, ahead of shutting down.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Jobs waiting or running.
+    pub fn active(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+
+    /// Stops every job still waiting or running with [`ImageError::ShuttingDown`]. Returns how
+    /// many were stopped.
+    pub fn abort_all(&self) -> usize {
+        let active = self.active.lock().unwrap();
+        for cancel in active.values() {
+            cancel.shut_down();
+        }
+        active.len()
+    }
+
+    /// Fails with [`ImageError::Busy`] if a new job would be turned away right now, or
+    /// [`ImageError::ShuttingDown`] if the server is draining.
+    pub fn check_capacity(&self) -> Result<(), ImageError> {
+        if self.is_draining() {
+            return Err(ImageError::ShuttingDown);
+        }
+
+        let full = self.slots.available_permits() == 0
+            && self.waiting.lock().unwrap().len() >= self.max_queued;
+
+        match full {
+            true => Err(ImageError::Busy(RETRY_AFTER_SECS)),
+            false => Ok(()),
+        }
+    }
+
+    /// How many jobs are waiting ahead of `ticket`, or `None` if it isn't waiting.
+    pub fn ahead_of(&self, ticket: u64) -> Option<usize> {
+        let waiting = self.waiting.lock().unwrap();
+        match waiting.contains(&ticket) {
+            true => Some(waiting.range(..ticket).count()),
+            false => None,
+        }
+    }
+
+    /// Waits for a free slot, or fails straight away if the queue is full. The job reports
+    /// into `progress`, including its place in the queue while it waits, and stops when
+    /// `cancel` says so.
+    pub async fn enter(
+        &self,
+        progress: Arc<Progress>,
+        cancel: CancelToken,
+    ) -> Result<Job, ImageError> {
+        if self.is_draining() {
+            return Err(ImageError::ShuttingDown);
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.active.lock().unwrap().insert(id, cancel.clone());
+        let active = Active(self.active.clone(), id);
+
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Ok(self.job(permit, active, progress, cancel));
+        }
+
+        let ticket = {
+            let mut waiting = self.waiting.lock().unwrap();
+            if waiting.len() >= self.max_queued {
+                return Err(ImageError::Busy(RETRY_AFTER_SECS));
+            }
+
+            let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+            waiting.insert(ticket);
+            ticket
+        };
+        progress.ticket.store(ticket, Ordering::Relaxed);
+
+        // leave the queue even if this future is dropped while waiting
+        let _leave = Leave(self, ticket);
+
+        let acquire = Box::pin(self.slots.clone().acquire_owned());
+        let stopped = Box::pin(cancel.stopped());
+        let permit = match future::select(acquire, stopped).await {
+            Either::Left((permit, _)) => {
+                permit.map_err(|e| ImageError::ProcessingFailure(e.to_string()))?
+            }
+            Either::Right((e, _)) => return Err(e),
+        };
+
+        Ok(self.job(permit, active, progress, cancel))
+    }
+
+    fn job(
+        &self,
+        permit: OwnedSemaphorePermit,
+        active: Active,
+        progress: Arc<Progress>,
+        cancel: CancelToken,
+    ) -> Job {
+        progress.ticket.store(0, Ordering::Relaxed);
+        Job {
+            _permit: permit,
+            _active: active,
+            frame_concurrency: self.frame_concurrency,
+            temp_dir: self.temp_dir.clone(),
+            progress,
+            cancel,
+        }
+    }
+}
+
+/// A job's entry in [`JobQueue::active`], removed once dropped.
+struct Active(Arc<Mutex<HashMap<u64, CancelToken>>>, u64);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().remove(&self.1);
+    }
+}
+
+struct Leave<'a>(&'a JobQueue, u64);
+
+impl Drop for Leave<'_> {
+    fn drop(&mut self) {
+        self.0.waiting.lock().unwrap().remove(&self.1);
+    }
+}
+
+#[derive(serde::Serialize, schemars::JsonSchema, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    #[default]
+    Processing,
+    Encoding,
+}
+
+/// How far along a job is: which stage it's in, and how many of that stage's frames are done.
+#[derive(Default)]
+pub struct Progress {
+    /// Queue ticket while the job waits for a slot, `0` otherwise.
+    ticket: AtomicU64,
+    stage: Mutex<Stage>,
+    done: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl Progress {
+    pub fn ticket(&self) -> Option<u64> {
+        match self.ticket.load(Ordering::Relaxed) {
+            0 => None,
+            ticket => Some(ticket),
+        }
+    }
+
+    /// Starts a stage of `frames` frames.
+    pub fn begin(&self, stage: Stage, frames: usize) {
+        *self.stage.lock().unwrap() = stage;
+        self.done.store(0, Ordering::Relaxed);
+        self.total.store(frames, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The current stage, with its frames done and total.
+    pub fn snapshot(&self) -> (Stage, usize, usize) {
+        (
+            *self.stage.lock().unwrap(),
+            self.done.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// A running image job. Holds its slot in the [`JobQueue`] until dropped.
+///
+/// Taking a `Job` as a handler argument queues the request. The job is cancelled once
+/// dropped, so a client hanging up stops any frames still waiting to be worked on.
+pub struct Job {
+    _permit: OwnedSemaphorePermit,
+    _active: Active,
+    /// How many frames of this job may be worked on at once.
+    pub frame_concurrency: usize,
+    /// Where this job's output may be written while it is encoded.
+    pub temp_dir: PathBuf,
+    pub progress: Arc<Progress>,
+    pub cancel: CancelToken,
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+/// The status a failed job is answered with.
+pub fn status_code(e: &ImageError) -> StatusCode {
+    match e {
+        ImageError::Busy(_) | ImageError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+        ImageError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// Seconds a client should wait before retrying after `e`, if it is worth retrying at all.
+pub fn retry_after(e: &ImageError) -> Option<u64> {
+    match e {
+        ImageError::Busy(secs) => Some(*secs),
+        // another replica can take it straight away
+        ImageError::ShuttingDown => Some(1),
+        _ => None,
+    }
+}
+
+/// An [`ImageError`] turned into a response. Busy servers answer 503 with `Retry-After`.
+#[derive(Debug)]
+pub struct JobError(pub ImageError);
+
+impl std::fmt::Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl ResponseError for JobError {
+    fn status_code(&self) -> StatusCode {
+        status_code(&self.0)
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        METRICS.error(&self.0);
+        let mut response = HttpResponse::build(self.status_code());
+        if let Some(secs) = retry_after(&self.0) {
+            response.append_header(("Retry-After", secs.to_string()));
+        }
+        response.body(self.0.to_string())
+    }
+}
+
+impl FromRequest for Job {
+    type Error = JobError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let state = match req.app_data::<web::Data<AppState>>() {
+            Some(data) => CancelToken::from_request(req, &data.config.limits)
+                .map(|cancel| (data.clone(), cancel)),
+            None => Err(ImageError::ProcessingFailure("No app state".to_string())),
+        };
+        if let Ok((_, cancel)) = &state {
+            cancel::watch_peer(req, cancel.clone());
+        }
+
+        Box::pin(async move {
+            let (data, cancel) = state.map_err(JobError)?;
+
+            let job = data.queue.enter(Arc::default(), cancel.clone()).await;
+            if job.is_err() {
+                // stop watching the connection
+                cancel.cancel();
+            }
+
+            job.map_err(JobError)
+        })
+    }
+}
+
+#[actix_web::get("/queue")]
+pub async fn queue_status(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(data.queue.status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn queue(max_running: usize, max_queued: usize) -> Arc<JobQueue> {
+        let mut config = Config::default();
+        config.limits.max_running = Some(max_running);
+        config.limits.max_queued = max_queued;
+        Arc::new(JobQueue::new(&config))
+    }
+
+    async fn settle() {
+        actix_rt::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    #[actix_rt::test]
+    async fn runs_waiting_jobs_in_order() {
+        let queue = queue(1, 3);
+        let first = queue
+            .enter(Arc::default(), CancelToken::default())
+            .await
+            .unwrap();
+
+        let started = Arc::new(Mutex::new(vec![]));
+        let mut progress = vec![];
+        for n in 0..3 {
+            let (queue, started) = (queue.clone(), started.clone());
+            let p = Arc::new(Progress::default());
+            progress.push(p.clone());
+            actix_rt::spawn(async move {
+                let _job = queue.enter(p, CancelToken::default()).await.unwrap();
+                started.lock().unwrap().push(n);
+            });
+            settle().await;
+        }
+
+        let tickets: Vec<u64> = progress.iter().map(|p| p.ticket().unwrap()).collect();
+        assert!(tickets.windows(2).all(|t| t[0] < t[1]));
+        let ahead: Vec<_> = tickets.iter().map(|t| queue.ahead_of(*t)).collect();
+        assert_eq!(ahead, [Some(0), Some(1), Some(2)]);
+        assert_eq!(queue.status().queued, 3);
+        assert!(started.lock().unwrap().is_empty());
+
+        drop(first);
+        settle().await;
+        assert_eq!(*started.lock().unwrap(), [0, 1, 2]);
+        assert!(tickets.iter().all(|t| queue.ahead_of(*t).is_none()));
+        assert!(progress.iter().all(|p| p.ticket().is_none()));
+        assert_eq!(queue.status().queued, 0);
+    }
+
+    #[actix_rt::test]
+    async fn turns_jobs_away_once_full() {
+        let queue = queue(1, 1);
+        let _running = queue
+            .enter(Arc::default(), CancelToken::default())
+            .await
+            .unwrap();
+        assert!(queue.check_capacity().is_ok());
+
+        let waiting = queue.clone();
+        actix_rt::spawn(async move {
+            let _ = waiting.enter(Arc::default(), CancelToken::default()).await;
+        });
+        settle().await;
+
+        assert!(matches!(queue.check_capacity(), Err(ImageError::Busy(5))));
+        let e = match queue.enter(Arc::default(), CancelToken::default()).await {
+            Err(e) => e,
+            Ok(_) => panic!("a full queue should turn jobs away"),
+        };
+        assert!(matches!(e, ImageError::Busy(5)));
+
+        let response = JobError(e).error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers().get("Retry-After").unwrap(),
+            &RETRY_AFTER_SECS.to_string()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn leaves_the_queue_when_dropped() {
+        let queue = queue(1, 2);
+        let _running = queue
+            .enter(Arc::default(), CancelToken::default())
+            .await
+            .unwrap();
+
+        let progress = Arc::new(Progress::default());
+        let waiting = {
+            let (queue, progress) = (queue.clone(), progress.clone());
+            actix_rt::spawn(async move {
+                let _ = queue.enter(progress, CancelToken::default()).await;
+            })
+        };
+        settle().await;
+        let ticket = progress.ticket().unwrap();
+        assert_eq!(queue.ahead_of(ticket), Some(0));
+
+        waiting.abort();
+        settle().await;
+        assert_eq!(queue.ahead_of(ticket), None);
+        assert_eq!(queue.status().queued, 0);
+    }
+
+    #[actix_rt::test]
+    async fn turns_jobs_away_while_draining() {
+        let queue = queue(1, 1);
+        queue.drain();
+
+        assert!(queue.status().draining);
+        assert!(matches!(
+            queue.check_capacity(),
+            Err(ImageError::ShuttingDown)
+        ));
+        assert!(matches!(
+            queue.enter(Arc::default(), CancelToken::default()).await,
+            Err(ImageError::ShuttingDown)
+        ));
+        assert_eq!(queue.active(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn aborts_jobs_with_a_retryable_error() {
+        let queue = queue(1, 1);
+        let running = queue
+            .enter(Arc::default(), CancelToken::default())
+            .await
+            .unwrap();
+        let waiting = {
+            let queue = queue.clone();
+            actix_rt::spawn(async move {
+                queue
+                    .enter(Arc::default(), CancelToken::default())
+                    .await
+                    .map(|_| ())
+            })
+        };
+        settle().await;
+        assert_eq!(queue.active(), 2);
+
+        queue.drain();
+        assert_eq!(queue.abort_all(), 2);
+
+        let e = running.cancel.check().unwrap_err();
+        assert!(matches!(e, ImageError::ShuttingDown));
+        assert!(matches!(
+            waiting.await.unwrap(),
+            Err(ImageError::ShuttingDown)
+        ));
+
+        let response = JobError(e).error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "1");
+
+        drop(running);
+        assert_eq!(queue.active(), 0);
+    }
+}
@@ -1,24 +1,26 @@
 use crate::font::{DrawableFont, HorizontalGravity, VerticalGravity};
 use crate::images::ExploitableImageRequest;
-use crate::{images};
+use crate::palette::GifOptions;
+use crate::queue::Job;
+use crate::{images, AppState};
 use actix_web::*;
 use bytes::Bytes;
 use image::{DynamicImage, Rgba};
 use rusttype::{Scale};
 use shared::ImageError;
 
-static SEVERED_FONT: &[u8] = include_bytes!("pack/severed.ttf");
-static SEVERED_IMG: &[u8] = include_bytes!("pack/severed.png");
-
-#[post("/severed")]
-pub async fn severed(request: web::Json<ExploitableImageRequest>) -> Result<HttpResponse> {
-    let image = Bytes::from(SEVERED_IMG);
-    let font = DrawableFont::from(SEVERED_FONT);
+pub async fn run(
+    data: &AppState,
+    job: &Job,
+    request: &ExploitableImageRequest,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    let image = Bytes::from(data.templates.severed_img);
+    let font = DrawableFont::from(data.templates.severed_font);
 
     let text = request.text.clone();
     let scale = Scale { x: 102., y: 102. };
 
-    let result = images::process(image, move |img| {
+    images::process(job, image, GifOptions::default(), move |img| {
         let mut img = img.into_rgba8();
 
         let mut font = font.lock().unwrap();
@@ -35,17 +37,14 @@ pub async fn severed(request: web::Json<ExploitableImageRequest>) -> Result<Http
 
         Ok(DynamicImage::ImageRgba8(img))
     })
-    .await;
-
-    if let Err(e) = result {
-        return Ok(
-            HttpResponse::BadRequest().body(format!("Failed to modify image. {}", e.to_string()))
-        );
-    }
-
-    let (result, _) = result.unwrap();
+    .await
+}
 
-    Ok(HttpResponse::Ok()
-        .content_type(mime::IMAGE_PNG)
-        .body(result))
+#[post("/severed")]
+pub async fn severed(
+    request: web::Json<ExploitableImageRequest>,
+    data: web::Data<AppState>,
+    job: Job,
+) -> Result<HttpResponse> {
+    images::respond(run(&data, &job, &request).await)
 }
@@ -0,0 +1,62 @@
+use std::time::{Duration, Instant};
+
+use actix_web::dev::ServerHandle;
+use actix_web::{rt, web};
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// How often draining checks whether the last jobs have finished.
+static DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+/// Time given to clients polling `/jobs` to collect results and errors before the server stops.
+pub static COLLECT_WINDOW: Duration = Duration::from_secs(2);
+/// Time actix gets to close connections once stopped, after the collect window.
+pub static STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn terminated() {
+    #[cfg(unix)]
+    {
+        use rt::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                futures::future::select(Box::pin(terminate.recv()), Box::pin(rt::signal::ctrl_c()))
+                    .await;
+            }
+            Err(_) => {
+                let _ = rt::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = rt::signal::ctrl_c().await;
+}
+
+/// Waits for SIGTERM or ctrl-c, then shuts the server down without dropping jobs on the floor.
+///
+/// New jobs are turned away and `/readyz` fails straight away, so traffic moves to other
+/// replicas. Jobs already running get until the grace period ends to finish; any left after
+/// that are stopped with a retryable error.
+pub async fn on_signal(data: web::Data<AppState>, server: ServerHandle) {
+    terminated().await;
+
+    let grace = data.config.shutdown_grace();
+    info!(active = data.queue.active(), grace_secs = grace.as_secs(), "Draining jobs");
+    data.queue.drain();
+
+    let deadline = Instant::now() + grace;
+    while data.queue.active() > 0 && Instant::now() < deadline {
+        rt::time::sleep(DRAIN_CHECK_INTERVAL).await;
+    }
+
+    let aborted = data.queue.abort_all();
+    if aborted > 0 {
+        warn!(aborted, "Stopped jobs still running at the end of the grace period");
+    }
+
+    rt::time::sleep(COLLECT_WINDOW).await;
+
+    info!("Stopping server");
+    server.stop(true).await;
+}
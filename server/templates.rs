@@ -0,0 +1,44 @@
+use std::fs;
+use std::path::Path;
+
+use shared::ConfigError;
+
+static CAPTION_FONT: &[u8] = include_bytes!("pack/caption.otf");
+static SEVERED_FONT: &[u8] = include_bytes!("pack/severed.ttf");
+static SEVERED_IMG: &[u8] = include_bytes!("pack/severed.png");
+
+/// Fonts and template images used to draw on images.
+///
+/// Built into the binary, but each can be replaced by a file of the same name in the
+/// configured templates directory.
+pub struct Templates {
+    pub caption_font: &'static [u8],
+    pub severed_font: &'static [u8],
+    pub severed_img: &'static [u8],
+}
+
+/// Reads `name` from `dir`, falling back to `builtin` if it isn't there.
+fn load(dir: Option<&Path>, name: &str, builtin: &'static [u8]) -> Result<&'static [u8], ConfigError> {
+    let path = match dir {
+        Some(dir) => dir.join(name),
+        None => return Ok(builtin),
+    };
+    if !path.exists() {
+        return Ok(builtin);
+    }
+
+    // loaded once at startup and used for the life of the server
+    fs::read(&path)
+        .map(|bytes| &*Box::leak(bytes.into_boxed_slice()))
+        .map_err(|e| ConfigError::Unreadable(path.display().to_string(), e.to_string()))
+}
+
+impl Templates {
+    pub fn load(dir: Option<&Path>) -> Result<Self, ConfigError> {
+        Ok(Self {
+            caption_font: load(dir, "caption.otf", CAPTION_FONT)?,
+            severed_font: load(dir, "severed.ttf", SEVERED_FONT)?,
+            severed_img: load(dir, "severed.png", SEVERED_IMG)?,
+        })
+    }
+}
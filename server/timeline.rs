@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+use actix_web::*;
+use image::imageops::FilterType;
+use image::{imageops, Delay, DynamicImage, Frame, RgbaImage};
+use shared::{FrameRange, ImageError};
+
+use crate::images::{ConcatImageRequest, TimelineImageRequest};
+use crate::queue::Job;
+use crate::{images, AppState};
+
+/// How long a still image lasts when it is put into an animation.
+static STILL_DELAY_MS: u32 = 1000;
+
+fn delay_duration(delay: Delay) -> Duration {
+    let (numer, denom) = delay.numer_denom_ms();
+    Duration::from_secs_f64(numer as f64 / denom.max(1) as f64 / 1000.)
+}
+
+pub fn reverse(mut frames: Vec<Frame>) -> Vec<Frame> {
+    frames.reverse();
+    frames
+}
+
+/// Plays the frames forwards, then backwards, without repeating either end.
+pub fn boomerang(frames: Vec<Frame>) -> Vec<Frame> {
+    let len = frames.len();
+    let back: Vec<Frame> = frames
+        .iter()
+        .rev()
+        .skip(1)
+        .take(len.saturating_sub(2))
+        .cloned()
+        .collect();
+
+    frames.into_iter().chain(back).collect()
+}
+
+pub fn trim(frames: Vec<Frame>, range: &FrameRange) -> Result<Vec<Frame>, ImageError> {
+    let delays: Vec<Duration> = frames.iter().map(|f| delay_duration(f.delay())).collect();
+    let range = range
+        .resolve(&delays)
+        .map_err(|e| ImageError::BadRequest(e.to_string()))?;
+
+    Ok(frames
+        .into_iter()
+        .skip(range.start)
+        .take(range.end - range.start)
+        .collect())
+}
+
+/// Appends `other` to `frames`. Frames of `other` are scaled to fit the first animation's
+/// canvas, as a GIF can only have one size.
+pub fn concat(frames: Vec<Frame>, other: Vec<Frame>) -> Vec<Frame> {
+    let (width, height) = match frames.first() {
+        Some(frame) => frame.buffer().dimensions(),
+        None => return other,
+    };
+
+    let fitted = other.into_iter().map(|frame| {
+        let delay = frame.delay();
+        let buffer = frame.into_buffer();
+        if buffer.dimensions() == (width, height) {
+            return Frame::from_parts(buffer, 0, 0, delay);
+        }
+
+        let scaled = DynamicImage::ImageRgba8(buffer)
+            .resize(width, height, FilterType::Triangle)
+            .into_rgba8();
+        let mut canvas = RgbaImage::new(width, height);
+        imageops::overlay(
+            &mut canvas,
+            &scaled,
+            (width - scaled.width()) / 2,
+            (height - scaled.height()) / 2,
+        );
+
+        Frame::from_parts(canvas, 0, 0, delay)
+    });
+
+    frames.into_iter().chain(fitted).collect()
+}
+
+/// Gives a still image a visible duration, so it survives being put into an animation.
+fn hold_still(frames: Vec<Frame>, is_gif: bool) -> Vec<Frame> {
+    if is_gif {
+        return frames;
+    }
+
+    frames
+        .into_iter()
+        .map(|f| {
+            Frame::from_parts(
+                f.into_buffer(),
+                0,
+                0,
+                Delay::from_numer_denom_ms(STILL_DELAY_MS, 1),
+            )
+        })
+        .collect()
+}
+
+async fn fetch(data: &AppState, url: &String) -> Result<(Vec<Frame>, bool), ImageError> {
+    let bytes = images::source_bytes(data, url).await?;
+    images::decode(bytes).await
+}
+
+pub async fn run_reverse(
+    data: &AppState,
+    job: &Job,
+    request: &TimelineImageRequest,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    let (frames, is_gif) = fetch(data, &request.target_url).await?;
+    images::encode(job, reverse(frames), is_gif, request.gif.clone()).await
+}
+
+pub async fn run_boomerang(
+    data: &AppState,
+    job: &Job,
+    request: &TimelineImageRequest,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    let (frames, is_gif) = fetch(data, &request.target_url).await?;
+    images::encode(job, boomerang(frames), is_gif, request.gif.clone()).await
+}
+
+pub async fn run_trim(
+    data: &AppState,
+    job: &Job,
+    request: &TimelineImageRequest,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    let range = request
+        .range
+        .as_ref()
+        .ok_or_else(|| ImageError::BadRequest("No range provided".to_string()))?
+        .parse::<FrameRange>()
+        .map_err(|e| ImageError::BadRequest(e.to_string()))?;
+
+    let (frames, is_gif) = fetch(data, &request.target_url).await?;
+    images::encode(job, trim(frames, &range)?, is_gif, request.gif.clone()).await
+}
+
+pub async fn run_concat(
+    data: &AppState,
+    job: &Job,
+    request: &ConcatImageRequest,
+) -> Result<(Vec<u8>, bool), ImageError> {
+    let (frames, is_gif) = fetch(data, &request.target_url).await?;
+    let (other, other_is_gif) = fetch(data, &request.other_url).await?;
+
+    let frames = concat(
+        hold_still(frames, is_gif),
+        hold_still(other, other_is_gif),
+    );
+
+    images::encode(job, frames, true, request.gif.clone()).await
+}
+
+#[post("/reverse")]
+pub async fn reverse_gif(
+    request: web::Json<TimelineImageRequest>,
+    data: web::Data<AppState>,
+    job: Job,
+) -> Result<HttpResponse> {
+    images::respond(run_reverse(&data, &job, &request).await)
+}
+
+#[post("/boomerang")]
+pub async fn boomerang_gif(
+    request: web::Json<TimelineImageRequest>,
+    data: web::Data<AppState>,
+    job: Job,
+) -> Result<HttpResponse> {
+    images::respond(run_boomerang(&data, &job, &request).await)
+}
+
+#[post("/trim")]
+pub async fn trim_gif(
+    request: web::Json<TimelineImageRequest>,
+    data: web::Data<AppState>,
+    job: Job,
+) -> Result<HttpResponse> {
+    images::respond(run_trim(&data, &job, &request).await)
+}
+
+#[post("/concat")]
+pub async fn concat_gif(
+    request: web::Json<ConcatImageRequest>,
+    data: web::Data<AppState>,
+    job: Job,
+) -> Result<HttpResponse> {
+    images::respond(run_concat(&data, &job, &request).await)
+}
@@ -0,0 +1,73 @@
+use std::future::Future;
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::Error;
+use shared::REQUEST_ID_HEADER;
+use tracing::{debug, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+/// Paths polled by k8s and Prometheus, which would drown out everything else at `info`.
+static QUIET_PATHS: &[&str] = &["/health", "/livez", "/readyz", "/metrics"];
+
+/// Takes the bot's id for a request, as long as it looks like one.
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 64)
+        .filter(|id| id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Middleware running each request in a span tagged with its request id, and logging how it was
+/// answered. The id is echoed back in the response. Used with `wrap_fn`.
+pub fn request_span<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    let start = Instant::now();
+    let request_id = request_id(&req);
+    let quiet = QUIET_PATHS.contains(&req.path());
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.path(),
+        // filled in once the request's API key is known
+        client = tracing::field::Empty,
+    );
+    let response = span.in_scope(|| srv.call(req));
+
+    async move {
+        let mut response = response.await;
+        let elapsed_ms = start.elapsed().as_millis() as u64;
+
+        match &mut response {
+            Ok(response) => {
+                let status = response.status().as_u16();
+                match quiet {
+                    true => debug!(status, elapsed_ms, "answered"),
+                    false => info!(status, elapsed_ms, "answered"),
+                }
+
+                if let Ok(id) = HeaderValue::from_str(&request_id) {
+                    response
+                        .headers_mut()
+                        .insert(HeaderName::from_static("x-request-id"), id);
+                }
+            }
+            Err(e) => warn!(error = %e, elapsed_ms, "failed"),
+        }
+
+        response
+    }
+    .instrument(span)
+}
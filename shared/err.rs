@@ -8,6 +8,8 @@ pub enum CommandError {
     StringError(String),
     SourcedError(&'static str, AnyError),
     UnhealthyServers,
+    /// The author lacks a guild permission, named for humans.
+    MissingPermission(&'static str),
 }
 
 impl Display for CommandError {
@@ -22,6 +24,9 @@ impl Display for CommandError {
             CommandError::UnhealthyServers => {
                 f.write_str("Image servers are unavailable - try again in a few minutes.")
             }
+            CommandError::MissingPermission(name) => {
+                write!(f, "You need the {} permission to do that.", name)
+            }
         }
     }
 }
@@ -41,6 +46,30 @@ pub enum ImageError {
     BadImage(String),
     ProcessingFailure(String),
     FontLoadFailure,
+    /// The server is at capacity; retry after this many seconds.
+    Busy(u64),
+    /// The request's deadline passed before it finished.
+    Timeout,
+    /// The job was stopped because its result is no longer wanted.
+    Cancelled,
+    /// The server is shutting down; the job can be retried on another replica.
+    ShuttingDown,
+}
+
+impl ImageError {
+    /// A short, stable name for the kind of error, e.g. for metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageError::BadRequest(_) => "bad_request",
+            ImageError::BadImage(_) => "bad_image",
+            ImageError::ProcessingFailure(_) => "processing_failure",
+            ImageError::FontLoadFailure => "font_load_failure",
+            ImageError::Busy(_) => "busy",
+            ImageError::Timeout => "timeout",
+            ImageError::Cancelled => "cancelled",
+            ImageError::ShuttingDown => "shutting_down",
+        }
+    }
 }
 
 impl Display for ImageError {
@@ -53,6 +82,14 @@ impl Display for ImageError {
                 f.write_str(format!("Failed to modify image: {}", str).as_str())
             }
             ImageError::FontLoadFailure => f.write_str("Font load failure"),
+            ImageError::Busy(secs) => f.write_str(
+                format!("Image server is busy, try again in {}s", secs).as_str(),
+            ),
+            ImageError::Timeout => f.write_str("Image took too long to process"),
+            ImageError::Cancelled => f.write_str("Job was cancelled"),
+            ImageError::ShuttingDown => {
+                f.write_str("Image server is restarting, try again in a moment")
+            }
         }
     }
 }
@@ -60,31 +97,130 @@ impl Display for ImageError {
 impl Error for ImageError {}
 
 #[derive(Debug)]
-pub enum TenorError {
-    Unavailable,
-    NoProcessed,
+pub enum ProviderError {
+    /// The provider needs an API key that isn't configured, named for humans.
+    Unavailable(&'static str),
     InvalidLink,
+    NoMedia,
+    /// The link leads to this machine or its network.
+    PrivateAddress,
     BadResponse(&'static str),
     RequestError(AnyError),
     CannotParse(AnyError),
 }
 
-impl Display for TenorError {
+impl Display for ProviderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("provider error: ")?;
+        match self {
+            ProviderError::Unavailable(name) => write!(f, "{} API is unavailable", name),
+            ProviderError::InvalidLink => f.write_str("Cannot find the GIF in this link"),
+            ProviderError::NoMedia => f.write_str("The page has no image or video"),
+            ProviderError::PrivateAddress => {
+                f.write_str("Links to private addresses are not allowed")
+            }
+            ProviderError::BadResponse(str) => write!(f, "Bad response: {}", str),
+            ProviderError::RequestError(e) => write!(f, "Request failed: {:#?}", e),
+            ProviderError::CannotParse(e) => write!(f, "Cannot parse response: {:#?}", e),
+        }
+    }
+}
+
+impl Error for ProviderError {}
+
+#[derive(Debug)]
+pub enum RangeError {
+    Empty,
+    BadBound(String),
+    Backwards,
+    OutOfBounds(usize),
+}
+
+impl Display for RangeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("range error: ")?;
+        match self {
+            RangeError::Empty => f.write_str("Empty range"),
+            RangeError::BadBound(str) => f.write_str(
+                format!("Cannot parse `{}`, expected a frame (5) or time (1.2s, 500ms)", str)
+                    .as_str(),
+            ),
+            RangeError::Backwards => f.write_str("Range ends before it starts"),
+            RangeError::OutOfBounds(len) => {
+                f.write_str(format!("Range starts after the last frame ({} frames)", len).as_str())
+            }
+        }
+    }
+}
+
+impl Error for RangeError {}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file at this path couldn't be read.
+    Unreadable(String, String),
+    /// The config file at this path isn't valid.
+    Malformed(String, String),
+    /// An environment variable override couldn't be parsed.
+    BadEnv(String, String),
+    /// Settings that parsed, but make no sense.
+    Invalid(Vec<String>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("config error: ")?;
+        match self {
+            ConfigError::Unreadable(path, e) => {
+                f.write_str(format!("Cannot read {}: {}", path, e).as_str())
+            }
+            ConfigError::Malformed(path, e) => {
+                f.write_str(format!("Cannot parse {}: {}", path, e).as_str())
+            }
+            ConfigError::BadEnv(var, value) => {
+                f.write_str(format!("Bad value `{}` for {}", value, var).as_str())
+            }
+            ConfigError::Invalid(problems) => f.write_str(problems.join("; ").as_str()),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// No credentials were sent.
+    Missing,
+    /// The key named or presented isn't configured.
+    UnknownKey,
+    BadSignature,
+    /// A signed request's timestamp is too far from the server's clock.
+    StaleTimestamp,
+    /// A signed request was sent again.
+    Replayed,
+    /// The key sent too many requests; retry after this many seconds.
+    RateLimited(u64),
+    /// The key used up its daily quota; it resets in this many seconds.
+    QuotaExceeded(u64),
+}
+
+impl Display for AuthError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str("tenor error: ")?;
+        f.write_str("auth error: ")?;
         match self {
-            TenorError::Unavailable => f.write_str("Tenor API is unavailable"),
-            TenorError::NoProcessed => f.write_str("Cannot parse link"),
-            TenorError::InvalidLink => f.write_str("Invalid tenor link"),
-            TenorError::BadResponse(str) => f.write_str(format!("Bad response: {}", str).as_str()),
-            TenorError::RequestError(e) => {
-                f.write_str(format!("Cannot parse response: {:#?}", e).as_str())
+            AuthError::Missing => f.write_str("An API key is required"),
+            AuthError::UnknownKey => f.write_str("Unknown API key"),
+            AuthError::BadSignature => f.write_str("Bad request signature"),
+            AuthError::StaleTimestamp => f.write_str("Request timestamp is too old or too new"),
+            AuthError::Replayed => f.write_str("Request signature was already used"),
+            AuthError::RateLimited(secs) => {
+                f.write_str(format!("Too many requests, try again in {}s", secs).as_str())
             }
-            TenorError::CannotParse(e) => {
-                f.write_str(format!("Cannot parse response: {:#?}", e).as_str())
+            AuthError::QuotaExceeded(secs) => {
+                f.write_str(format!("Daily quota used up, resets in {}s", secs).as_str())
             }
         }
     }
 }
 
-impl Error for TenorError {}
+impl Error for AuthError {}
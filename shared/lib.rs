@@ -1,8 +1,12 @@
 mod action;
 mod err;
+mod logging;
+mod range;
 
 pub use action::*;
 pub use err::*;
+pub use logging::*;
+pub use range::*;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,29 @@
+use std::env;
+
+use tracing_subscriber::EnvFilter;
+
+/// Header carrying the id the bot gives each command, so the server's logs for it can be found.
+pub static REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Sets up logging for a binary.
+///
+/// Levels are picked with `RUST_LOG` (default `info`). Lines are JSON when `LOG_FORMAT` is
+/// `json`, or when running in k8s and `LOG_FORMAT` isn't set, so the cluster can collect them.
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let json = match env::var("LOG_FORMAT") {
+        Ok(format) => format == "json",
+        Err(_) => env::var("KUBERNETES_SERVICE_HOST").is_ok(),
+    };
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match json {
+        true => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+        false => builder.init(),
+    }
+}
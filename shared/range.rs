@@ -0,0 +1,194 @@
+use crate::RangeError;
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// One end of a [`FrameRange`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RangeBound {
+    /// A zero-based frame index.
+    Frame(usize),
+    /// A point in time from the start of the animation.
+    Time(Duration),
+}
+
+impl FromStr for RangeBound {
+    type Err = RangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let bad = || RangeError::BadBound(s.to_string());
+
+        if let Some(ms) = s.strip_suffix("ms") {
+            let ms = ms.parse::<f64>().map_err(|_| bad())?;
+            let time = Duration::try_from_secs_f64(ms / 1000.).map_err(|_| bad())?;
+            return Ok(RangeBound::Time(time));
+        }
+
+        if let Some(secs) = s.strip_suffix('s') {
+            // negative, infinite and too long to be a `Duration` all fail here
+            let secs = secs.parse::<f64>().map_err(|_| bad())?;
+            let time = Duration::try_from_secs_f64(secs).map_err(|_| bad())?;
+            return Ok(RangeBound::Time(time));
+        }
+
+        s.parse::<usize>().map(RangeBound::Frame).map_err(|_| bad())
+    }
+}
+
+/// A slice of an animation, written as `start-end`.
+///
+/// Bounds are either frame indices (`5-20`, inclusive) or times (`1.2s-3s`, `500ms-2s`, end
+/// exclusive), and may be mixed. Either side may be left open: `2s-` runs to the end, `-10`
+/// starts from the first frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameRange {
+    pub start: Option<RangeBound>,
+    pub end: Option<RangeBound>,
+}
+
+impl FromStr for FrameRange {
+    type Err = RangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(RangeError::Empty);
+        }
+
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (start.trim(), end.trim()),
+            None => (s, s),
+        };
+
+        let bound = |s: &str| match s.is_empty() {
+            true => Ok(None),
+            false => s.parse::<RangeBound>().map(Some),
+        };
+
+        let range = Self {
+            start: bound(start)?,
+            end: bound(end)?,
+        };
+
+        if range.start.is_none() && range.end.is_none() {
+            return Err(RangeError::Empty);
+        }
+
+        Ok(range)
+    }
+}
+
+impl FrameRange {
+    /// Resolves this range into frame indices, given the delay of every frame.
+    pub fn resolve(&self, delays: &[Duration]) -> Result<Range<usize>, RangeError> {
+        // start time of every frame, plus the end of the last one
+        let mut starts = Vec::with_capacity(delays.len() + 1);
+        let mut elapsed = Duration::ZERO;
+        starts.push(elapsed);
+        for delay in delays {
+            elapsed += *delay;
+            starts.push(elapsed);
+        }
+
+        let start = match self.start {
+            None => 0,
+            Some(RangeBound::Frame(i)) => i,
+            Some(RangeBound::Time(t)) => starts[..delays.len()]
+                .iter()
+                .position(|s| *s >= t)
+                .unwrap_or(delays.len()),
+        };
+
+        let end = match self.end {
+            None => delays.len(),
+            Some(RangeBound::Frame(i)) => (i + 1).min(delays.len()),
+            Some(RangeBound::Time(t)) => starts[..delays.len()]
+                .iter()
+                .filter(|s| **s < t)
+                .count(),
+        };
+
+        if start >= delays.len() {
+            return Err(RangeError::OutOfBounds(delays.len()));
+        }
+
+        if start >= end {
+            return Err(RangeError::Backwards);
+        }
+
+        Ok(start..end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn parses_bounds() {
+        assert_eq!(
+            "1.2s-3s".parse::<FrameRange>().unwrap(),
+            FrameRange {
+                start: Some(RangeBound::Time(ms(1200))),
+                end: Some(RangeBound::Time(ms(3000))),
+            }
+        );
+        assert_eq!(
+            "5-20".parse::<FrameRange>().unwrap(),
+            FrameRange {
+                start: Some(RangeBound::Frame(5)),
+                end: Some(RangeBound::Frame(20)),
+            }
+        );
+        assert_eq!(
+            "500ms-".parse::<FrameRange>().unwrap(),
+            FrameRange {
+                start: Some(RangeBound::Time(ms(500))),
+                end: None,
+            }
+        );
+        assert!("".parse::<FrameRange>().is_err());
+        assert!("-".parse::<FrameRange>().is_err());
+        assert!("a-3".parse::<FrameRange>().is_err());
+        assert!("-1s-2s".parse::<FrameRange>().is_err());
+    }
+
+    #[test]
+    fn rejects_times_out_of_range() {
+        for bound in [
+            "1e300s",
+            "18446744073709551616s",
+            "1e300ms",
+            "infs",
+            "NaNms",
+            "-1s",
+        ] {
+            assert!(
+                matches!(bound.parse::<RangeBound>(), Err(RangeError::BadBound(_))),
+                "{}",
+                bound
+            );
+        }
+    }
+
+    #[test]
+    fn resolves_against_delays() {
+        let delays = vec![ms(100); 10];
+
+        let range = |s: &str| s.parse::<FrameRange>().unwrap().resolve(&delays);
+
+        assert_eq!(range("2-4").unwrap(), 2..5);
+        assert_eq!(range("3").unwrap(), 3..4);
+        assert_eq!(range("0.2s-0.5s").unwrap(), 2..5);
+        assert_eq!(range("-250ms").unwrap(), 0..3);
+        assert_eq!(range("8-").unwrap(), 8..10);
+        assert_eq!(range("5-100").unwrap(), 5..10);
+        assert!(matches!(range("4-2"), Err(RangeError::Backwards)));
+        assert!(matches!(range("10-"), Err(RangeError::OutOfBounds(10))));
+    }
+}